@@ -0,0 +1,127 @@
+//! Selection, stats and reload-hashing benchmarks across map sizes. Run with
+//! `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, SeedableRng};
+use roulette::{
+    filter_after, scaled_decay, select_biased_with, select_sample, select_uniform_with,
+    sip_hash_content, tag_counts, ImageMap,
+};
+use std::collections::HashMap;
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+/// A map of `n` keys one hour apart from 2000-01-01.
+fn map_of(n: usize) -> ImageMap {
+    let map: HashMap<String, String> = (0..n)
+        .map(|i| {
+            let at = chrono::DateTime::UNIX_EPOCH
+                + chrono::Duration::days(10_957)
+                + chrono::Duration::hours(i as i64);
+            let key = at.format("%Y-%m-%d_%H-%M-%S_UTC.jpg").to_string();
+            (key, format!("{i}.jpg"))
+        })
+        .collect();
+    ImageMap::parse(&serde_json::to_string(&map).unwrap()).unwrap()
+}
+
+fn selection(c: &mut Criterion) {
+    let maps: Vec<_> = SIZES.iter().map(|&n| (n, map_of(n))).collect();
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let mut group = c.benchmark_group("select_uniform");
+    for (n, map) in &maps {
+        group.bench_with_input(BenchmarkId::from_parameter(n), map, |b, map| {
+            b.iter(|| select_uniform_with(black_box(&map.sorted_keys), &mut rng))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("select_biased");
+    for (n, map) in &maps {
+        let decay = scaled_decay(*n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), map, |b, map| {
+            b.iter(|| select_biased_with(black_box(&map.sorted_keys), decay, 0.0, None, &mut rng))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("filter_after");
+    for (n, map) in &maps {
+        let last = map.sorted_keys.last().unwrap();
+        // A year bound, a month bound and a bound near the newest key.
+        for bound in ["2001", "2000-06", &last[..13]] {
+            let id = BenchmarkId::new(bound, n);
+            group.bench_with_input(id, map, |b, map| {
+                b.iter(|| filter_after(black_box(&map.sorted_keys), black_box(bound)))
+            });
+        }
+    }
+    group.finish();
+
+    // `keys_after` caches the partition point `filter_after` searches for.
+    let mut group = c.benchmark_group("keys_after");
+    for (n, map) in &maps {
+        let bound = &map.sorted_keys[n / 2][..13];
+        group.bench_with_input(BenchmarkId::new("uncached", n), map, |b, map| {
+            b.iter(|| filter_after(black_box(&map.sorted_keys), black_box(bound)))
+        });
+        group.bench_with_input(BenchmarkId::new("cached", n), map, |b, map| {
+            b.iter(|| map.keys_after(black_box(bound)))
+        });
+    }
+    group.finish();
+
+    // The `?count=` JSON batch: sample, resolve and serialize.
+    let mut group = c.benchmark_group("json_batch");
+    for (n, map) in &maps {
+        group.bench_with_input(BenchmarkId::from_parameter(n), map, |b, map| {
+            b.iter(|| {
+                let batch: Vec<_> = select_sample(&map.sorted_keys, 20, &mut rng)
+                    .into_iter()
+                    .map(|key| {
+                        let url = format!("https://cdn.example.com/{}", map.get(key).unwrap());
+                        serde_json::json!({ "key": key, "url": url })
+                    })
+                    .collect();
+                serde_json::to_string(&batch).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The `/stats` passes, which should scale linearly with the map.
+fn stats(c: &mut Criterion) {
+    let now = chrono::Utc::now();
+    let mut group = c.benchmark_group("stats");
+    // 500k too, where quadratic work would stand out.
+    for n in SIZES.into_iter().chain([500_000]) {
+        let map = map_of(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &map, |b, map| {
+            b.iter(|| (map.count_future(now), tag_counts(black_box(map), 1)))
+        });
+    }
+    group.finish();
+}
+
+/// Reload change detection over a map's raw content.
+fn hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash_content");
+    for n in SIZES {
+        let content: String = (0..n)
+            .map(|i| format!("\"{i:08}_UTC.jpg\": \"{i}.jpg\",\n"))
+            .collect();
+        group.bench_with_input(BenchmarkId::new("sip", n), &content, |b, content| {
+            b.iter(|| sip_hash_content(black_box(content)))
+        });
+        #[cfg(feature = "xxhash")]
+        group.bench_with_input(BenchmarkId::new("xxh3", n), &content, |b, content| {
+            b.iter(|| roulette::hash_content(black_box(content)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, selection, stats, hashing);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+use std::{env, path::PathBuf};
+
+// Resolves the map embedded as the default, overridable at build time with
+// `EMBEDDED_IMAGE_MAP_PATH` (relative paths are from the crate root).
+fn main() {
+    println!("cargo:rerun-if-env-changed=EMBEDDED_IMAGE_MAP_PATH");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let path = env::var("EMBEDDED_IMAGE_MAP_PATH").unwrap_or_else(|_| "image-map.json".into());
+    let path = manifest_dir.join(path);
+    assert!(
+        path.is_file(),
+        "EMBEDDED_IMAGE_MAP_PATH {} is not a file",
+        path.display()
+    );
+    println!("cargo:rerun-if-changed={}", path.display());
+    println!("cargo:rustc-env=EMBEDDED_IMAGE_MAP_FILE={}", path.display());
+}
@@ -0,0 +1,143 @@
+use crate::forwarded::ClientInfo;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+pub struct AccessLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl AccessLog {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!(error = %e, "access log write failed");
+        }
+    }
+}
+
+pub struct Entry<'a> {
+    pub client: Option<IpAddr>,
+    pub time: DateTime<Utc>,
+    pub request_line: &'a str,
+    pub status: u16,
+    pub bytes: Option<u64>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(v) => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => "\"-\"".to_string(),
+    }
+}
+
+pub fn format_line(entry: &Entry) -> String {
+    let host = entry
+        .client
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let bytes = entry
+        .bytes
+        .filter(|&b| b > 0)
+        .map(|b| b.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    format!(
+        "{} - - [{}] {} {} {} {} {}",
+        host,
+        entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        quoted(Some(entry.request_line)),
+        entry.status,
+        bytes,
+        quoted(entry.referer),
+        quoted(entry.user_agent),
+    )
+}
+
+fn header_string(req: &Request, name: header::HeaderName) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+pub async fn middleware(State(log): State<Arc<AccessLog>>, req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let request_line = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+    let referer = header_string(&req, header::REFERER);
+    let user_agent = header_string(&req, header::USER_AGENT);
+    let time = Utc::now();
+    let response = next.run(req).await;
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    // Layered outside the router, so the resolved client comes back on the
+    // response.
+    let client = response
+        .extensions()
+        .get::<ClientInfo>()
+        .map_or(peer, |info| info.ip);
+    log.write(&format_line(&Entry {
+        client,
+        time,
+        request_line: &request_line,
+        status: response.status().as_u16(),
+        bytes,
+        referer: referer.as_deref(),
+        user_agent: user_agent.as_deref(),
+    }));
+    response
+}
+
+#[cfg(unix)]
+pub async fn reopen_on_sighup(log: Arc<AccessLog>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        match log.reopen() {
+            Ok(()) => info!(path = %log.path.display(), "reopened access log"),
+            Err(e) => warn!(error = %e, "failed to reopen access log"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reopen_on_sighup(_log: Arc<AccessLog>) {}
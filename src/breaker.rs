@@ -0,0 +1,216 @@
+//! Per-origin circuit breakers for upstream image and validation requests, so
+//! a dead CDN is fast-failed instead of piled up on.
+//!
+//! A host's circuit opens after `threshold` consecutive failures and stays
+//! open for the cooldown, or for longer when the origin sent a `Retry-After`.
+//! Once that passes one request is let through as a probe: success closes the
+//! circuit, failure opens it again.
+// Only `Breakers::send` records outcomes, and it needs the `http` feature.
+#![cfg_attr(not(feature = "http"), allow(dead_code))]
+
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+const STATE: &str = "roulette_upstream_breaker_state";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    /// The cooldown has passed; the next request probes the origin.
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// The `roulette_upstream_breaker_state` gauge value.
+    pub fn gauge(self) -> u8 {
+        match self {
+            Self::Closed => 0,
+            Self::Open => 1,
+            Self::HalfOpen => 2,
+        }
+    }
+}
+
+/// Why a request through [`Breakers::send`] failed.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// The origin's circuit is open; retry after this long.
+    Open(Duration),
+    Failed(String),
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(wait) => write!(f, "circuit open for another {}s", wait.as_secs()),
+            Self::Failed(error) => f.write_str(error),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// When the half-open probe went out. A probe that never reports back
+    /// (its request was dropped) stops blocking after another cooldown.
+    probe: Option<Instant>,
+}
+
+pub struct Breakers {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl Breakers {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `Err` with the time left to wait while `host`'s circuit is open or
+    /// another request is probing it. Letting a request through a half-open
+    /// circuit makes it the probe.
+    pub fn allow(&self, host: &str, now: Instant) -> Result<(), Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = circuit.open_until else {
+            return Ok(());
+        };
+        if now < open_until {
+            return Err(open_until - now);
+        }
+        match circuit.probe {
+            Some(sent) if now < sent + self.cooldown => Err(sent + self.cooldown - now),
+            _ => {
+                circuit.probe = Some(now);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn succeeded(&self, host: &str) {
+        self.circuits.lock().unwrap().remove(host);
+    }
+
+    /// Counts a failure against `host`, opening its circuit at the threshold
+    /// or when a probe fails. `retry_after` from the origin extends the
+    /// cooldown but never shortens it.
+    pub fn failed(&self, host: &str, retry_after: Option<Duration>, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(host.to_string()).or_default();
+        circuit.failures += 1;
+        if circuit.probe.is_some() || circuit.failures >= self.threshold {
+            let cooldown = retry_after.map_or(self.cooldown, |r| r.max(self.cooldown));
+            circuit.open_until = Some(now + cooldown);
+            circuit.probe = None;
+        }
+    }
+
+    /// Every host with failures on record, alphabetically.
+    pub fn states(&self, now: Instant) -> Vec<(String, BreakerState)> {
+        let mut states: Vec<_> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, circuit)| (host.clone(), circuit_state(circuit, now)))
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    /// The `/metrics` gauge for every host in [`states`](Self::states).
+    pub fn render(&self, now: Instant) -> String {
+        let mut out = format!(
+            "# HELP {STATE} Upstream circuit per host: 0 closed, 1 open, 2 half-open.\n\
+             # TYPE {STATE} gauge\n"
+        );
+        for (host, state) in self.states(now) {
+            writeln!(out, "{STATE}{{host=\"{host}\"}} {}", state.gauge()).unwrap();
+        }
+        out
+    }
+
+    /// Sends `request` for `url` unless its host's circuit is open, and
+    /// records the outcome. Transport errors, `429` and `5xx` count as
+    /// failures; other statuses show the origin is up but still come back as
+    /// [`UpstreamError::Failed`] when unsuccessful.
+    #[cfg(feature = "http")]
+    pub async fn send(
+        &self,
+        url: &str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, UpstreamError> {
+        let host = host(url);
+        if let Some(host) = &host {
+            self.allow(host, Instant::now())
+                .map_err(UpstreamError::Open)?;
+        }
+        let result = request.send().await;
+        let Some(host) = &host else {
+            return result
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| UpstreamError::Failed(e.to_string()));
+        };
+        match result {
+            Ok(resp) if resp.status().is_success() => {
+                self.succeeded(host);
+                Ok(resp)
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                    let retry_after = resp
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| parse_retry_after(v, Utc::now()));
+                    self.failed(host, retry_after, Instant::now());
+                } else {
+                    self.succeeded(host);
+                }
+                Err(UpstreamError::Failed(format!("{url} returned {status}")))
+            }
+            Err(error) => {
+                self.failed(host, None, Instant::now());
+                Err(UpstreamError::Failed(error.to_string()))
+            }
+        }
+    }
+}
+
+fn circuit_state(circuit: &Circuit, now: Instant) -> BreakerState {
+    match circuit.open_until {
+        Some(open_until) if now < open_until => BreakerState::Open,
+        Some(_) => BreakerState::HalfOpen,
+        None => BreakerState::Closed,
+    }
+}
+
+/// The host a circuit for `url` is kept under.
+pub fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(String::from)
+}
+
+/// An origin's `Retry-After`, as delta-seconds or an HTTP-date from `now`.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some((at.to_utc() - now).to_std().unwrap_or_default())
+}
@@ -0,0 +1,45 @@
+//! Several `IMAGE_URL_PREFIX` origins sharing the redirects, either in turn
+//! or by weight.
+
+use rand::{distributions::WeightedIndex, Rng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Absolute prefixes with their hosts. Empty unless `IMAGE_URL_PREFIX` lists
+/// more than one, in which case the first is also `AppState::url_prefix`.
+#[derive(Default)]
+pub struct PrefixPool {
+    prefixes: Vec<(String, String)>,
+    /// From `IMAGE_URL_PREFIX_WEIGHTS`; `None` rotates round-robin.
+    weights: Option<WeightedIndex<f64>>,
+    next: AtomicUsize,
+}
+
+impl PrefixPool {
+    /// `None` if `weights` doesn't give one positive weight per prefix.
+    pub fn new(prefixes: Vec<(String, String)>, weights: Option<&[f64]>) -> Option<Self> {
+        let weights = match weights {
+            Some(weights) if weights.len() != prefixes.len() => return None,
+            Some(weights) => Some(WeightedIndex::new(weights).ok()?),
+            None => None,
+        };
+        Some(Self {
+            prefixes,
+            weights,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The prefix and host for the next redirect, `None` when the pool is
+    /// empty. `rng` is only called for a weighted pool.
+    pub fn pick<R: Rng>(&self, rng: impl FnOnce() -> R) -> Option<(&str, &str)> {
+        if self.prefixes.is_empty() {
+            return None;
+        }
+        let i = match &self.weights {
+            Some(weights) => rng().sample(weights),
+            None => self.next.fetch_add(1, Ordering::Relaxed) % self.prefixes.len(),
+        };
+        let (prefix, host) = &self.prefixes[i];
+        Some((prefix, host))
+    }
+}
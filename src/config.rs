@@ -0,0 +1,545 @@
+use crate::cdn::PrefixPool;
+#[cfg(feature = "tls")]
+use crate::tls::TlsFiles;
+use crate::validate::DEFAULT_MAX_FAILURES;
+use crate::{
+    deprecation::Deprecations, normalize_base_path, parse_max_weight, prefix_host, relative_prefix,
+    ExcludeNewest, FieldNames, ResponseFormat, RetryAfterFormat, TlsVersion, Weighting,
+};
+use regex::Regex;
+use roulette::{halflife_decay, parse_duration};
+use serde::{Serialize, Serializer};
+use std::{error::Error, fmt};
+
+/// Settings resolved from the environment, shared by the server and `--print-config`.
+#[derive(Serialize)]
+pub struct Config {
+    /// The first `IMAGE_URL_PREFIX` entry.
+    pub url_prefix: String,
+    /// Further `IMAGE_URL_PREFIX` entries, sharing redirects with the first.
+    pub mirror_prefixes: Vec<String>,
+    /// `IMAGE_URL_PREFIX_WEIGHTS`, one per prefix; `None` rotates in turn.
+    pub url_prefix_weights: Option<Vec<f64>>,
+    /// `None` serves the embedded map.
+    pub map_path: Option<String>,
+    pub sync_url: Option<String>,
+    pub sync_interval_secs: Option<u64>,
+    pub s3_bucket: Option<String>,
+    /// Object key of the map in `s3_bucket`.
+    pub s3_key: String,
+    pub s3_region: String,
+    /// S3-compatible endpoint, addressed path-style, instead of AWS.
+    pub s3_endpoint: Option<String>,
+    pub strict_map: bool,
+    pub drop_future_keys: bool,
+    /// Only keys starting with this are loaded.
+    pub key_prefix_filter: Option<String>,
+    /// `RECENCY_DECAY`, else derived from `LATEST_HALFLIFE`.
+    pub recency_decay: Option<f64>,
+    /// Probability ceiling per key for `/latest`, as a fraction.
+    pub max_weight: Option<f64>,
+    /// Fewest candidates `/latest/after` weights; narrower slices widen.
+    pub min_biased_pool: Option<usize>,
+    /// Newest keys the `latest` endpoints leave out.
+    pub latest_skip_newest: usize,
+    /// Default `?weighting=` for the `latest` endpoints, from `BIAS_WEIGHTING`.
+    pub bias_weighting: Weighting,
+    /// Newest keys plain `/image` leaves out, by count or by age.
+    pub uniform_exclude_newest: Option<ExcludeNewest>,
+    /// Append a random `r` query parameter to plain `/image` URLs.
+    pub redirect_cache_buster: bool,
+    /// Names of `key` and `url` in JSON selection bodies.
+    pub field_names: FieldNames,
+    pub default_response: ResponseFormat,
+    /// Absolute URL served instead of `404` for empty selections.
+    pub fallback_url: Option<String>,
+    pub retry_after: RetryAfterFormat,
+    /// Fraction, not percent.
+    pub cache_jitter: f64,
+    /// Weight multiplier `/image/dow` gives keys from today's weekday.
+    pub dow_boost: f64,
+    pub session_ttl_secs: u64,
+    /// Live shuffle sessions kept at most.
+    pub max_sessions: usize,
+    /// URLs `HEAD`-checked before a reloaded map goes live; `None` disables it.
+    pub validate_sample: Option<usize>,
+    /// Fraction, not percent.
+    pub validate_max_failures: f64,
+    /// Consecutive upstream failures that open an origin's circuit.
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+    /// How long `/image/discover` keeps penalizing a served key.
+    pub discover_ttl_secs: u64,
+    /// Where `/image/fair` counts are saved; `None` keeps them in memory.
+    pub served_counts_path: Option<String>,
+    pub served_counts_interval_secs: u64,
+    pub base_path: String,
+    #[serde(serialize_with = "redact")]
+    pub admin_token: Option<String>,
+    #[serde(serialize_with = "redact")]
+    pub signing_key: Option<String>,
+    pub prefix_hosts: Vec<String>,
+    /// Regex every public bound must match; `None` allows any valid bound.
+    pub allowed_bound_pattern: Option<String>,
+    /// `400` for an empty `{bound}` instead of treating it as no bound.
+    pub reject_empty_bound: bool,
+    /// `route=sunset=successor` entries flagged with deprecation headers.
+    pub deprecated_routes: Option<String>,
+    pub port: u16,
+    pub access_log_path: Option<String>,
+    /// PEM certificate chain and key for native HTTPS; `None` serves plain HTTP.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_min_version: TlsVersion,
+    /// Proxies in front of the service, `0` (the peer) by default; `None`, from
+    /// `leftmost`, trusts the oldest forwarded entry.
+    pub trusted_proxies: Option<usize>,
+}
+
+fn redact<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+/// `/image/dow` boost when `DOW_BOOST` is unset.
+pub const DEFAULT_DOW_BOOST: f64 = 2.0;
+
+/// A required setting that is missing or a value that doesn't parse, with
+/// the message naming the variable and what it expects.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+fn invalid(message: &str) -> ConfigError {
+    ConfigError(message.to_string())
+}
+
+/// Every setting at the value used when its variable is unset. `url_prefix`
+/// has no default and is left empty, so set it before serving.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            url_prefix: String::new(),
+            mirror_prefixes: Vec::new(),
+            url_prefix_weights: None,
+            map_path: None,
+            sync_url: None,
+            sync_interval_secs: None,
+            s3_bucket: None,
+            s3_key: "image-map.json".to_string(),
+            s3_region: "us-east-1".to_string(),
+            s3_endpoint: None,
+            strict_map: false,
+            drop_future_keys: false,
+            key_prefix_filter: None,
+            recency_decay: None,
+            max_weight: None,
+            min_biased_pool: None,
+            latest_skip_newest: 0,
+            bias_weighting: Weighting::Slice,
+            uniform_exclude_newest: None,
+            redirect_cache_buster: false,
+            field_names: FieldNames::default(),
+            default_response: ResponseFormat::Redirect,
+            fallback_url: None,
+            retry_after: RetryAfterFormat::Seconds,
+            cache_jitter: 0.0,
+            dow_boost: DEFAULT_DOW_BOOST,
+            session_ttl_secs: 3600,
+            max_sessions: 100_000,
+            validate_sample: None,
+            validate_max_failures: DEFAULT_MAX_FAILURES / 100.0,
+            breaker_threshold: 5,
+            breaker_cooldown_secs: 30,
+            discover_ttl_secs: 600,
+            served_counts_path: None,
+            served_counts_interval_secs: 60,
+            base_path: String::new(),
+            admin_token: None,
+            signing_key: None,
+            prefix_hosts: Vec::new(),
+            allowed_bound_pattern: None,
+            reject_empty_bound: false,
+            deprecated_routes: None,
+            port: 8080,
+            access_log_path: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_min_version: TlsVersion::Tls12,
+            trusted_proxies: Some(0),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// `BASE_PATH`, normalized. `--export-openapi` reads only this, so it works
+    /// without the rest of the configuration.
+    pub fn base_path_from(var: impl Fn(&str) -> Option<String>) -> String {
+        normalize_base_path(&var("BASE_PATH").unwrap_or_default())
+    }
+
+    /// Resolves every setting through `var`, validating all of them up front.
+    pub fn from_lookup(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let defaults = Self::default();
+        let prefixes =
+            var("IMAGE_URL_PREFIX").ok_or_else(|| invalid("IMAGE_URL_PREFIX required"))?;
+        let mut prefixes: Vec<String> = prefixes.split(',').map(|p| p.trim().to_string()).collect();
+        for prefix in &prefixes {
+            if !relative_prefix(prefix) && prefix_host(prefix).is_none() {
+                return Err(invalid(
+                    "IMAGE_URL_PREFIX must be an absolute URL or a path starting with /",
+                ));
+            }
+        }
+        if prefixes.len() > 1 && prefixes.iter().any(|p| relative_prefix(p)) {
+            return Err(invalid(
+                "IMAGE_URL_PREFIX must list only absolute URLs when it has several",
+            ));
+        }
+        let url_prefix_weights = var("IMAGE_URL_PREFIX_WEIGHTS")
+            .map(|s| {
+                s.split(',')
+                    .map(|w| {
+                        w.trim()
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|w| w.is_finite() && *w > 0.0)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|weights| weights.len() == prefixes.len())
+                    .ok_or_else(|| {
+                        invalid("IMAGE_URL_PREFIX_WEIGHTS must be one positive weight per prefix")
+                    })
+            })
+            .transpose()?;
+        let url_prefix = prefixes.remove(0);
+        let mirror_prefixes = prefixes;
+        let sync_interval_secs = var("IMAGE_MAP_SYNC_INTERVAL")
+            .map(|s| {
+                s.parse()
+                    .map_err(|_| invalid("IMAGE_MAP_SYNC_INTERVAL must be seconds"))
+            })
+            .transpose()?;
+        let default_response = var("DEFAULT_RESPONSE")
+            .map(|s| {
+                ResponseFormat::parse(&s)
+                    .ok_or_else(|| invalid("DEFAULT_RESPONSE must be redirect, json or html"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.default_response);
+        let bias_weighting = var("BIAS_WEIGHTING")
+            .map(|s| {
+                Weighting::parse(&s)
+                    .ok_or_else(|| invalid("BIAS_WEIGHTING must be slice or global"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.bias_weighting);
+        let retry_after = var("RETRY_AFTER_FORMAT")
+            .map(|s| {
+                RetryAfterFormat::parse(&s)
+                    .ok_or_else(|| invalid("RETRY_AFTER_FORMAT must be seconds or date"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.retry_after);
+        let cache_jitter = var("CACHE_JITTER")
+            .map(|s| {
+                s.parse::<f64>()
+                    .ok()
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .map(|p| p / 100.0)
+                    .ok_or_else(|| invalid("CACHE_JITTER must be a percentage between 0 and 100"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.cache_jitter);
+        let dow_boost = var("DOW_BOOST")
+            .map(|s| {
+                s.parse::<f64>()
+                    .ok()
+                    .filter(|f| f.is_finite() && *f > 0.0)
+                    .ok_or_else(|| invalid("DOW_BOOST must be a positive factor"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.dow_boost);
+        let fallback_url = var("FALLBACK_URL");
+        if fallback_url
+            .as_deref()
+            .is_some_and(|url| prefix_host(url).is_none())
+        {
+            return Err(invalid("FALLBACK_URL must be an absolute URL"));
+        }
+        let recency_decay = var("RECENCY_DECAY")
+            .map(|s| {
+                s.parse::<f64>()
+                    .ok()
+                    .filter(|d| d.is_finite() && *d >= 0.0)
+                    .ok_or_else(|| invalid("RECENCY_DECAY must be a non-negative number"))
+            })
+            .transpose()?;
+        let recency_decay = match recency_decay {
+            Some(decay) => Some(decay),
+            None => var("LATEST_HALFLIFE")
+                .map(|s| {
+                    s.parse::<f64>()
+                        .ok()
+                        .filter(|h| h.is_finite() && *h > 0.0)
+                        .map(halflife_decay)
+                        .ok_or_else(|| {
+                            invalid("LATEST_HALFLIFE must be a positive number of posts")
+                        })
+                })
+                .transpose()?,
+        };
+        let max_weight = var("MAX_WEIGHT")
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .and_then(|w| parse_max_weight(Some(w)).ok().flatten())
+                    .ok_or_else(|| invalid("MAX_WEIGHT must be a fraction in (0, 1]"))
+            })
+            .transpose()?;
+        let min_biased_pool = var("MIN_BIASED_POOL")
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid("MIN_BIASED_POOL must be a positive number of images"))
+            })
+            .transpose()?;
+        let latest_skip_newest = var("LATEST_SKIP_NEWEST")
+            .map(|s| {
+                s.parse().map_err(|_| {
+                    invalid("LATEST_SKIP_NEWEST must be a non-negative number of images")
+                })
+            })
+            .transpose()?
+            .unwrap_or(defaults.latest_skip_newest);
+        let uniform_exclude_newest = var("UNIFORM_EXCLUDE_NEWEST")
+            .map(|s| {
+                ExcludeNewest::parse(&s).ok_or_else(|| {
+                    invalid(
+                        "UNIFORM_EXCLUDE_NEWEST must be a number of images or a duration like 6h",
+                    )
+                })
+            })
+            .transpose()?;
+        let field_names = FieldNames {
+            key: var("JSON_KEY_FIELD").unwrap_or(defaults.field_names.key),
+            url: var("JSON_URL_FIELD").unwrap_or(defaults.field_names.url),
+        };
+        for name in [&field_names.key, &field_names.url] {
+            if name.is_empty() || ["probability", "fallback"].contains(&name.as_str()) {
+                return Err(invalid(
+                    "JSON_KEY_FIELD and JSON_URL_FIELD must be non-empty and not a reserved field name",
+                ));
+            }
+        }
+        if field_names.key == field_names.url {
+            return Err(invalid("JSON_KEY_FIELD and JSON_URL_FIELD must differ"));
+        }
+        let trusted_proxies = match var("TRUSTED_PROXIES").as_deref() {
+            None => defaults.trusted_proxies,
+            Some("leftmost") => None,
+            Some(s) => Some(s.parse().map_err(|_| {
+                invalid("TRUSTED_PROXIES must be a non-negative number of proxies or leftmost")
+            })?),
+        };
+        let validate_sample = var("VALIDATE_ON_RELOAD")
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid("VALIDATE_ON_RELOAD must be a positive sample size"))
+            })
+            .transpose()?;
+        #[cfg(not(feature = "http"))]
+        if validate_sample.is_some() {
+            return Err(invalid("VALIDATE_ON_RELOAD requires the http feature"));
+        }
+        if validate_sample.is_some() && relative_prefix(&url_prefix) {
+            return Err(invalid(
+                "VALIDATE_ON_RELOAD requires an absolute IMAGE_URL_PREFIX",
+            ));
+        }
+        let validate_max_failures = var("VALIDATE_MAX_FAILURES")
+            .map(|s| {
+                s.parse::<f64>()
+                    .ok()
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .map(|p| p / 100.0)
+                    .ok_or_else(|| {
+                        invalid("VALIDATE_MAX_FAILURES must be a percentage between 0 and 100")
+                    })
+            })
+            .transpose()?
+            .unwrap_or(defaults.validate_max_failures);
+        let breaker_threshold = var("BREAKER_THRESHOLD")
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid("BREAKER_THRESHOLD must be a positive integer"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.breaker_threshold);
+        let breaker_cooldown_secs = var("BREAKER_COOLDOWN")
+            .map(|s| {
+                parse_duration(&s)
+                    .filter(|&secs| secs > 0)
+                    .ok_or_else(|| invalid("BREAKER_COOLDOWN must be a duration like 30s"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.breaker_cooldown_secs);
+        let session_ttl_secs = var("SESSION_TTL")
+            .map(|s| {
+                parse_duration(&s).ok_or_else(|| invalid("SESSION_TTL must be a duration like 1h"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.session_ttl_secs);
+        let max_sessions = var("MAX_SESSIONS")
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| invalid("MAX_SESSIONS must be a positive integer"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.max_sessions);
+        let discover_ttl_secs = var("DISCOVER_TTL")
+            .map(|s| {
+                parse_duration(&s)
+                    .ok_or_else(|| invalid("DISCOVER_TTL must be a duration like 10m"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.discover_ttl_secs);
+        let served_counts_interval_secs = var("SERVED_COUNTS_INTERVAL")
+            .map(|s| {
+                parse_duration(&s)
+                    .filter(|&secs| secs > 0)
+                    .ok_or_else(|| invalid("SERVED_COUNTS_INTERVAL must be a duration like 1m"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.served_counts_interval_secs);
+        let prefix_hosts = var("PREFIX_ALLOWED_HOSTS")
+            .map(|s| {
+                s.split(',')
+                    .map(|h| h.trim().to_string())
+                    .filter(|h| !h.is_empty())
+                    .collect()
+            })
+            .unwrap_or(defaults.prefix_hosts);
+        let allowed_bound_pattern = var("ALLOWED_BOUND_PATTERN");
+        if let Some(pattern) = &allowed_bound_pattern {
+            Regex::new(pattern)
+                .map_err(|_| invalid("ALLOWED_BOUND_PATTERN must be a valid regex"))?;
+        }
+        let tls_cert_path = var("TLS_CERT_PATH").filter(|p| !p.is_empty());
+        let tls_key_path = var("TLS_KEY_PATH").filter(|p| !p.is_empty());
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            return Err(invalid(
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            ));
+        }
+        if tls_cert_path.is_some() && !cfg!(feature = "tls") {
+            return Err(invalid("TLS_CERT_PATH requires the tls feature"));
+        }
+        let tls_min_version = var("TLS_MIN_VERSION")
+            .map(|s| {
+                TlsVersion::parse(&s).ok_or_else(|| invalid("TLS_MIN_VERSION must be 1.2 or 1.3"))
+            })
+            .transpose()?
+            .unwrap_or(defaults.tls_min_version);
+        let base_path = Self::base_path_from(&var);
+        let deprecated_routes = var("DEPRECATED_ROUTES");
+        if let Some(spec) = &deprecated_routes {
+            Deprecations::parse(spec, &base_path).ok_or_else(|| {
+                invalid("DEPRECATED_ROUTES must be route=YYYY-MM-DD=successor entries")
+            })?;
+        }
+        Ok(Self {
+            url_prefix,
+            mirror_prefixes,
+            url_prefix_weights,
+            map_path: var("IMAGE_MAP_PATH"),
+            sync_url: var("IMAGE_MAP_SYNC_URL"),
+            sync_interval_secs,
+            s3_bucket: var("S3_BUCKET"),
+            s3_key: var("S3_KEY").unwrap_or(defaults.s3_key),
+            s3_region: var("S3_REGION").unwrap_or(defaults.s3_region),
+            s3_endpoint: var("S3_ENDPOINT"),
+            strict_map: var("STRICT_MAP").is_some_and(|v| v == "1"),
+            drop_future_keys: var("DROP_FUTURE_KEYS").is_some_and(|v| v == "1"),
+            key_prefix_filter: var("KEY_PREFIX_FILTER").filter(|p| !p.is_empty()),
+            recency_decay,
+            max_weight,
+            min_biased_pool,
+            latest_skip_newest,
+            bias_weighting,
+            uniform_exclude_newest,
+            redirect_cache_buster: var("REDIRECT_CACHE_BUSTER").is_some_and(|v| v == "1"),
+            field_names,
+            default_response,
+            fallback_url,
+            retry_after,
+            cache_jitter,
+            dow_boost,
+            session_ttl_secs,
+            max_sessions,
+            validate_sample,
+            validate_max_failures,
+            breaker_threshold,
+            breaker_cooldown_secs,
+            discover_ttl_secs,
+            served_counts_path: var("SERVED_COUNTS_PATH"),
+            served_counts_interval_secs,
+            base_path,
+            admin_token: var("ADMIN_TOKEN").filter(|t| !t.is_empty()),
+            signing_key: var("SIGNING_KEY"),
+            prefix_hosts,
+            allowed_bound_pattern,
+            reject_empty_bound: var("REJECT_EMPTY_BOUND").is_some_and(|v| v == "1"),
+            deprecated_routes,
+            port: var("PORT")
+                .map(|p| p.parse().map_err(|_| invalid("PORT must be a port number")))
+                .transpose()?
+                .unwrap_or(defaults.port),
+            access_log_path: var("ACCESS_LOG_PATH"),
+            tls_cert_path,
+            tls_key_path,
+            tls_min_version,
+            trusted_proxies,
+        })
+    }
+
+    /// Where to load the HTTPS certificate from, when both paths are set.
+    #[cfg(feature = "tls")]
+    pub fn tls_files(&self) -> Option<TlsFiles> {
+        Some(TlsFiles {
+            cert_path: self.tls_cert_path.clone()?,
+            key_path: self.tls_key_path.clone()?,
+            min_version: self.tls_min_version,
+        })
+    }
+
+    /// The redirect origins to rotate across; empty for a single prefix.
+    pub fn prefix_pool(&self) -> PrefixPool {
+        if self.mirror_prefixes.is_empty() {
+            return PrefixPool::default();
+        }
+        let prefixes = std::iter::once(&self.url_prefix)
+            .chain(&self.mirror_prefixes)
+            .map(|prefix| (prefix.clone(), prefix_host(prefix).unwrap_or_default()))
+            .collect();
+        PrefixPool::new(prefixes, self.url_prefix_weights.as_deref())
+            .expect("from_lookup checks the weights")
+    }
+}
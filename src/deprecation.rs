@@ -0,0 +1,90 @@
+//! `Deprecation`, `Sunset` and successor `Link` headers for the routes listed
+//! in `DEPRECATED_ROUTES`.
+
+use crate::http_date;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use chrono::NaiveDate;
+use std::{collections::HashMap, sync::Arc};
+
+struct Deprecation {
+    /// `Sunset` header value, as an HTTP-date.
+    sunset: Option<HeaderValue>,
+    /// `Link` header value pointing at the replacement.
+    successor: Option<HeaderValue>,
+}
+
+/// Deprecated route patterns, as registered on the router (e.g.
+/// `/random/after/{bound}`), relative to `base_path`.
+#[derive(Default)]
+pub struct Deprecations {
+    base_path: String,
+    routes: HashMap<String, Deprecation>,
+}
+
+impl Deprecations {
+    /// Parses comma-separated `route[=sunset[=successor]]` entries, where
+    /// `sunset` is a `YYYY-MM-DD` date (midnight UTC) and either part may be
+    /// left empty. `None` if any entry is malformed.
+    pub fn parse(spec: &str, base_path: &str) -> Option<Self> {
+        let mut routes = HashMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(3, '=');
+            let route = parts.next().filter(|r| r.starts_with('/'))?;
+            let sunset = match parts.next().filter(|s| !s.is_empty()) {
+                Some(date) => {
+                    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+                    let time = date.and_hms_opt(0, 0, 0)?.and_utc();
+                    Some(HeaderValue::from_str(&http_date(time)).ok()?)
+                }
+                None => None,
+            };
+            let successor = match parts.next().filter(|s| !s.is_empty()) {
+                Some(url) => {
+                    let link = format!("<{url}>; rel=\"successor-version\"");
+                    Some(HeaderValue::from_str(&link).ok()?)
+                }
+                None => None,
+            };
+            routes.insert(route.to_string(), Deprecation { sunset, successor });
+        }
+        Some(Self {
+            base_path: base_path.to_string(),
+            routes,
+        })
+    }
+
+    fn get(&self, matched: &str) -> Option<&Deprecation> {
+        let route = matched.strip_prefix(&self.base_path).unwrap_or(matched);
+        self.routes.get(route)
+    }
+}
+
+/// Adds the headers to responses from deprecated routes. Installed as a route
+/// layer so the matched route pattern is known.
+pub async fn middleware(
+    State(deprecations): State<Arc<Deprecations>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let mut response = next.run(req).await;
+    if let Some(deprecation) = route.and_then(|route| deprecations.get(&route)) {
+        let headers = response.headers_mut();
+        headers.insert("deprecation", HeaderValue::from_static("true"));
+        if let Some(sunset) = &deprecation.sunset {
+            headers.insert("sunset", sunset.clone());
+        }
+        if let Some(successor) = &deprecation.successor {
+            headers.append("link", successor.clone());
+        }
+    }
+    response
+}
@@ -0,0 +1,68 @@
+//! Keys served recently by `/image/discover`, penalized so that a shared
+//! slideshow spreads out over the collection.
+
+use lru::LruCache;
+use rand::{distributions::WeightedIndex, Rng};
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Most recently served keys remembered; older ones carry no penalty.
+pub const CAPACITY: usize = 1024;
+/// Weight removed from a key at the moment it is served.
+pub const MAX_PENALTY: f64 = 0.9;
+
+pub struct RecentlyServed {
+    ttl: Duration,
+    served: Mutex<LruCache<String, Instant>>,
+}
+
+impl RecentlyServed {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            served: Mutex::new(LruCache::new(NonZeroUsize::new(CAPACITY).unwrap())),
+        }
+    }
+
+    pub fn record(&self, key: &str, now: Instant) {
+        self.served.lock().unwrap().put(key.to_string(), now);
+    }
+
+    /// The penalty for a key served at `at`: [`MAX_PENALTY`], decaying
+    /// linearly to zero over the TTL.
+    fn penalty(&self, at: Instant, now: Instant) -> f64 {
+        let age = now.saturating_duration_since(at).as_secs_f64();
+        let ttl = self.ttl.as_secs_f64();
+        if ttl <= 0.0 || age >= ttl {
+            return 0.0;
+        }
+        MAX_PENALTY * (1.0 - age / ttl)
+    }
+
+    /// `1 - penalty` for each of the sorted `keys`.
+    pub fn weights(&self, keys: &[String], now: Instant) -> Vec<f64> {
+        let mut weights = vec![1.0; keys.len()];
+        for (key, &at) in self.served.lock().unwrap().iter() {
+            if let Ok(i) = keys.binary_search(key) {
+                weights[i] = 1.0 - self.penalty(at, now);
+            }
+        }
+        weights
+    }
+
+    /// Draws from `keys` by [`weights`](Self::weights) and records the pick.
+    pub fn select<'a>(
+        &self,
+        keys: &'a [String],
+        now: Instant,
+        rng: &mut impl Rng,
+    ) -> Option<&'a str> {
+        let dist = WeightedIndex::new(self.weights(keys, now)).ok()?;
+        let key = &keys[rng.sample(dist)];
+        self.record(key, now);
+        Some(key)
+    }
+}
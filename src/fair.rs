@@ -0,0 +1,96 @@
+//! Per-key serve counts for `/image/fair`, steering selection toward the keys
+//! served least so the collection evens out over the long run. Counts can be
+//! saved to a JSON file so restarts don't reset them.
+
+use rand::{distributions::WeightedIndex, Rng};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+#[derive(Default)]
+pub struct ServedCounts {
+    counts: Mutex<HashMap<String, u64>>,
+    /// Where [`save`](Self::save) writes; `None` keeps counts in memory only.
+    path: Option<PathBuf>,
+    /// Set by [`record`](Self::record), cleared by a save.
+    dirty: AtomicBool,
+}
+
+impl ServedCounts {
+    /// Counts persisted at `path`, starting empty if the file doesn't exist.
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let counts = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            counts: Mutex::new(counts),
+            path: Some(path),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn record(&self, key: &str) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default() += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// `1 / (1 + c - min)` for each of `keys`, where `c` is the key's count
+    /// and `min` the lowest among `keys`, so the least served weigh `1`.
+    pub fn weights(&self, keys: &[String]) -> Vec<f64> {
+        let served = self.counts.lock().unwrap();
+        let counts: Vec<u64> = keys
+            .iter()
+            .map(|key| served.get(key).copied().unwrap_or(0))
+            .collect();
+        let min = counts.iter().copied().min().unwrap_or(0);
+        counts
+            .into_iter()
+            .map(|c| 1.0 / (1 + c - min) as f64)
+            .collect()
+    }
+
+    /// Draws from `keys` by [`weights`](Self::weights) and records the pick.
+    pub fn select<'a>(&self, keys: &'a [String], rng: &mut impl Rng) -> Option<&'a str> {
+        let dist = WeightedIndex::new(self.weights(keys)).ok()?;
+        let key = &keys[rng.sample(dist)];
+        self.record(key);
+        Some(key)
+    }
+
+    /// Writes the counts if they changed since the last save, through a
+    /// temporary file so a crash never leaves a partial one. Returns whether
+    /// anything was written.
+    pub fn save(&self) -> io::Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let json = serde_json::to_string(&*self.counts.lock().unwrap())?;
+        let tmp = path.with_extension("tmp");
+        let result = fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, path));
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result.map(|()| true)
+    }
+}
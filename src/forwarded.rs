@@ -0,0 +1,127 @@
+//! The client address and scheme behind reverse proxies, resolved once per
+//! request from `Forwarded` or `X-Forwarded-For`/`X-Forwarded-Proto`.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, SocketAddr};
+
+/// Who a request came from, as IP-dependent features should see it. Stored in
+/// both request and response extensions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`.
+    pub scheme: &'static str,
+}
+
+/// A `for=` or `X-Forwarded-For` node: an address, optionally bracketed
+/// and with a port. Obfuscated and `unknown` nodes yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+fn parse_proto(proto: &str) -> Option<&'static str> {
+    match proto.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+        "http" => Some("http"),
+        "https" => Some("https"),
+        _ => None,
+    }
+}
+
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+}
+
+/// Per-hop client addresses and schemes, oldest first: from `Forwarded` when
+/// present, else from the `X-Forwarded-*` pair.
+fn hops(headers: &HeaderMap) -> (Vec<Option<IpAddr>>, Vec<Option<&'static str>>) {
+    if headers.contains_key("forwarded") {
+        values(headers, "forwarded")
+            .map(|element| {
+                let (mut node, mut proto) = (None, None);
+                for pair in element.split(';') {
+                    let Some((key, value)) = pair.split_once('=') else {
+                        continue;
+                    };
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => node = parse_node(value),
+                        "proto" => proto = parse_proto(value),
+                        _ => {}
+                    }
+                }
+                (node, proto)
+            })
+            .unzip()
+    } else {
+        (
+            values(headers, "x-forwarded-for").map(parse_node).collect(),
+            values(headers, "x-forwarded-proto")
+                .map(parse_proto)
+                .collect(),
+        )
+    }
+}
+
+/// The entry that `trusted` proxies, each appending one, leave `trusted`-th
+/// from the end; the oldest when the chain is shorter.
+fn pick<T: Copy>(hops: &[Option<T>], trusted: usize) -> Option<T> {
+    hops.get(hops.len().saturating_sub(trusted))
+        .copied()
+        .flatten()
+}
+
+/// Resolves the client behind `trusted` proxies in front of `peer`. `Some(0)`
+/// ignores the headers entirely; `None` takes the oldest parseable entry, which
+/// is only safe behind a proxy that overwrites them.
+pub fn resolve(peer: Option<IpAddr>, headers: &HeaderMap, trusted: Option<usize>) -> ClientInfo {
+    let (ip, scheme) = match trusted {
+        Some(0) => (None, None),
+        Some(n) => {
+            let (nodes, protos) = hops(headers);
+            (pick(&nodes, n), pick(&protos, n))
+        }
+        None => {
+            let (nodes, protos) = hops(headers);
+            (
+                nodes.into_iter().flatten().next(),
+                protos.into_iter().flatten().next(),
+            )
+        }
+    };
+    ClientInfo {
+        ip: ip.or(peer),
+        scheme: scheme.unwrap_or("http"),
+    }
+}
+
+/// Resolves [`ClientInfo`] for the rest of the stack. Installed outermost so
+/// every handler and the access log see the same answer.
+pub async fn middleware(
+    State(trusted): State<Option<usize>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let info = resolve(peer, req.headers(), trusted);
+    req.extensions_mut().insert(info);
+    let mut response = next.run(req).await;
+    response.extensions_mut().insert(info);
+    response
+}
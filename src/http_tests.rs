@@ -0,0 +1,4049 @@
+use super::*;
+use crate::breaker::BreakerState;
+use crate::config::ConfigError;
+use axum::body::Body;
+use rand::{rngs::StdRng, SeedableRng};
+use roulette::source::SourceError;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tower::ServiceExt as _;
+
+fn test_keys() -> Vec<String> {
+    vec![
+        "2022-01-01_00-00-00_UTC.jpg",
+        "2023-06-15_12-30-00_UTC.jpg",
+        "2024-01-01_00-00-00_UTC.jpg",
+        "2024-06-15_12-30-00_UTC.jpg",
+        "2025-01-01_00-00-00_UTC.jpg",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+fn test_state() -> Arc<AppState> {
+    Arc::new(test_app_state())
+}
+
+fn test_map_json() -> String {
+    let map: HashMap<String, String> = test_keys()
+        .into_iter()
+        .map(|k| {
+            let v = format!("{}.jpg", &k[..10]);
+            (k, v)
+        })
+        .collect();
+    serde_json::to_string(&map).unwrap()
+}
+
+fn test_app_state() -> AppState {
+    AppState {
+        base_path: String::new(),
+        url_prefix: "https://cdn.example.com".to_string(),
+        allowed_host: Some("cdn.example.com".to_string()),
+        prefix_pool: PrefixPool::default(),
+        signing_key: Some(b"secret".to_vec()),
+        admin_token: Some("admin".to_string()),
+        prefix_hosts: vec!["new-cdn.example.com".to_string()],
+        image_map: RwLock::new(ImageMap::parse(&test_map_json()).unwrap()),
+        last_modified: RwLock::new(
+            chrono::DateTime::parse_from_rfc3339("2024-10-10T13:55:36Z")
+                .unwrap()
+                .to_utc(),
+        ),
+        recency_decay: None,
+        max_weight: None,
+        min_biased_pool: None,
+        latest_skip_newest: 0,
+        bias_weighting: Weighting::Slice,
+        fallback_url: None,
+        allowed_bound: None,
+        reject_empty_bound: false,
+        default_response: ResponseFormat::Redirect,
+        retry_after: RetryAfterFormat::Seconds,
+        cache_jitter: 0.0,
+        strict_map: false,
+        drop_future_keys: false,
+        key_prefix_filter: None,
+        sessions: SessionStore::new(Duration::from_secs(3600), 100_000),
+        recently_served: RecentlyServed::new(Duration::from_secs(600)),
+        served_counts: ServedCounts::default(),
+        maintenance: AtomicBool::new(false),
+        shutdown: None,
+        rng: thread_rng_factory(),
+        clock: system_clock(),
+        metrics: Arc::default(),
+        breakers: Arc::new(Breakers::new(5, Duration::from_secs(30))),
+        deprecations: Arc::default(),
+        reload_check: None,
+        trusted_proxies: Some(0),
+        dow_boost: 2.0,
+        uniform_exclude_newest: None,
+        redirect_cache_buster: false,
+        field_names: FieldNames::default(),
+    }
+}
+
+/// `test_app_state` serving `map_json`, with the clock pinned to the RFC 3339
+/// `now` when given.
+fn state_with(map_json: &str, now: Option<&str>) -> AppState {
+    let mut state = AppState {
+        image_map: RwLock::new(ImageMap::parse(map_json).unwrap()),
+        ..test_app_state()
+    };
+    if let Some(now) = now {
+        let now = chrono::DateTime::parse_from_rfc3339(now).unwrap().to_utc();
+        state.clock = Box::new(move || now);
+    }
+    state
+}
+
+async fn get(uri: &str) -> Response {
+    get_with(test_state(), uri).await
+}
+
+async fn get_with(state: Arc<AppState>, uri: &str) -> Response {
+    send(state, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+async fn send(state: Arc<AppState>, req: Request) -> Response {
+    normalize(router(state)).oneshot(req).await.unwrap()
+}
+
+async fn body_json(resp: Response) -> serde_json::Value {
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn parse_recency_range() {
+    assert_eq!(parse_recency(None), Ok(1.0));
+    assert_eq!(parse_recency(Some(0.0)), Ok(0.0));
+    assert_eq!(parse_recency(Some(0.5)), Ok(0.5));
+    assert_eq!(parse_recency(Some(1.0)), Ok(1.0));
+    assert_eq!(parse_recency(Some(1.5)), Err(StatusCode::BAD_REQUEST));
+    assert_eq!(parse_recency(Some(-0.1)), Err(StatusCode::BAD_REQUEST));
+    assert_eq!(parse_recency(Some(f64::NAN)), Err(StatusCode::BAD_REQUEST));
+}
+
+#[test]
+fn decay_precedence() {
+    let mut state = test_app_state();
+    assert!((state.decay(1000, None) - scaled_decay(1000)).abs() < 1e-12);
+    assert_eq!(state.decay(1000, Some(0.2)), 0.2);
+    state.recency_decay = Some(0.1);
+    assert_eq!(state.decay(1000, None), 0.1);
+    assert_eq!(state.decay(1000, Some(0.2)), 0.2);
+}
+
+#[tokio::test]
+async fn latest_decay_override_is_validated() {
+    assert_eq!(
+        get("/image/latest?decay=0.5").await.status(),
+        StatusCode::FOUND
+    );
+    assert_eq!(
+        get("/image/latest?decay=-1").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[test]
+fn latest_halflife_sets_default_decay() {
+    let config = |vars: &[(&str, &str)]| {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        Config::from_lookup(|name| match name {
+            "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+            _ => vars.get(name).map(|v| v.to_string()),
+        })
+        .unwrap()
+    };
+    let halflife = config(&[("LATEST_HALFLIFE", "50")]);
+    assert_eq!(halflife.recency_decay, Some(halflife_decay(50.0)));
+    let both = config(&[("LATEST_HALFLIFE", "50"), ("RECENCY_DECAY", "0.2")]);
+    assert_eq!(both.recency_decay, Some(0.2));
+}
+
+#[tokio::test]
+async fn halflife_query_converts_and_yields_to_decay() {
+    for (query, expected) in [
+        ("halflife=2", halflife_decay(2.0)),
+        ("halflife=2&decay=0.3", 0.3),
+    ] {
+        let uri = format!("/debug/weights?{query}");
+        let body = body_json(send(test_state(), debug_request(&uri, Some("admin"))).await).await;
+        assert_eq!(body["decay"], expected, "{uri}");
+    }
+    for uri in ["/image/latest?halflife=0", "/image/latest?halflife=-3"] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+    assert_eq!(
+        get("/image/latest?halflife=5").await.status(),
+        StatusCode::FOUND
+    );
+}
+
+#[tokio::test]
+async fn top_lists_keys_by_descending_weight() {
+    let resp = get("/image/top?count=3&decay=1").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    let keys: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            "2025-01-01_00-00-00_UTC.jpg",
+            "2024-06-15_12-30-00_UTC.jpg",
+            "2024-01-01_00-00-00_UTC.jpg",
+        ]
+    );
+    assert_eq!(body[0]["url"], "https://cdn.example.com/2025-01-01.jpg");
+    let probabilities: Vec<f64> = (0..3)
+        .map(|i| body[i]["probability"].as_f64().unwrap())
+        .collect();
+    assert!(probabilities.windows(2).all(|w| w[0] > w[1]));
+    assert_eq!(get("/image/top").await.status(), StatusCode::OK);
+    for uri in [
+        "/image/top?count=0",
+        "/image/top?decay=-1",
+        "/image/top?max_weight=2",
+    ] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn months_only_select_requested_months() {
+    for route in ["/image/months", "/image/latest/months"] {
+        let mut seen = std::collections::BTreeSet::new();
+        for seed in 0..40 {
+            let uri = format!("{route}?m=06&seed={seed}&recency=0");
+            let resp = get(&uri).await;
+            assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+            seen.insert(
+                resp.headers()[header::LOCATION]
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        assert_eq!(
+            seen.into_iter().collect::<Vec<_>>(),
+            [
+                "https://cdn.example.com/2023-06-15.jpg",
+                "https://cdn.example.com/2024-06-15.jpg",
+            ],
+            "{route}"
+        );
+        let none = get(&format!("{route}?m=03,04")).await;
+        assert_eq!(none.status(), StatusCode::NOT_FOUND);
+        for bad in ["?m=13", "?m=", ""] {
+            let resp = get(&format!("{route}{bad}")).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{route}{bad}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn since_and_until_bound_selection() {
+    let seen = |uri: String| async move {
+        let mut seen = std::collections::BTreeSet::new();
+        for seed in 0..40 {
+            let resp = get(&format!("{uri}&seed={seed}")).await;
+            assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+            let location = resp.headers()[header::LOCATION].to_str().unwrap();
+            seen.insert(
+                location
+                    .trim_start_matches("https://cdn.example.com/")
+                    .to_string(),
+            );
+        }
+        seen.into_iter().collect::<Vec<_>>()
+    };
+    for route in ["/image", "/image/latest"] {
+        assert_eq!(
+            seen(format!("{route}?since=2024-06&recency=0")).await,
+            ["2024-06-15.jpg", "2025-01-01.jpg"]
+        );
+        assert_eq!(
+            seen(format!("{route}?until=2023&recency=0")).await,
+            ["2022-01-01.jpg"]
+        );
+        assert_eq!(
+            seen(format!("{route}?since=2023&until=2024-06&recency=0")).await,
+            ["2023-06-15.jpg", "2024-01-01.jpg"]
+        );
+        let empty = get(&format!("{route}?since=2025&until=2024")).await;
+        assert_eq!(empty.status(), StatusCode::NOT_FOUND);
+    }
+    for uri in [
+        "/image/after/2024?since=2023",
+        "/image/latest/after/2024?until=2025",
+        "/image?since=2024%2F10",
+        "/image/latest?until=",
+    ] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn latest_skip_drops_newest_keys() {
+    let locations = |uri: &'static str, state: Arc<AppState>| async move {
+        let mut seen = std::collections::BTreeSet::new();
+        for seed in 0..50 {
+            let resp = get_with(state.clone(), &format!("{uri}&seed={seed}")).await;
+            seen.insert(
+                resp.headers()[header::LOCATION]
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        seen
+    };
+    let all = locations("/image/latest?skip=0&recency=0", test_state()).await;
+    assert!(all.contains("https://cdn.example.com/2025-01-01.jpg"));
+    let partial = locations("/image/latest?skip=2&recency=0", test_state()).await;
+    assert_eq!(
+        partial.last().unwrap(),
+        "https://cdn.example.com/2024-01-01.jpg"
+    );
+    let after = locations("/image/latest/after/2024?skip=2&recency=0", test_state()).await;
+    assert_eq!(
+        after.into_iter().collect::<Vec<_>>(),
+        ["https://cdn.example.com/2024-01-01.jpg"]
+    );
+    for uri in ["/image/latest?skip=5", "/image/latest/after/2024?skip=3"] {
+        assert_eq!(get(uri).await.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+    let configured = Arc::new(AppState {
+        latest_skip_newest: 1,
+        ..test_app_state()
+    });
+    let seen = locations("/image/latest?recency=0", configured.clone()).await;
+    assert!(!seen.contains("https://cdn.example.com/2025-01-01.jpg"));
+    let overridden = locations("/image/latest?skip=0&recency=0", configured).await;
+    assert!(overridden.contains("https://cdn.example.com/2025-01-01.jpg"));
+}
+
+#[tokio::test]
+async fn min_biased_pool_widens_narrow_bounds() {
+    let locations = |state: Arc<AppState>| async move {
+        let mut seen = std::collections::HashSet::new();
+        for seed in 0..50 {
+            let uri = format!("/image/latest/after/2025?seed={seed}");
+            let resp = get_with(state.clone(), &uri).await;
+            seen.insert(
+                resp.headers()[header::LOCATION]
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        seen
+    };
+    assert_eq!(locations(test_state()).await.len(), 1);
+    let widened = Arc::new(AppState {
+        min_biased_pool: Some(3),
+        ..test_app_state()
+    });
+    let seen = locations(widened.clone()).await;
+    assert!(seen.len() > 1, "{seen:?}");
+    assert!(seen
+        .iter()
+        .all(|l| l.as_str() >= "https://cdn.example.com/2024-01-01.jpg"));
+    assert_eq!(
+        get_with(widened, "/image/latest/after/2030").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn latest_max_weight_is_validated() {
+    for (query, expected) in [
+        ("max_weight=0.3", StatusCode::FOUND),
+        ("max_weight=1", StatusCode::FOUND),
+        ("max_weight=0", StatusCode::BAD_REQUEST),
+        ("max_weight=1.5", StatusCode::BAD_REQUEST),
+    ] {
+        let uri = format!("/image/latest?{query}");
+        assert_eq!(get(&uri).await.status(), expected, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn latest_weighting_is_validated() {
+    for (uri, expected) in [
+        ("/image/latest?weighting=slice", StatusCode::FOUND),
+        ("/image/latest?weighting=global", StatusCode::FOUND),
+        ("/image/latest?weighting=global&count=2", StatusCode::OK),
+        (
+            "/image/latest/after/2024?weighting=global",
+            StatusCode::FOUND,
+        ),
+        ("/image/latest?weighting=newest", StatusCode::BAD_REQUEST),
+    ] {
+        assert_eq!(get(uri).await.status(), expected, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn global_weighting_scales_decay_to_the_whole_map() {
+    let decay = |weighting: &'static str| async move {
+        let uri = format!("/debug/weights?after=2024&weighting={weighting}");
+        let body = body_json(send(test_state(), debug_request(&uri, Some("admin"))).await).await;
+        assert_eq!(body["count"], 3);
+        body["decay"].as_f64().unwrap()
+    };
+    let (slice, global) = (decay("slice").await, decay("global").await);
+    assert!((slice - scaled_decay(3)).abs() < 1e-12, "{slice}");
+    assert!((global - scaled_decay(5)).abs() < 1e-12, "{global}");
+}
+
+#[tokio::test]
+async fn debug_weights_apply_max_weight() {
+    let mut state = test_app_state();
+    state.max_weight = Some(0.3);
+    let resp = send(
+        Arc::new(state),
+        debug_request("/debug/weights?decay=2&limit=100", Some("admin")),
+    )
+    .await;
+    let body = body_json(resp).await;
+    assert_eq!(body["max_weight"], 0.3);
+    let probabilities: Vec<f64> = body["weights"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|w| w["probability"].as_f64().unwrap())
+        .collect();
+    assert!(probabilities.iter().all(|&p| p <= 0.3 + 1e-12));
+    assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn access_log_combined_format() {
+    let time = chrono::DateTime::parse_from_rfc3339("2024-10-10T13:55:36Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let line = access_log::format_line(&access_log::Entry {
+        client: Some("203.0.113.7".parse().unwrap()),
+        time,
+        request_line: "GET /image?cache=1h HTTP/1.1",
+        status: 302,
+        bytes: Some(0),
+        referer: Some("https://example.com/"),
+        user_agent: Some("curl/8.0"),
+    });
+    assert_eq!(
+        line,
+        r#"203.0.113.7 - - [10/Oct/2024:13:55:36 +0000] "GET /image?cache=1h HTTP/1.1" 302 - "https://example.com/" "curl/8.0""#
+    );
+}
+
+#[test]
+fn access_log_missing_fields_are_dashes() {
+    let line = access_log::format_line(&access_log::Entry {
+        client: None,
+        time: chrono::Utc::now(),
+        request_line: "GET /health HTTP/1.1",
+        status: 200,
+        bytes: Some(3),
+        referer: None,
+        user_agent: Some(r#"evil"agent"#),
+    });
+    assert!(line.starts_with("- - - ["));
+    assert!(line.ends_with(r#"200 3 "-" "evil\"agent""#));
+}
+
+#[tokio::test]
+async fn trailing_slash_matches_unslashed() {
+    for (slashed, plain) in [
+        ("/image/", "/image"),
+        ("/image/latest/", "/image/latest"),
+        ("/image/after/2024/", "/image/after/2024"),
+    ] {
+        let slashed = get(slashed).await;
+        let plain = get(plain).await;
+        assert_eq!(slashed.status(), StatusCode::FOUND);
+        assert_eq!(slashed.status(), plain.status());
+    }
+}
+
+#[tokio::test]
+async fn malformed_bounds_are_rejected() {
+    let long = "2".repeat(roulette::MAX_BOUND_LEN + 1);
+    for uri in [
+        "/image/after/2024%2F10".to_string(),
+        "/image/latest/after/2024%2F10".to_string(),
+        "/image/after/%ZZ".to_string(),
+        format!("/image/after/{long}"),
+        "/image/index/0?after=".to_string(),
+        "/image/index/0?after=2024%2F10".to_string(),
+    ] {
+        assert_eq!(get(&uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+    for uri in ["/debug/weights?after=", "/debug/keys?around=%2F"] {
+        let resp = send(test_state(), debug_request(uri, Some("admin"))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+    assert_eq!(
+        get("/image/after/2024-10-10_13").await.status(),
+        StatusCode::FOUND
+    );
+}
+
+#[tokio::test]
+async fn empty_selection_uses_fallback_when_configured() {
+    let empty = [
+        "/image/after/2030",
+        "/image/latest/after/2030",
+        "/image/index/99",
+    ];
+    for uri in empty {
+        assert_eq!(get(uri).await.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+    let state = Arc::new(AppState {
+        fallback_url: Some("https://cdn.example.com/placeholder.jpg".to_string()),
+        ..test_app_state()
+    });
+    for uri in empty {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            "https://cdn.example.com/placeholder.jpg"
+        );
+    }
+    let req = Request::get("/image/after/2030")
+        .header(header::ACCEPT, "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let body = body_json(send(state.clone(), req).await).await;
+    assert_eq!(body["url"], "https://cdn.example.com/placeholder.jpg");
+    assert_eq!(body["fallback"], true);
+    let found = get_with(state, "/image/after/2024").await;
+    assert_ne!(
+        found.headers()[header::LOCATION],
+        "https://cdn.example.com/placeholder.jpg"
+    );
+}
+
+#[tokio::test]
+async fn allowed_bound_pattern_restricts_public_bounds() {
+    let state = Arc::new(AppState {
+        allowed_bound: Some(Regex::new(r"^\d{4}(-\d{2})?$").unwrap()),
+        ..test_app_state()
+    });
+    for uri in [
+        "/image/after/2024",
+        "/image/after/2024-06",
+        "/image/latest/after/2023",
+    ] {
+        assert_eq!(
+            get_with(state.clone(), uri).await.status(),
+            StatusCode::FOUND,
+            "{uri}"
+        );
+    }
+    for uri in [
+        "/image/after/2024-10-10_13",
+        "/image/latest/after/a",
+        "/image/index/0?after=2024-10-10",
+    ] {
+        assert_eq!(
+            get_with(state.clone(), uri).await.status(),
+            StatusCode::BAD_REQUEST,
+            "{uri}"
+        );
+    }
+    let debug = debug_request("/debug/weights?after=2024-10-10_13", Some("admin"));
+    assert_eq!(send(state, debug).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn trailing_slash_preserves_empty_result() {
+    assert_eq!(
+        get("/image/after/2030/").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+#[allow(clippy::await_holding_lock)]
+async fn reload_in_progress_returns_503() {
+    let state = test_state();
+    let _reloading = state.image_map.write().unwrap();
+    for uri in [
+        "/image",
+        "/image/after/2024",
+        "/image/latest",
+        "/image/latest/after/2024",
+    ] {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "1");
+    }
+}
+
+#[test]
+fn retry_after_formats() {
+    let now = chrono::DateTime::parse_from_rfc3339("2024-10-10T13:55:36Z")
+        .unwrap()
+        .to_utc();
+    let delay = Duration::from_millis(2500);
+    assert_eq!(RetryAfterFormat::Seconds.value(delay, now), "3");
+    assert_eq!(
+        RetryAfterFormat::Date.value(delay, now),
+        "Thu, 10 Oct 2024 13:55:39 GMT"
+    );
+    assert_eq!(
+        RetryAfterFormat::Seconds.value(Duration::from_secs(1), now),
+        "1"
+    );
+}
+
+#[tokio::test]
+#[allow(clippy::await_holding_lock)]
+async fn reload_in_progress_honors_retry_after_format() {
+    let state = Arc::new(AppState {
+        retry_after: RetryAfterFormat::Date,
+        ..test_app_state()
+    });
+    let _reloading = state.image_map.write().unwrap();
+    let resp = get_with(state.clone(), "/image").await;
+    let value = resp.headers()[header::RETRY_AFTER].to_str().unwrap();
+    assert!(
+        chrono::DateTime::parse_from_rfc2822(value).is_ok(),
+        "{}",
+        value
+    );
+}
+
+#[tokio::test]
+async fn serves_again_after_reload_completes() {
+    let state = test_state();
+    drop(state.image_map.write().unwrap());
+    assert_eq!(get_with(state, "/image").await.status(), StatusCode::FOUND);
+}
+
+#[tokio::test]
+async fn image_mode_matches_dedicated_routes() {
+    let location = |uri: String| async move {
+        get(&uri).await.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    for seed in 0..20 {
+        for (mode, dedicated) in [
+            ("/image?mode=uniform", "/image?x"),
+            ("/image?mode=biased&decay=0.5", "/image/latest?decay=0.5"),
+            (
+                "/image?mode=biased&after=2024",
+                "/image/latest/after/2024?x",
+            ),
+            ("/image?mode=uniform&after=2024", "/image?since=2024"),
+        ] {
+            assert_eq!(
+                location(format!("{mode}&seed={seed}")).await,
+                location(format!("{dedicated}&seed={seed}")).await,
+                "{mode}"
+            );
+        }
+    }
+    let resp = get("/image?mode=uniform&after=2030").await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = get("/image?mode=weighted&boost=sunset:3").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+}
+
+#[tokio::test]
+async fn image_mode_uniform_after_keeps_uniform_parameters() {
+    let content = r#"{
+        "2024-01-01_00-00-00_UTC.jpg": "a.jpg",
+        "2024-02-01_00-00-00_UTC.mp4": "b.mp4",
+        "2024-03-01_00-00-00_UTC.jpg": "c.jpg"
+    }"#;
+    let state = Arc::new(state_with(content, None));
+    for _ in 0..20 {
+        let resp = get_with(
+            state.clone(),
+            "/image?mode=uniform&after=2024-02&type=video",
+        )
+        .await;
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            "https://cdn.example.com/b.mp4"
+        );
+    }
+}
+
+#[tokio::test]
+async fn image_mode_daily_is_stable_within_a_day() {
+    let daily = |now: &str| {
+        let state = Arc::new(state_with(&test_map_json(), Some(now)));
+        async move {
+            get_with(state, "/image?mode=daily").await.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+    let morning = daily("2024-10-14T08:00:00Z").await;
+    assert_eq!(daily("2024-10-14T23:59:00Z").await, morning);
+    let mut seen = std::collections::HashSet::new();
+    for day in 1..=28 {
+        seen.insert(daily(&format!("2024-11-{day:02}T12:00:00Z")).await);
+    }
+    assert!(seen.len() > 1, "every day picked the same image");
+}
+
+#[tokio::test]
+async fn image_mode_rejects_invalid_combinations() {
+    for uri in [
+        "/image?mode=daily&seed=1",
+        "/image?mode=uniform&after=2024&since=2024",
+        "/image?mode=weighted&after=2024",
+        "/image?mode=weighted&since=2024",
+        "/image?mode=biased&after=2024&since=2024",
+        "/image?mode=weighted&boost=sunset",
+        "/image?after=a%2Fb",
+    ] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn dow_boosts_todays_weekday() {
+    // 2024-06-03 is a Monday, 2024-06-04 a Tuesday.
+    let content = r#"{
+        "2024-06-03_10-00-00_UTC.jpg": "monday.jpg",
+        "2024-06-04_10-00-00_UTC.jpg": "tuesday.jpg",
+        "no-timestamp.jpg": "undated.jpg"
+    }"#;
+    let counts = |today: &str, uri: &'static str| {
+        let state = Arc::new(state_with(content, Some(today)));
+        async move {
+            let mut counts = HashMap::new();
+            for _ in 0..300 {
+                let resp = get_with(state.clone(), uri).await;
+                let location = resp.headers()[header::LOCATION]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                *counts.entry(location).or_insert(0) += 1;
+            }
+            counts
+        }
+    };
+    let get_count = |counts: &HashMap<String, i32>, file: &str| {
+        counts
+            .get(&format!("https://cdn.example.com/{file}"))
+            .copied()
+            .unwrap_or(0)
+    };
+    let monday = counts("2024-06-10T08:00:00Z", "/image/dow?factor=20").await;
+    assert!(get_count(&monday, "monday.jpg") > 225, "{monday:?}");
+    assert!(get_count(&monday, "undated.jpg") > 0, "{monday:?}");
+    let tuesday = counts("2024-06-11T08:00:00Z", "/image/dow?factor=20").await;
+    assert!(get_count(&tuesday, "tuesday.jpg") > 225, "{tuesday:?}");
+    let sunday = counts("2024-06-09T08:00:00Z", "/image/dow?factor=20").await;
+    for file in ["monday.jpg", "tuesday.jpg", "undated.jpg"] {
+        assert!(get_count(&sunday, file) > 50, "{sunday:?}");
+    }
+    for uri in [
+        "/image/dow?factor=0",
+        "/image/dow?factor=-1",
+        "/image/dow?factor=x",
+    ] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn themed_rejects_malformed_boost() {
+    assert_eq!(
+        get("/image/themed?boost=sunset").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        get("/image/themed?boost=sunset:0").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        get("/image/themed?boost=sunset:3").await.status(),
+        StatusCode::FOUND
+    );
+    assert_eq!(get("/image/themed").await.status(), StatusCode::FOUND);
+}
+
+#[tokio::test]
+async fn tags_empty_array_without_metadata() {
+    let resp = get("/tags").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(body_json(resp).await, serde_json::json!([]));
+}
+
+#[test]
+fn negotiate_precedence() {
+    use ResponseFormat::*;
+    assert_eq!(negotiate(None, Redirect), Redirect);
+    assert_eq!(negotiate(None, Json), Json);
+    assert_eq!(negotiate(Some("*/*"), Json), Json);
+    assert_eq!(negotiate(Some("application/json"), Redirect), Json);
+    assert_eq!(
+        negotiate(Some("text/html;q=0.9, application/json"), Redirect),
+        Json
+    );
+    assert_eq!(negotiate(Some("text/html"), Json), Redirect);
+    assert_eq!(negotiate(Some("image/*"), Json), Redirect);
+}
+
+#[tokio::test]
+async fn accept_json_returns_selection_body() {
+    let req = Request::get("/image/latest")
+        .header(header::ACCEPT, "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let resp = send(test_state(), req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    let key = body["key"].as_str().unwrap();
+    assert!(test_keys().iter().any(|k| k == key));
+    assert_eq!(
+        body["url"],
+        format!("https://cdn.example.com/{}.jpg", &key[..10])
+    );
+}
+
+#[tokio::test]
+async fn default_response_json_honored_and_overridable() {
+    let state = || {
+        Arc::new(AppState {
+            default_response: ResponseFormat::Json,
+            ..test_app_state()
+        })
+    };
+    assert_eq!(get_with(state(), "/image").await.status(), StatusCode::OK);
+    let req = Request::get("/image")
+        .header(header::ACCEPT, "text/html")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(state(), req).await.status(), StatusCode::FOUND);
+}
+
+#[tokio::test]
+async fn index_route_resolves_within_after_slice() {
+    let resp = get("/image/index/0?after=2024-06").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://cdn.example.com/2024-06-15.jpg"
+    );
+    assert_eq!(get("/image/index/-1").await.status(), StatusCode::FOUND);
+    assert_eq!(get("/image/index/5").await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        get("/image/index/2?after=2024-06").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[test]
+fn prefix_host_requires_absolute_url() {
+    assert_eq!(
+        prefix_host("https://cdn.example.com/images").as_deref(),
+        Some("cdn.example.com")
+    );
+    assert_eq!(prefix_host("/images"), None);
+    assert_eq!(prefix_host("not a url"), None);
+}
+
+#[test]
+fn on_host_rejects_other_hosts() {
+    assert!(on_host("https://cdn.example.com/a.jpg", "cdn.example.com"));
+    assert!(!on_host("https://evil.com/a.jpg", "cdn.example.com"));
+    assert!(!on_host(
+        "https://cdn.example.com.evil.com/a.jpg",
+        "cdn.example.com"
+    ));
+    assert!(!on_host("garbage", "cdn.example.com"));
+}
+
+#[test]
+fn redirect_with_crafted_filename_stays_on_prefix_host() {
+    let state = test_state();
+    let map = HashMap::from([("k".to_string(), "../https://evil.com".to_string())]);
+    let resp = state.redirect(
+        "k",
+        &map,
+        &HashMap::new(),
+        None,
+        None,
+        ResponseFormat::Redirect,
+    );
+    let location = resp.headers()[header::LOCATION].to_str().unwrap();
+    assert!(on_host(location, "cdn.example.com"));
+}
+
+#[test]
+fn redirect_refuses_unexpected_host() {
+    let state = AppState {
+        url_prefix: "https://evil.com".to_string(),
+        ..test_app_state()
+    };
+    let map = HashMap::from([("k".to_string(), "a.jpg".to_string())]);
+    let resp = state.redirect(
+        "k",
+        &map,
+        &HashMap::new(),
+        None,
+        None,
+        ResponseFormat::Redirect,
+    );
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(resp.headers().get(header::LOCATION).is_none());
+}
+
+fn relative_state(url_prefix: &str) -> AppState {
+    AppState {
+        url_prefix: url_prefix.to_string(),
+        allowed_host: None,
+        ..test_app_state()
+    }
+}
+
+#[tokio::test]
+async fn absolute_and_relative_prefixes_set_location() {
+    let absolute = get("/image/index/0").await;
+    assert_eq!(
+        absolute.headers()[header::LOCATION],
+        "https://cdn.example.com/2022-01-01.jpg"
+    );
+    for (prefix, expected) in [
+        ("/images", "/images/2022-01-01.jpg"),
+        ("/images/", "/images/2022-01-01.jpg"),
+        ("/", "/2022-01-01.jpg"),
+    ] {
+        let resp = get_with(Arc::new(relative_state(prefix)), "/image/index/0").await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{prefix}");
+        assert_eq!(resp.headers()[header::LOCATION], expected, "{prefix}");
+    }
+}
+
+#[test]
+fn relative_prefix_refuses_off_origin_filenames() {
+    let state = relative_state("/");
+    for file in ["/evil.com/a.jpg", "\\evil.com/a.jpg"] {
+        let map = HashMap::from([("k".to_string(), file.to_string())]);
+        let resp = state.redirect(
+            "k",
+            &map,
+            &HashMap::new(),
+            None,
+            None,
+            ResponseFormat::Redirect,
+        );
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR, "{file}");
+    }
+}
+
+#[tokio::test]
+async fn json_selection_carries_permalink() {
+    let state = test_state();
+    let json = get_with(state.clone(), "/image?format=json").await;
+    let location = json.headers()[header::CONTENT_LOCATION]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = body_json(json).await;
+    let key = body["key"].as_str().unwrap();
+    assert_eq!(location, format!("/image/key/{key}"));
+
+    let permalink =
+        body_json(get_with(state.clone(), &format!("{location}?format=json")).await).await;
+    assert_eq!(permalink, body);
+    assert_eq!(
+        get_with(state.clone(), "/image?format=redirect")
+            .await
+            .headers()
+            .get(header::CONTENT_LOCATION),
+        None
+    );
+    assert_eq!(
+        get_with(state, "/image/key/missing.jpg").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn permalink_escapes_key_under_base_path() {
+    let state = Arc::new(AppState {
+        base_path: "/roulette".to_string(),
+        ..state_with(r#"{"a b#1.jpg": "a.jpg"}"#, None)
+    });
+    let json = get_with(state.clone(), "/roulette/image?format=json").await;
+    let location = json.headers()[header::CONTENT_LOCATION].to_str().unwrap();
+    assert_eq!(location, "/roulette/image/key/a%20b%231.jpg");
+    let resp = get_with(state, location).await;
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://cdn.example.com/a.jpg"
+    );
+}
+
+#[tokio::test]
+async fn embed_page_refreshes_image_at_interval() {
+    let body = |resp: Response| async move {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    };
+    let resp = get("/embed?interval=2m").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-store");
+    let page = body(resp).await;
+    assert!(page.contains(r#"<meta http-equiv="refresh" content="120">"#));
+    assert!(page.contains(r#"<img src="/image""#));
+    assert!(body(get("/embed").await).await.contains(r#"content="30""#));
+
+    let state = Arc::new(AppState {
+        base_path: "/a&b".to_string(),
+        ..test_app_state()
+    });
+    let page = body(get_with(state, "/a&b/embed").await).await;
+    assert!(page.contains(r#"<img src="/a&amp;b/image""#), "{page}");
+
+    for uri in ["/embed?interval=0s", "/embed?interval=soon"] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn format_param_each_value_on_image() {
+    let redirect = get("/image?format=redirect").await;
+    assert_eq!(redirect.status(), StatusCode::FOUND);
+
+    let json = get("/image?format=json").await;
+    assert_eq!(json.status(), StatusCode::OK);
+    assert_eq!(json.headers()[header::CONTENT_TYPE], "application/json");
+    let body = body_json(json).await;
+    assert!(body["url"]
+        .as_str()
+        .unwrap()
+        .starts_with("https://cdn.example.com/"));
+
+    let html = get("/image?format=html").await;
+    assert_eq!(html.status(), StatusCode::OK);
+    assert!(html.headers()[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let body = axum::body::to_bytes(html.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(r#"<meta property="og:image" content="https://cdn.example.com/"#));
+}
+
+#[tokio::test]
+async fn format_param_unknown_is_400() {
+    assert_eq!(
+        get("/image?format=xml").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        get("/image/latest?format=").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn format_param_overrides_accept() {
+    let req = Request::get("/image?format=redirect")
+        .header(header::ACCEPT, "application/json")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(test_state(), req).await.status(), StatusCode::FOUND);
+}
+
+#[test]
+fn view_page_escapes_values() {
+    let page = view_page("<script>", "https://cdn.example.com/a\"b.jpg");
+    assert!(!page.contains("<script>"));
+    assert!(page.contains("&lt;script&gt;"));
+    assert!(page.contains("a&quot;b.jpg"));
+}
+
+#[tokio::test]
+async fn response_carries_generated_request_id() {
+    let resp = get("/image").await;
+    let id = resp.headers()["x-request-id"].to_str().unwrap();
+    assert_eq!(id.len(), 36);
+}
+
+#[tokio::test]
+async fn incoming_request_id_is_preserved() {
+    let req = Request::get("/health")
+        .header("x-request-id", "abc-123")
+        .body(Body::empty())
+        .unwrap();
+    let resp = send(test_state(), req).await;
+    assert_eq!(resp.headers()["x-request-id"], "abc-123");
+}
+
+#[tokio::test]
+async fn evenly_returns_chronological_urls() {
+    let body = body_json(get("/image/evenly?count=2").await).await;
+    let keys: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        keys,
+        vec!["2022-01-01_00-00-00_UTC.jpg", "2024-01-01_00-00-00_UTC.jpg"]
+    );
+    assert_eq!(body[1]["url"], "https://cdn.example.com/2024-01-01.jpg");
+}
+
+#[tokio::test]
+async fn evenly_zero_or_missing_count_is_400() {
+    assert_eq!(
+        get("/image/evenly?count=0").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(get("/image/evenly").await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn normalize_base_path_forms() {
+    assert_eq!(normalize_base_path(""), "");
+    assert_eq!(normalize_base_path("/"), "");
+    assert_eq!(normalize_base_path("roulette"), "/roulette");
+    assert_eq!(normalize_base_path("/roulette/"), "/roulette");
+    assert_eq!(normalize_base_path("/a/b"), "/a/b");
+}
+
+#[tokio::test]
+async fn routes_served_under_base_path() {
+    let state = || {
+        Arc::new(AppState {
+            base_path: "/roulette".to_string(),
+            ..test_app_state()
+        })
+    };
+    assert_eq!(
+        get_with(state(), "/roulette/image").await.status(),
+        StatusCode::FOUND
+    );
+    assert_eq!(
+        get_with(state(), "/roulette/image/").await.status(),
+        StatusCode::FOUND
+    );
+    assert_eq!(
+        get_with(state(), "/roulette/health").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        get_with(state(), "/image").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn routes_served_at_root_without_base_path() {
+    assert_eq!(get("/image").await.status(), StatusCode::FOUND);
+    assert_eq!(get("/roulette/image").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn version_reports_hash_with_etag() {
+    let state = test_state();
+    let hash = format!("{:016x}", state.image_map.read().unwrap().content_hash);
+    let resp = get_with(state.clone(), "/version").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+    assert_eq!(etag, format!("\"{hash}\""));
+    let body = body_json(resp).await;
+    assert_eq!(body["hash"], hash);
+    assert_eq!(
+        body["count"],
+        state.image_map.read().unwrap().sorted_keys.len()
+    );
+    assert_eq!(body["loaded_at"], "2024-10-10T13:55:36Z");
+
+    for (if_none_match, expected) in [
+        (etag.as_str(), StatusCode::NOT_MODIFIED),
+        ("\"0000000000000000\"", StatusCode::OK),
+    ] {
+        let req = Request::get("/version")
+            .header(header::IF_NONE_MATCH, if_none_match)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(state.clone(), req).await.status(), expected);
+    }
+}
+
+#[tokio::test]
+async fn stats_reports_future_keys() {
+    let content = r#"{
+        "2024-01-01_00-00-00_UTC.jpg": "a.jpg",
+        "2999-01-01_00-00-00_UTC.jpg": "b.jpg"
+    }"#;
+    let state = Arc::new(state_with(content, None));
+    let body = body_json(get_with(state, "/stats").await).await;
+    assert_eq!(body["count"], 2);
+    assert_eq!(body["oldest"], "2024-01-01_00-00-00_UTC.jpg");
+    assert_eq!(body["newest"], "2999-01-01_00-00-00_UTC.jpg");
+    assert_eq!(body["future_keys"], 1);
+}
+
+#[test]
+fn parse_options_drop_future_sets_cutoff() {
+    assert!(parse_options(false, false, None).not_after.is_none());
+    assert!(parse_options(false, true, None).not_after.is_some());
+    assert!(parse_options(true, false, None).strict);
+}
+
+#[test]
+fn session_yields_each_index_once_then_exhausts() {
+    let store = SessionStore::new(Duration::from_secs(60), 100);
+    let now = Instant::now();
+    let token = store.create(false, now);
+    let mut seen: Vec<usize> = (0..5)
+        .map(|_| store.next(&token, 5, now).unwrap())
+        .collect();
+    seen.sort();
+    assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+    assert_eq!(store.next(&token, 5, now), Err(SessionError::Exhausted));
+}
+
+#[test]
+fn session_reshuffles_when_requested() {
+    let store = SessionStore::new(Duration::from_secs(60), 100);
+    let now = Instant::now();
+    let token = store.create(true, now);
+    for _ in 0..3 {
+        store.next(&token, 3, now).unwrap();
+    }
+    let mut second: Vec<usize> = (0..3)
+        .map(|_| store.next(&token, 3, now).unwrap())
+        .collect();
+    second.sort();
+    assert_eq!(second, vec![0, 1, 2]);
+}
+
+#[test]
+fn session_expires_after_ttl() {
+    let store = SessionStore::new(Duration::from_secs(60), 100);
+    let now = Instant::now();
+    let token = store.create(false, now);
+    assert!(store.next(&token, 5, now + Duration::from_secs(59)).is_ok());
+    let later = now + Duration::from_secs(59 + 61);
+    assert_eq!(store.next(&token, 5, later), Err(SessionError::Unknown));
+    assert_eq!(store.next("nope", 5, now), Err(SessionError::Unknown));
+}
+
+#[test]
+fn session_store_evicts_the_soonest_to_expire_at_its_limit() {
+    let store = SessionStore::new(Duration::from_secs(60), 2);
+    let now = Instant::now();
+    let first = store.create(false, now);
+    let second = store.create(false, now + Duration::from_secs(1));
+    let third = store.create(false, now + Duration::from_secs(2));
+    let at = now + Duration::from_secs(3);
+    assert_eq!(store.next(&first, 5, at), Err(SessionError::Unknown));
+    assert!(store.next(&second, 5, at).is_ok());
+    assert!(store.next(&third, 5, at).is_ok());
+}
+
+#[tokio::test]
+async fn session_routes_walk_the_collection() {
+    let state = test_state();
+    let first = get_with(state.clone(), "/image/session").await;
+    assert_eq!(first.status(), StatusCode::FOUND);
+    let token = first.headers()["x-session-token"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let mut locations = vec![first.headers()[header::LOCATION].clone()];
+    let next = format!("/image/session/{}/next", token);
+    for _ in 1..test_keys().len() {
+        let resp = get_with(state.clone(), &next).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        locations.push(resp.headers()[header::LOCATION].clone());
+    }
+    locations.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    locations.dedup();
+    assert_eq!(locations.len(), test_keys().len());
+    assert_eq!(
+        get_with(state.clone(), &next).await.status(),
+        StatusCode::GONE
+    );
+    assert_eq!(
+        get_with(state, "/image/session/unknown/next")
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn recent_route_counts_back_from_newest() {
+    let newest = get("/image/recent/1").await;
+    assert_eq!(
+        newest.headers()[header::LOCATION],
+        get("/image/index/-1").await.headers()[header::LOCATION]
+    );
+    let resp = get("/image/recent/2?after=2024-06").await;
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://cdn.example.com/2024-06-15.jpg"
+    );
+    for uri in [
+        "/image/recent/0",
+        "/image/recent/6",
+        "/image/recent/3?after=2024-06",
+    ] {
+        assert_eq!(get(uri).await.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+}
+
+#[test]
+fn discover_penalizes_just_served_keys() {
+    let keys = test_keys();
+    let now = Instant::now();
+    let mut rng = StdRng::seed_from_u64(7);
+    let trials = 2000;
+    let mut repeats = 0;
+    for _ in 0..trials {
+        let served = RecentlyServed::new(Duration::from_secs(600));
+        served.record(&keys[0], now);
+        if served.select(&keys, now, &mut rng) == Some(keys[0].as_str()) {
+            repeats += 1;
+        }
+    }
+    // Uniform would pick it a fifth of the time; 0.1 of 4.1 is under 3%.
+    assert!(repeats < trials / 20, "{repeats} of {trials}");
+}
+
+#[test]
+fn discover_penalty_decays_over_ttl() {
+    let keys = test_keys();
+    let served = RecentlyServed::new(Duration::from_secs(600));
+    let now = Instant::now();
+    served.record(&keys[1], now);
+    let weights = served.weights(&keys, now);
+    assert!((weights[1] - 0.1).abs() < 1e-9);
+    assert!(weights.iter().enumerate().all(|(i, &w)| i == 1 || w == 1.0));
+    let halfway = served.weights(&keys, now + Duration::from_secs(300));
+    assert!((halfway[1] - 0.55).abs() < 1e-9);
+    assert_eq!(
+        served.weights(&keys, now + Duration::from_secs(600))[1],
+        1.0
+    );
+}
+
+#[tokio::test]
+async fn discover_records_served_keys() {
+    let state = test_state();
+    let resp = get_with(state.clone(), "/image/discover?format=json").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let key = body_json(resp).await["key"].as_str().unwrap().to_string();
+    let keys = test_keys();
+    let weights = state.recently_served.weights(&keys, Instant::now());
+    let i = keys.iter().position(|k| *k == key).unwrap();
+    assert!(weights[i] < 1.0);
+}
+
+#[tokio::test]
+async fn negotiated_responses_vary_on_accept() {
+    for uri in [
+        "/image",
+        "/image?format=json",
+        "/image/after/2024",
+        "/image/latest",
+        "/image/index/0",
+        "/image/themed",
+    ] {
+        let resp = get(uri).await;
+        assert_eq!(resp.headers()[header::VARY], "Accept", "{}", uri);
+    }
+}
+
+struct MockSource {
+    content: Result<String, ()>,
+    changed: bool,
+}
+
+impl MapSource for MockSource {
+    async fn fetch(&self) -> Result<String, SourceError> {
+        self.content
+            .clone()
+            .map_err(|_| SourceError::Io(std::io::Error::other("unavailable")))
+    }
+
+    async fn changed(&self, _last_hash: u64) -> bool {
+        self.changed
+    }
+}
+
+fn mock(content: &str) -> MockSource {
+    MockSource {
+        content: Ok(content.to_string()),
+        changed: true,
+    }
+}
+
+#[tokio::test]
+async fn load_reads_from_source() {
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        "BASE_PATH" => Some("roulette/".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    let state = AppState::load(&config, &mock(r#"{"a.jpg": "b.jpg"}"#)).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, vec!["a.jpg"]);
+    assert_eq!(state.base_path, "/roulette");
+    assert_eq!(state.allowed_host.as_deref(), Some("cdn.example.com"));
+}
+
+#[test]
+fn config_accepts_relative_url_prefix() {
+    let config =
+        Config::from_lookup(|name| (name == "IMAGE_URL_PREFIX").then(|| "/images".to_string()))
+            .unwrap();
+    assert_eq!(config.url_prefix, "/images");
+}
+
+#[test]
+fn config_requires_url_prefix() {
+    assert_eq!(
+        Config::from_lookup(|_| None).err(),
+        Some(ConfigError("IMAGE_URL_PREFIX required".to_string()))
+    );
+}
+
+#[test]
+fn config_reports_invalid_values() {
+    for (name, value, message) in [
+        ("CACHE_JITTER", "150", "CACHE_JITTER must be a percentage"),
+        ("SESSION_TTL", "soon", "SESSION_TTL must be a duration"),
+        ("MAX_WEIGHT", "2", "MAX_WEIGHT must be a fraction"),
+        ("DEFAULT_RESPONSE", "xml", "DEFAULT_RESPONSE must be"),
+        (
+            "BREAKER_THRESHOLD",
+            "0",
+            "BREAKER_THRESHOLD must be a positive integer",
+        ),
+        (
+            "BREAKER_COOLDOWN",
+            "0s",
+            "BREAKER_COOLDOWN must be a duration",
+        ),
+        (
+            "IMAGE_URL_PREFIX_WEIGHTS",
+            "1,2",
+            "IMAGE_URL_PREFIX_WEIGHTS must be one positive weight per prefix",
+        ),
+        (
+            "BIAS_WEIGHTING",
+            "position",
+            "BIAS_WEIGHTING must be slice or global",
+        ),
+        (
+            "TLS_KEY_PATH",
+            "key.pem",
+            "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+        ),
+        (
+            "TLS_MIN_VERSION",
+            "1.1",
+            "TLS_MIN_VERSION must be 1.2 or 1.3",
+        ),
+        (
+            "MAX_SESSIONS",
+            "0",
+            "MAX_SESSIONS must be a positive integer",
+        ),
+        (
+            "RECENCY_DECAY",
+            "fast",
+            "RECENCY_DECAY must be a non-negative number",
+        ),
+        (
+            "RECENCY_DECAY",
+            "-0.5",
+            "RECENCY_DECAY must be a non-negative number",
+        ),
+        (
+            "RECENCY_DECAY",
+            "inf",
+            "RECENCY_DECAY must be a non-negative number",
+        ),
+        ("PORT", "http", "PORT must be a port number"),
+        ("PORT", "70000", "PORT must be a port number"),
+    ] {
+        let error = Config::from_lookup(|var| match var {
+            "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+            _ if var == name => Some(value.to_string()),
+            _ => None,
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().starts_with(message), "{}", error);
+    }
+}
+
+#[test]
+fn config_defaults_match_unset_variables() {
+    let parsed = Config::from_lookup(|name| {
+        (name == "IMAGE_URL_PREFIX").then(|| "https://cdn.example.com".to_string())
+    })
+    .unwrap();
+    let built = Config {
+        url_prefix: "https://cdn.example.com".to_string(),
+        ..Config::default()
+    };
+    assert_eq!(
+        serde_json::to_value(&parsed).unwrap(),
+        serde_json::to_value(&built).unwrap()
+    );
+    let state = AppState::new(&built, ImageMap::parse(r#"{"a.jpg": "a.jpg"}"#).unwrap());
+    assert_eq!(state.allowed_host.as_deref(), Some("cdn.example.com"));
+}
+
+#[test]
+fn config_rejects_protocol_relative_url_prefix() {
+    let error =
+        Config::from_lookup(|name| (name == "IMAGE_URL_PREFIX").then(|| "//evil.com".to_string()))
+            .err()
+            .unwrap();
+    assert!(error
+        .to_string()
+        .starts_with("IMAGE_URL_PREFIX must be an absolute URL or a path"));
+}
+
+#[tokio::test]
+async fn from_map_serves_the_given_map() {
+    let map = HashMap::from([
+        (
+            "2024-01-01_00-00-00_UTC.jpg".to_string(),
+            "a.jpg".to_string(),
+        ),
+        (
+            "2025-01-01_00-00-00_UTC.jpg".to_string(),
+            "b.jpg".to_string(),
+        ),
+    ]);
+    let state = Arc::new(AppState::from_map(
+        map,
+        "https://img.example.com".to_string(),
+    ));
+    let resp = get_with(state.clone(), "/image/after/2025").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://img.example.com/b.jpg"
+    );
+    let resp = get_with(state.clone(), "/image/latest").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    let body = body_json(get_with(state.clone(), "/stats").await).await;
+    assert_eq!(body["count"], 2);
+    reload_once(&state, &mock(r#"{"new.jpg": "n.jpg"}"#)).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, vec!["new.jpg"]);
+}
+
+#[tokio::test]
+async fn core_routes_redirect_under_url_prefix() {
+    let map = test_keys().into_iter().map(|k| (k.clone(), k)).collect();
+    let state = Arc::new(AppState::from_map(
+        map,
+        "https://img.example.com".to_string(),
+    ));
+    for uri in [
+        "/image",
+        "/image/after/2024",
+        "/image/latest",
+        "/image/latest/after/2024",
+    ] {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        let key = location.strip_prefix("https://img.example.com/").unwrap();
+        assert!(test_keys().iter().any(|k| k == key), "{uri}: {location}");
+        if uri.contains("/after/") {
+            assert!(key >= "2024", "{uri}: {key}");
+        }
+    }
+    for uri in ["/image/after/2030", "/image/latest/after/2030"] {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn from_map_with_empty_map_finds_nothing() {
+    let state = Arc::new(AppState::from_map(HashMap::new(), "/images".to_string()));
+    assert_eq!(
+        get_with(state, "/image").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn validate_map_reports_the_bad_entry() {
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        "STRICT_MAP" => Some("1".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(
+        validate_map(&config, &mock(r#"{"a.jpg": "a.jpg"}"#)).await,
+        Ok(1)
+    );
+    assert_eq!(
+        validate_map(&config, &mock(r#"{"a.jpg": "a\u0000.jpg"}"#)).await,
+        Err(r#"entry "a.jpg": filename "a\0.jpg" contains a control character"#.to_string())
+    );
+}
+
+#[tokio::test]
+async fn reconcile_map_compares_against_the_manifest() {
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    let source = mock(r#"{"a.jpg": "a.jpg", "b.jpg": "gone.jpg"}"#);
+    let reconciliation = reconcile_map(
+        &config,
+        &source,
+        "a.jpg
+extra.jpg
+",
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        reconciliation.missing,
+        vec![("b.jpg".to_string(), "gone.jpg".to_string())]
+    );
+    assert_eq!(reconciliation.orphaned, vec!["extra.jpg"]);
+    assert!(reconcile_map(&config, &mock("{"), "").await.is_err());
+}
+
+#[tokio::test]
+async fn reload_swaps_in_changed_map() {
+    let state = test_app_state();
+    reload_once(&state, &mock(r#"{"new.jpg": "n.jpg"}"#)).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, vec!["new.jpg"]);
+}
+
+#[tokio::test]
+async fn reload_skips_when_source_unchanged() {
+    let state = test_app_state();
+    let source = MockSource {
+        changed: false,
+        ..mock(r#"{"new.jpg": "n.jpg"}"#)
+    };
+    reload_once(&state, &source).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, test_keys());
+}
+
+#[tokio::test]
+async fn reload_keeps_map_on_fetch_error() {
+    let state = test_app_state();
+    let source = MockSource {
+        content: Err(()),
+        changed: true,
+    };
+    reload_once(&state, &source).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, test_keys());
+    assert_eq!(state.metrics.reload_failures(), 1);
+}
+
+#[tokio::test]
+async fn reload_keeps_serving_after_repeated_failures() {
+    let state = Arc::new(test_app_state());
+    let unavailable = MockSource {
+        content: Err(()),
+        changed: true,
+    };
+    reload_once(&state, &unavailable).await;
+    reload_once(&state, &mock("not json")).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, test_keys());
+    assert_eq!(
+        get_with(state.clone(), "/image").await.status(),
+        StatusCode::FOUND
+    );
+    let body = axum::body::to_bytes(get_with(state, "/metrics").await.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        text.contains("roulette_reload_failures_total 2\n"),
+        "{text}"
+    );
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn reload_validation_rejects_broken_map() {
+    let origin = Router::new().fallback(|uri: axum::http::Uri| async move {
+        if uri.path().starts_with("/good") {
+            StatusCode::OK
+        } else {
+            StatusCode::NOT_FOUND
+        }
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url_prefix = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+    let state = AppState {
+        url_prefix,
+        allowed_host: Some("127.0.0.1".to_string()),
+        reload_check: Some(ReloadCheck {
+            sample: 4,
+            max_failure_rate: 0.25,
+        }),
+        ..test_app_state()
+    };
+
+    let broken = r#"{"a.jpg": "good/a.jpg", "b.jpg": "gone/b.jpg", "c.jpg": "gone/c.jpg"}"#;
+    reload_once(&state, &mock(broken)).await;
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, test_keys());
+
+    let healthy = r#"{"a.jpg": "good/a.jpg", "b.jpg": "good/b.jpg"}"#;
+    reload_once(&state, &mock(healthy)).await;
+    assert_eq!(
+        state.image_map.read().unwrap().sorted_keys,
+        vec!["a.jpg", "b.jpg"]
+    );
+}
+
+#[test]
+fn breaker_opens_half_opens_and_closes() {
+    let breakers = Breakers::new(3, Duration::from_secs(30));
+    let states = |now| breakers.states(now);
+    let t0 = Instant::now();
+    breakers.failed("cdn.example.com", None, t0);
+    breakers.failed("cdn.example.com", None, t0);
+    assert_eq!(breakers.allow("cdn.example.com", t0), Ok(()));
+    assert_eq!(
+        states(t0),
+        [("cdn.example.com".to_string(), BreakerState::Closed)]
+    );
+
+    breakers.failed("cdn.example.com", None, t0);
+    assert_eq!(
+        breakers.allow("cdn.example.com", t0 + Duration::from_secs(10)),
+        Err(Duration::from_secs(20))
+    );
+    assert_eq!(breakers.allow("other.example.com", t0), Ok(()));
+
+    let t1 = t0 + Duration::from_secs(30);
+    assert_eq!(states(t1)[0].1, BreakerState::HalfOpen);
+    assert_eq!(breakers.allow("cdn.example.com", t1), Ok(()));
+    assert!(breakers.allow("cdn.example.com", t1).is_err(), "one probe");
+    breakers.failed("cdn.example.com", None, t1);
+    assert_eq!(states(t1)[0].1, BreakerState::Open);
+
+    let t2 = t1 + Duration::from_secs(30);
+    assert_eq!(breakers.allow("cdn.example.com", t2), Ok(()));
+    breakers.succeeded("cdn.example.com");
+    assert!(states(t2).is_empty());
+    assert_eq!(breakers.allow("cdn.example.com", t2), Ok(()));
+}
+
+#[test]
+fn breaker_honors_longer_retry_after() {
+    let breakers = Breakers::new(1, Duration::from_secs(30));
+    let now = Instant::now();
+    breakers.failed("a.example.com", Some(Duration::from_secs(120)), now);
+    breakers.failed("b.example.com", Some(Duration::from_secs(5)), now);
+    assert_eq!(
+        breakers.allow("a.example.com", now),
+        Err(Duration::from_secs(120))
+    );
+    assert_eq!(
+        breakers.allow("b.example.com", now),
+        Err(Duration::from_secs(30))
+    );
+}
+
+#[test]
+fn breaker_replaces_an_abandoned_probe() {
+    let breakers = Breakers::new(1, Duration::from_secs(30));
+    let t0 = Instant::now();
+    breakers.failed("cdn.example.com", None, t0);
+    let probe = t0 + Duration::from_secs(30);
+    assert_eq!(breakers.allow("cdn.example.com", probe), Ok(()));
+    assert_eq!(
+        breakers.allow("cdn.example.com", probe + Duration::from_secs(30)),
+        Ok(())
+    );
+}
+
+#[test]
+fn parses_upstream_retry_after() {
+    let now = chrono::DateTime::parse_from_rfc3339("2024-10-10T13:55:36Z")
+        .unwrap()
+        .to_utc();
+    assert_eq!(
+        breaker::parse_retry_after("120", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        breaker::parse_retry_after("Thu, 10 Oct 2024 13:56:36 GMT", now),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        breaker::parse_retry_after("Thu, 10 Oct 2024 13:00:00 GMT", now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(breaker::parse_retry_after("soon", now), None);
+}
+
+#[tokio::test]
+async fn metrics_report_breaker_state() {
+    let state = test_state();
+    for _ in 0..5 {
+        state
+            .breakers
+            .failed("cdn.example.com", None, Instant::now());
+    }
+    let body = axum::body::to_bytes(get_with(state, "/metrics").await.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        text.contains("roulette_upstream_breaker_state{host=\"cdn.example.com\"} 1"),
+        "{text}"
+    );
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn reload_validation_stops_at_an_open_breaker() {
+    let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = hits.clone();
+    let origin = Router::new().fallback(move || {
+        let counted = counted.clone();
+        async move {
+            counted.fetch_add(1, Ordering::Relaxed);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "60")],
+            )
+        }
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url_prefix = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+    let state = AppState {
+        url_prefix,
+        allowed_host: Some("127.0.0.1".to_string()),
+        reload_check: Some(ReloadCheck {
+            sample: 4,
+            max_failure_rate: 0.25,
+        }),
+        breakers: Arc::new(Breakers::new(2, Duration::from_secs(1))),
+        ..test_app_state()
+    };
+    let map = r#"{"a.jpg": "a.jpg", "b.jpg": "b.jpg", "c.jpg": "c.jpg", "d.jpg": "d.jpg"}"#;
+    reload_once(&state, &mock(map)).await;
+    let sent = hits.load(Ordering::Relaxed);
+    assert!(sent >= 2, "{sent}");
+    assert_eq!(
+        state
+            .breakers
+            .allow("127.0.0.1", Instant::now())
+            .map_err(|_| ()),
+        Err(())
+    );
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    reload_once(&state, &mock(map)).await;
+    assert_eq!(
+        hits.load(Ordering::Relaxed),
+        sent,
+        "Retry-After keeps it open"
+    );
+    assert_eq!(state.image_map.read().unwrap().sorted_keys, test_keys());
+}
+
+#[test]
+fn config_reads_validate_on_reload() {
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        "VALIDATE_ON_RELOAD" => Some("20".to_string()),
+        "VALIDATE_MAX_FAILURES" => Some("25".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(config.validate_sample, Some(20));
+    assert_eq!(config.validate_max_failures, 0.25);
+}
+
+#[test]
+fn config_rejects_validate_on_reload_with_relative_prefix() {
+    let result = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("/images".to_string()),
+        "VALIDATE_ON_RELOAD" => Some("20".to_string()),
+        _ => None,
+    });
+    assert_eq!(
+        result.err(),
+        Some(ConfigError(
+            "VALIDATE_ON_RELOAD requires an absolute IMAGE_URL_PREFIX".to_string()
+        ))
+    );
+}
+
+#[tokio::test]
+async fn probe_returns_no_content_with_key() {
+    let resp = get("/image?probe=1").await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(resp.headers().get(header::LOCATION).is_none());
+    let key = resp.headers()["x-image-key"].to_str().unwrap();
+    assert!(test_keys().iter().any(|k| k == key));
+
+    let empty = Arc::new(state_with("{}", None));
+    assert_eq!(
+        get_with(empty, "/image?probe=1").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn image_type_filter() {
+    let content = r#"{
+        "2024-01-01_00-00-00_UTC.jpg": "a.jpg",
+        "2024-02-01_00-00-00_UTC.mp4": "b.mp4"
+    }"#;
+    let state = Arc::new(state_with(content, None));
+    for _ in 0..20 {
+        let image = get_with(state.clone(), "/image?type=image").await;
+        assert_eq!(
+            image.headers()[header::LOCATION],
+            "https://cdn.example.com/a.jpg"
+        );
+        let video = get_with(state.clone(), "/image?type=video").await;
+        assert_eq!(
+            video.headers()[header::LOCATION],
+            "https://cdn.example.com/b.mp4"
+        );
+    }
+    let mut seen = std::collections::HashSet::new();
+    for seed in 0..20 {
+        let resp = get_with(state.clone(), &format!("/image?seed={seed}")).await;
+        seen.insert(resp.headers()[header::LOCATION].clone());
+    }
+    assert_eq!(seen.len(), 2, "unfiltered selection includes every type");
+    assert_eq!(
+        get_with(state, "/image?type=gif").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+fn state_at(now: &str) -> Arc<AppState> {
+    let map: HashMap<String, String> = (0..60)
+        .map(|i| (format!("{i:02}.jpg"), format!("{i}.jpg")))
+        .collect();
+    Arc::new(state_with(&serde_json::to_string(&map).unwrap(), Some(now)))
+}
+
+async fn week_keys(state: Arc<AppState>) -> Vec<String> {
+    let resp = get_with(state, "/image/week").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    body_json(resp)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["key"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn week_set_is_stable_within_a_week() {
+    let monday = week_keys(state_at("2024-10-07T00:00:00Z")).await;
+    let sunday = week_keys(state_at("2024-10-13T23:59:59Z")).await;
+    let next = week_keys(state_at("2024-10-14T00:00:00Z")).await;
+    assert_eq!(monday.len(), 7);
+    assert!(monday.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(monday, sunday);
+    assert_ne!(monday, next);
+    assert_eq!(
+        get("/image/week?count=0").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn distinct_week_skips_duplicate_hashes() {
+    let map: HashMap<String, serde_json::Value> = (0..20)
+        .map(|i| {
+            let hash = if i < 10 {
+                "dup".to_string()
+            } else {
+                format!("h{i}")
+            };
+            let entry = serde_json::json!({"file": format!("{i}.jpg"), "hash": hash});
+            (format!("{i:02}.jpg"), entry)
+        })
+        .collect();
+    let state = Arc::new(state_with(&serde_json::to_string(&map).unwrap(), None));
+    let resp = get_with(state, "/image/week?count=15&distinct=true").await;
+    let keys: Vec<String> = body_json(resp)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["key"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(keys.len(), 11);
+    assert_eq!(keys.iter().filter(|k| k.as_str() < "10").count(), 1);
+}
+
+fn sign(prefix: &str) -> String {
+    let mac = Hmac::<Sha256>::new_from_slice(b"secret")
+        .unwrap()
+        .chain_update(prefix)
+        .finalize()
+        .into_bytes();
+    mac.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[tokio::test]
+async fn signed_prefix_overrides_url_prefix() {
+    let prefix = "https://new-cdn.example.com/v2";
+    let uri = format!("/image/index/0?prefix={}&sig={}", prefix, sign(prefix));
+    let resp = get(&uri).await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://new-cdn.example.com/v2/2022-01-01.jpg"
+    );
+}
+
+#[tokio::test]
+async fn invalid_prefix_override_is_forbidden() {
+    let prefix = "https://new-cdn.example.com";
+    let forged = format!("/image?prefix={}&sig={}", prefix, sign("https://other"));
+    assert_eq!(get(&forged).await.status(), StatusCode::FORBIDDEN);
+    let unsigned = format!("/image?prefix={}", prefix);
+    assert_eq!(get(&unsigned).await.status(), StatusCode::FORBIDDEN);
+    let unlisted = "https://evil.example.com";
+    let uri = format!("/image?prefix={}&sig={}", unlisted, sign(unlisted));
+    assert_eq!(get(&uri).await.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn absent_prefix_uses_url_prefix() {
+    let resp = get("/image/index/0").await;
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://cdn.example.com/2022-01-01.jpg"
+    );
+}
+
+#[tokio::test]
+async fn seeded_latest_is_reproducible() {
+    for uri in [
+        "/image/latest?seed=42",
+        "/image/latest/after/2023?seed=42",
+        "/image?seed=42",
+    ] {
+        let first = get(uri).await.headers()[header::LOCATION].clone();
+        for _ in 0..5 {
+            assert_eq!(get(uri).await.headers()[header::LOCATION], first, "{}", uri);
+        }
+    }
+}
+
+#[tokio::test]
+async fn prefetch_hints_the_next_seeds_selection() {
+    for (path, seed) in [("/image", 42), ("/image/latest", 7), ("/image", u64::MAX)] {
+        let uri = format!("{path}?seed={seed}&prefetch=1");
+        let resp = get(&uri).await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+        let served = get(&format!("{path}?seed={seed}")).await;
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            served.headers()[header::LOCATION]
+        );
+        let next = get(&format!("{path}?seed={}", seed.wrapping_add(1))).await;
+        let next = next.headers()[header::LOCATION].to_str().unwrap();
+        assert_eq!(
+            resp.headers()[header::LINK],
+            format!("<{next}>; rel=\"prefetch\"").as_str(),
+            "{uri}"
+        );
+    }
+    let unhinted = get("/image?seed=42").await;
+    assert!(!unhinted.headers().contains_key(header::LINK));
+}
+
+#[tokio::test]
+async fn prefetch_adds_next_to_json_selections() {
+    let body = body_json(get("/image?seed=42&prefetch=1&format=json").await).await;
+    let next = body_json(get("/image?seed=43&format=json").await).await;
+    assert_eq!(body["next"], next);
+    assert!(body["key"].is_string());
+    let body = body_json(get("/image?seed=42&format=json").await).await;
+    assert!(body.get("next").is_none());
+}
+
+#[tokio::test]
+async fn count_by_buckets_each_granularity() {
+    let counts = |uri: &'static str| async move {
+        let resp = get(uri).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{uri}");
+        body_json(resp).await
+    };
+    assert_eq!(
+        counts("/image/count-by/year").await,
+        serde_json::json!([
+            { "bucket": "2022", "count": 1 },
+            { "bucket": "2023", "count": 1 },
+            { "bucket": "2024", "count": 2 },
+            { "bucket": "2025", "count": 1 },
+        ])
+    );
+    let months = counts("/image/count-by/month").await;
+    let buckets: Vec<&str> = months
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|b| b["bucket"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        buckets,
+        ["2022-01", "2023-06", "2024-01", "2024-06", "2025-01"]
+    );
+    assert_eq!(
+        counts("/image/count-by/day?after=2024&until=2025").await,
+        serde_json::json!([
+            { "bucket": "2024-01-01", "count": 1 },
+            { "bucket": "2024-06-15", "count": 1 },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn count_by_is_empty_past_the_last_key() {
+    let resp = get("/image/count-by/month?after=2030").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(body_json(resp).await, serde_json::json!([]));
+    for (uri, expected) in [
+        ("/image/count-by/week", StatusCode::NOT_FOUND),
+        ("/image/count-by/year?after=../x", StatusCode::BAD_REQUEST),
+        ("/image/count-by/year?until=", StatusCode::BAD_REQUEST),
+    ] {
+        assert_eq!(get(uri).await.status(), expected, "{uri}");
+    }
+}
+
+/// `(X-Selection-Mode, X-Selection-Tag, Location)` for seeds `0..100`.
+async fn surprises(state: Arc<AppState>) -> Vec<(String, Option<String>, String)> {
+    let mut seen = Vec::new();
+    for seed in 0..100 {
+        let resp = get_with(state.clone(), &format!("/image/surprise?seed={seed}")).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        seen.push((
+            header("x-selection-mode").unwrap(),
+            header("x-selection-tag"),
+            header("location").unwrap(),
+        ));
+    }
+    seen
+}
+
+#[tokio::test]
+async fn surprise_reports_the_mode_it_drew() {
+    let map = r#"{
+        "2022-01-01_00-00-00_UTC.jpg": {"file": "a.jpg", "tags": ["snow"]},
+        "2023-06-15_12-30-00_UTC.jpg": {"file": "b.jpg", "tags": ["beach"]},
+        "2024-01-01_00-00-00_UTC.jpg": "c.jpg",
+        "2024-06-15_12-30-00_UTC.jpg": {"file": "d.jpg", "tags": ["beach"]},
+        "2025-03-01_00-00-00_UTC.jpg": "e.jpg"
+    }"#;
+    let seen = surprises(Arc::new(state_with(map, Some("2026-01-01T12:00:00Z")))).await;
+    for (mode, tag, location) in &seen {
+        let file = location
+            .strip_prefix("https://cdn.example.com/")
+            .unwrap_or_else(|| panic!("{location}"));
+        match mode.as_str() {
+            "uniform" | "biased" => assert!("abcde".contains(&file[..1]), "{file}"),
+            "seasonal" | "on-this-day" => assert!(["a.jpg", "c.jpg"].contains(&file), "{file}"),
+            "tag-random" => match tag.as_deref() {
+                Some("snow") => assert_eq!(file, "a.jpg"),
+                Some("beach") => assert!(["b.jpg", "d.jpg"].contains(&file), "{file}"),
+                other => panic!("{other:?}"),
+            },
+            other => panic!("unexpected mode {other}"),
+        }
+        assert_eq!(tag.is_some(), mode == "tag-random");
+    }
+    let modes: std::collections::HashSet<&str> =
+        seen.iter().map(|(mode, _, _)| mode.as_str()).collect();
+    assert_eq!(modes.len(), 5, "{modes:?}");
+
+    let state = Arc::new(state_with(map, Some("2026-01-01T12:00:00Z")));
+    let first = get_with(state.clone(), "/image/surprise?seed=3").await;
+    let again = get_with(state, "/image/surprise?seed=3").await;
+    assert_eq!(
+        first.headers()["x-selection-mode"],
+        again.headers()["x-selection-mode"]
+    );
+    assert_eq!(
+        first.headers()[header::LOCATION],
+        again.headers()[header::LOCATION]
+    );
+}
+
+#[tokio::test]
+async fn surprise_falls_back_to_uniform_for_empty_modes() {
+    let map = r#"{"2022-05-05_00-00-00_UTC.jpg": "a.jpg", "2023-06-15_12-30-00_UTC.jpg": "b.jpg"}"#;
+    let seen = surprises(Arc::new(state_with(map, Some("2026-01-01T12:00:00Z")))).await;
+    let modes: std::collections::HashSet<&str> =
+        seen.iter().map(|(mode, _, _)| mode.as_str()).collect();
+    assert_eq!(
+        modes,
+        std::collections::HashSet::from(["uniform", "biased"])
+    );
+    let empty = Arc::new(state_with("{}", Some("2026-01-01T12:00:00Z")));
+    assert_eq!(
+        get_with(empty, "/image/surprise").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+fn debug_request(uri: &str, token: Option<&str>) -> Request {
+    let mut req = Request::get(uri);
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    req.body(Body::empty()).unwrap()
+}
+
+fn maintenance_request(on: &str, token: Option<&str>) -> Request {
+    let mut req = Request::post(format!("/admin/maintenance?on={on}"));
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    req.body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn maintenance_mode_drains_without_failing_health() {
+    let state = test_state();
+    let toggle = |on| send(state.clone(), maintenance_request(on, Some("admin")));
+    assert_eq!(
+        get_with(state.clone(), "/ready").await.status(),
+        StatusCode::OK
+    );
+
+    assert_eq!(toggle("true").await.status(), StatusCode::NO_CONTENT);
+    let resp = get_with(state.clone(), "/image").await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()[header::RETRY_AFTER], "30");
+    assert_eq!(
+        get_with(state.clone(), "/image/latest").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        get_with(state.clone(), "/health").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        get_with(state.clone(), "/ready").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    assert_eq!(toggle("false").await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        get_with(state.clone(), "/image").await.status(),
+        StatusCode::FOUND
+    );
+    assert_eq!(get_with(state, "/ready").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn maintenance_toggle_requires_admin_token() {
+    let state = test_state();
+    for (token, status) in [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some("wrong"), StatusCode::UNAUTHORIZED),
+    ] {
+        let resp = send(state.clone(), maintenance_request("true", token)).await;
+        assert_eq!(resp.status(), status);
+    }
+    assert!(!state.in_maintenance());
+    let resp = send(state.clone(), maintenance_request("maybe", Some("admin"))).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let unconfigured = Arc::new(AppState {
+        admin_token: None,
+        ..test_app_state()
+    });
+    assert_eq!(
+        send(unconfigured, maintenance_request("true", Some("admin")))
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+/// Collects the formatted `audit` events emitted on this thread while the
+/// returned guard is held.
+fn capture_audit_log() -> (Arc<Mutex<Vec<u8>>>, tracing::subscriber::DefaultGuard) {
+    #[derive(Clone)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let writer = Captured(buffer.clone());
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("audit=info")
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    (buffer, tracing::subscriber::set_default(subscriber))
+}
+
+#[tokio::test]
+async fn admin_actions_are_audited_without_the_token() {
+    let (buffer, _guard) = capture_audit_log();
+    let state = test_state();
+    let resp = send(state.clone(), maintenance_request("true", Some("admin"))).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    send(state.clone(), maintenance_request("false", Some("wrong"))).await;
+    let shutdown = Request::post("/admin/shutdown")
+        .header(header::AUTHORIZATION, "Bearer admin")
+        .body(Body::empty())
+        .unwrap();
+    send(state.clone(), shutdown).await;
+
+    let log = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 3, "{log}");
+    let admin_id = audit::token_id("admin");
+    for (line, action, outcome) in [
+        (lines[0], "maintenance", "ok"),
+        (lines[1], "maintenance", "unauthorized"),
+        (lines[2], "shutdown", "unavailable"),
+    ] {
+        assert!(line.contains(" audit: admin action"), "{line}");
+        assert!(line.contains(&format!("action=\"{action}\"")), "{line}");
+        assert!(line.contains(&format!("outcome=\"{outcome}\"")), "{line}");
+    }
+    assert!(
+        lines[0].contains(&format!("token_id=\"{admin_id}\"")),
+        "{log}"
+    );
+    assert!(lines[0].contains("detail=\"on\""), "{log}");
+    assert!(lines[1].contains(&audit::token_id("wrong")), "{log}");
+    assert!(
+        !log.contains("admin\"") && !log.contains("wrong\""),
+        "{log}"
+    );
+}
+
+#[tokio::test]
+async fn debug_routes_are_audited() {
+    let (buffer, _guard) = capture_audit_log();
+    let state = test_state();
+    for (uri, token) in [
+        ("/debug/keys", "wrong"),
+        ("/debug/weights", "wrong"),
+        ("/debug/echo", "wrong"),
+        ("/debug/echo", "admin"),
+        ("/debug/keys?window=0", "admin"),
+        ("/debug/weights?recency=2", "admin"),
+        ("/debug/weights", "admin"),
+    ] {
+        let req = Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        send(state.clone(), req).await;
+    }
+
+    let log = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 7, "{log}");
+    for (line, action, outcome) in [
+        (lines[0], "debug_keys", "unauthorized"),
+        (lines[1], "debug_weights", "unauthorized"),
+        (lines[2], "debug_echo", "unauthorized"),
+        (lines[3], "debug_echo", "ok"),
+        (lines[4], "debug_keys", "bad_request"),
+        (lines[5], "debug_weights", "bad_request"),
+        (lines[6], "debug_weights", "ok"),
+    ] {
+        assert!(line.contains(&format!("action=\"{action}\"")), "{line}");
+        assert!(line.contains(&format!("outcome=\"{outcome}\"")), "{line}");
+    }
+}
+
+fn deprecated_state(spec: &str, base_path: &str) -> Arc<AppState> {
+    Arc::new(AppState {
+        base_path: base_path.to_string(),
+        deprecations: Arc::new(Deprecations::parse(spec, base_path).unwrap()),
+        ..test_app_state()
+    })
+}
+
+#[tokio::test]
+async fn deprecated_routes_carry_headers() {
+    let spec = "/random=2026-12-31=/image, /random/after/{bound}";
+    let state = deprecated_state(spec, "");
+    let resp = get_with(state.clone(), "/random").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()["deprecation"], "true");
+    assert_eq!(resp.headers()["sunset"], "Thu, 31 Dec 2026 00:00:00 GMT");
+    assert_eq!(
+        resp.headers()[header::LINK],
+        "</image>; rel=\"successor-version\""
+    );
+    let after = get_with(state.clone(), "/random/after/2024").await;
+    assert_eq!(after.headers()["deprecation"], "true");
+    assert!(after.headers().get("sunset").is_none());
+    assert!(after.headers().get(header::LINK).is_none());
+    let current = get_with(state, "/image").await;
+    assert!(current.headers().get("deprecation").is_none());
+
+    let nested = deprecated_state("/random", "/roulette");
+    let resp = get_with(nested, "/roulette/random").await;
+    assert_eq!(resp.headers()["deprecation"], "true");
+}
+
+#[test]
+fn deprecations_reject_malformed_specs() {
+    for spec in ["random", "/random=31-12-2026", "/random=2026-02-30"] {
+        assert!(Deprecations::parse(spec, "").is_none(), "{spec}");
+    }
+    assert!(Deprecations::parse("/random==/image", "").is_some());
+}
+
+#[tokio::test]
+async fn debug_echo_reports_forwarded_client() {
+    let mut req = debug_request("/debug/echo", Some("admin"));
+    req.headers_mut().insert(
+        "x-forwarded-for",
+        HeaderValue::from_static("unknown, 203.0.113.7, 10.0.0.1"),
+    );
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 41000))));
+    let leftmost = Arc::new(AppState {
+        trusted_proxies: None,
+        ..test_app_state()
+    });
+    let resp = send(leftmost, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    assert_eq!(body["remote_addr"], "10.0.0.2:41000");
+    assert_eq!(body["client_ip"], "203.0.113.7");
+    assert_eq!(
+        body["headers"]["x-forwarded-for"],
+        "unknown, 203.0.113.7, 10.0.0.1"
+    );
+    assert!(body["headers"].get("authorization").is_none());
+}
+
+#[tokio::test]
+async fn forwarded_headers_are_ignored_without_trusted_proxies() {
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    let state = Arc::new(AppState {
+        trusted_proxies: config.trusted_proxies,
+        ..test_app_state()
+    });
+    let mut req = debug_request("/debug/echo", Some("admin"));
+    req.headers_mut()
+        .insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 41000))));
+    let body = body_json(send(state, req).await).await;
+    assert_eq!(body["client_ip"], "10.0.0.2");
+}
+
+#[test]
+fn config_reads_trusted_proxies() {
+    let trusted = |value: &'static str| {
+        Config::from_lookup(move |name| match name {
+            "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+            "TRUSTED_PROXIES" => Some(value.to_string()),
+            _ => None,
+        })
+        .map(|config| config.trusted_proxies)
+    };
+    assert_eq!(trusted("2").unwrap(), Some(2));
+    assert_eq!(trusted("leftmost").unwrap(), None);
+    assert!(trusted("-1").is_err());
+}
+
+#[tokio::test]
+async fn debug_echo_requires_admin_token() {
+    assert_eq!(
+        send(test_state(), debug_request("/debug/echo", None))
+            .await
+            .status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let unconfigured = Arc::new(AppState {
+        admin_token: None,
+        ..test_app_state()
+    });
+    assert_eq!(
+        send(unconfigured, debug_request("/debug/echo", Some("admin")))
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[test]
+fn client_ip_falls_back_to_peer() {
+    let peer = IpAddr::from([192, 0, 2, 1]);
+    let mut headers = HeaderMap::new();
+    let ip = |headers: &HeaderMap, peer, trusted| forwarded::resolve(peer, headers, trusted).ip;
+    assert_eq!(ip(&headers, Some(peer), None), Some(peer));
+    headers.insert("x-forwarded-for", HeaderValue::from_static("garbage"));
+    assert_eq!(ip(&headers, Some(peer), None), Some(peer));
+    assert_eq!(ip(&headers, Some(peer), Some(1)), Some(peer));
+    assert_eq!(ip(&headers, None, None), None);
+}
+
+#[test]
+fn trusted_proxies_pick_from_the_right() {
+    let peer = Some(IpAddr::from([10, 0, 0, 2]));
+    let mut headers = HeaderMap::new();
+    // The client sent a forged entry; two trusted proxies appended theirs.
+    headers.insert(
+        "x-forwarded-for",
+        HeaderValue::from_static("198.51.100.9, 203.0.113.7, 10.0.0.1"),
+    );
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+    let resolve = |trusted| forwarded::resolve(peer, &headers, Some(trusted));
+    assert_eq!(
+        resolve(0),
+        ClientInfo {
+            ip: peer,
+            scheme: "http"
+        }
+    );
+    assert_eq!(resolve(1).ip, Some(IpAddr::from([10, 0, 0, 1])));
+    assert_eq!(resolve(2).ip, Some(IpAddr::from([203, 0, 113, 7])));
+    assert_eq!(resolve(2).scheme, "https");
+    // More proxies configured than entries: the oldest entry.
+    assert_eq!(resolve(5).ip, Some(IpAddr::from([198, 51, 100, 9])));
+}
+
+#[test]
+fn forwarded_header_takes_precedence() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.9"));
+    headers.append(
+        "forwarded",
+        HeaderValue::from_static(r#"for="[2001:db8::1]:4711";proto=https"#),
+    );
+    headers.append(
+        "forwarded",
+        HeaderValue::from_static("for=192.0.2.60:80;proto=http;by=10.0.0.1"),
+    );
+    let resolve = |trusted| forwarded::resolve(None, &headers, Some(trusted));
+    assert_eq!(
+        resolve(2),
+        ClientInfo {
+            ip: Some("2001:db8::1".parse().unwrap()),
+            scheme: "https",
+        }
+    );
+    assert_eq!(
+        resolve(1),
+        ClientInfo {
+            ip: Some(IpAddr::from([192, 0, 2, 60])),
+            scheme: "http",
+        }
+    );
+    headers.insert(
+        "forwarded",
+        HeaderValue::from_static("for=_hidden, for=unknown"),
+    );
+    assert_eq!(forwarded::resolve(None, &headers, Some(1)).ip, None);
+}
+
+#[tokio::test]
+async fn debug_echo_uses_trusted_proxy_count() {
+    let state = Arc::new(AppState {
+        trusted_proxies: Some(1),
+        ..test_app_state()
+    });
+    let mut req = debug_request("/debug/echo", Some("admin"));
+    req.headers_mut().insert(
+        "x-forwarded-for",
+        HeaderValue::from_static("198.51.100.9, 203.0.113.7"),
+    );
+    req.headers_mut()
+        .insert("x-forwarded-proto", HeaderValue::from_static("HTTPS"));
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 41000))));
+    let body = body_json(send(state, req).await).await;
+    assert_eq!(body["client_ip"], "203.0.113.7");
+    assert_eq!(body["scheme"], "https");
+}
+
+#[tokio::test]
+async fn debug_keys_returns_window_around_bound() {
+    let resp = send(
+        test_state(),
+        debug_request("/debug/keys?around=2024&window=2", Some("admin")),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    assert_eq!(body["count"], 5);
+    assert_eq!(body["first"], "2022-01-01_00-00-00_UTC.jpg");
+    assert_eq!(body["last"], "2025-01-01_00-00-00_UTC.jpg");
+    assert_eq!(
+        body["around"],
+        serde_json::json!([
+            {"key": "2023-06-15_12-30-00_UTC.jpg", "file": "2023-06-15.jpg"},
+            {"key": "2024-01-01_00-00-00_UTC.jpg", "file": "2024-01-01.jpg"},
+        ])
+    );
+}
+
+#[tokio::test]
+async fn debug_keys_requires_admin_token() {
+    let send_debug = |token| send(test_state(), debug_request("/debug/keys", token));
+    assert_eq!(send_debug(None).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        send_debug(Some("wrong")).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(send_debug(Some("admin")).await.status(), StatusCode::OK);
+    let unconfigured = Arc::new(AppState {
+        admin_token: None,
+        ..test_app_state()
+    });
+    assert_eq!(
+        send(unconfigured, debug_request("/debug/keys", Some("admin")))
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn cache_jitter_stays_within_band() {
+    let state = Arc::new(AppState {
+        cache_jitter: 0.1,
+        ..test_app_state()
+    });
+    for _ in 0..50 {
+        let resp = get_with(state.clone(), "/image/latest?cache=1h").await;
+        let value = resp.headers()[header::CACHE_CONTROL].to_str().unwrap();
+        let secs: u64 = value
+            .strip_prefix("public, max-age=")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((3240..=3960).contains(&secs), "{}", value);
+    }
+}
+
+#[cfg(feature = "montage")]
+#[tokio::test]
+async fn montage_composites_grid() {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::from_pixel(40, 30, image::Rgb([200, 10, 10]))
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    let png = png.into_inner();
+    let origin = Router::new().fallback(move || {
+        let png = png.clone();
+        async move { ([(header::CONTENT_TYPE, "image/png")], png) }
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url_prefix = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+    let state = Arc::new(AppState {
+        url_prefix,
+        allowed_host: Some("127.0.0.1".to_string()),
+        ..test_app_state()
+    });
+
+    let resp = get_with(state.clone(), "/montage?count=4&cols=2&output=png").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/png");
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let sheet = image::load_from_memory(&body).unwrap();
+    assert_eq!(
+        (sheet.width(), sheet.height()),
+        (2 * montage::TILE_SIZE, 2 * montage::TILE_SIZE)
+    );
+
+    for uri in [
+        "/montage?count=0",
+        "/montage?count=100",
+        "/montage?count=4&cols=5",
+    ] {
+        assert_eq!(
+            get_with(state.clone(), uri).await.status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            uri
+        );
+    }
+}
+
+fn conditional_request(uri: &str, since: &str) -> Request {
+    Request::get(uri)
+        .header(header::IF_MODIFIED_SINCE, since)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn listings_honor_if_modified_since() {
+    for uri in ["/tags", "/image/evenly?count=2"] {
+        let resp = get(uri).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(
+            resp.headers()[header::LAST_MODIFIED],
+            "Thu, 10 Oct 2024 13:55:36 GMT"
+        );
+        for since in [
+            "Thu, 10 Oct 2024 13:55:36 GMT",
+            "Fri, 11 Oct 2024 00:00:00 GMT",
+        ] {
+            let resp = send(test_state(), conditional_request(uri, since)).await;
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{} {}", uri, since);
+        }
+        let stale = conditional_request(uri, "Thu, 10 Oct 2024 13:55:35 GMT");
+        assert_eq!(send(test_state(), stale).await.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn seq_is_stable_per_seed_and_varies_with_n() {
+    let location = |uri: String| async move {
+        let resp = get(&uri).await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{}", uri);
+        resp.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(
+        location("/image/seq?seed=42&n=5".to_string()).await,
+        location("/image/seq?seed=42&n=5".to_string()).await
+    );
+    assert_eq!(
+        location("/image/seq?seed=42&n=1".to_string()).await,
+        location("/image?seed=42".to_string()).await
+    );
+    assert_eq!(
+        location("/image/seq?seed=42&n=5".to_string()).await,
+        "https://cdn.example.com/2023-06-15.jpg"
+    );
+    let mut draws = std::collections::HashSet::new();
+    for n in 1..=10 {
+        draws.insert(location(format!("/image/seq?seed=42&n={n}")).await);
+    }
+    assert!(draws.len() > 1, "{:?}", draws);
+
+    for uri in [
+        "/image/seq?seed=42&n=0",
+        "/image/seq?seed=42&n=10001",
+        "/image/seq?n=5",
+        "/image/seq?seed=-1&n=5",
+    ] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+}
+
+async fn short_link_json(state: Arc<AppState>) -> serde_json::Value {
+    let resp = get_with(state, "/image/short").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    body_json(resp).await
+}
+
+#[tokio::test]
+async fn short_links_resolve_to_their_key() {
+    let state = test_state();
+    for _ in 0..10 {
+        let link = short_link_json(state.clone()).await;
+        let code = link["code"].as_str().unwrap();
+        assert_eq!(link["path"], format!("/s/{code}"));
+        let resp = get_with(state.clone(), link["path"].as_str().unwrap()).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let key = link["key"].as_str().unwrap();
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            format!("https://cdn.example.com/{}.jpg", &key[..10])
+        );
+    }
+
+    let unsigned = Arc::new(AppState {
+        signing_key: None,
+        ..test_app_state()
+    });
+    let resp = get_with(unsigned.clone(), "/s/0").await;
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://cdn.example.com/2022-01-01.jpg"
+    );
+    let resp = get_with(unsigned.clone(), "/s/4").await;
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://cdn.example.com/2025-01-01.jpg"
+    );
+    for uri in ["/s/5", "/s/00", "/s/a-b", "/s/LygHa16AHYG"] {
+        assert_eq!(
+            get_with(unsigned.clone(), uri).await.status(),
+            StatusCode::NOT_FOUND,
+            "{}",
+            uri
+        );
+    }
+}
+
+#[tokio::test]
+async fn signed_short_codes_reject_tampering_and_go_stale() {
+    let state = test_state();
+    let link = short_link_json(state.clone()).await;
+    let code = link["code"].as_str().unwrap().to_string();
+    let (index, tag) = code.split_at(code.len() - 6);
+    let other = (decode_base62(index).unwrap() + 1) % 5;
+    for forged in [
+        index.to_string(),
+        format!("{}{tag}", encode_base62(other)),
+        format!("{index}000000"),
+    ] {
+        assert_eq!(
+            get_with(state.clone(), &format!("/s/{forged}"))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND,
+            "{}",
+            forged
+        );
+    }
+
+    reload_once(
+        &state,
+        &mock(r#"{"a.jpg": "a.jpg", "b.jpg": "b.jpg", "c.jpg": "c.jpg", "d.jpg": "d.jpg", "e.jpg": "e.jpg"}"#),
+    )
+    .await;
+    assert_eq!(
+        get_with(state.clone(), &format!("/s/{code}"))
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+    let fresh = short_link_json(state.clone()).await;
+    let resp = get_with(state, fresh["path"].as_str().unwrap()).await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+}
+
+#[tokio::test]
+async fn injected_rng_drives_selection() {
+    let state = || {
+        Arc::new(AppState {
+            rng: Box::new(|| Box::new(rand::rngs::mock::StepRng::new(0, 0))),
+            ..test_app_state()
+        })
+    };
+    for uri in ["/image", "/image/after/2024", "/image/themed"] {
+        let resp = get_with(state(), uri).await;
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        let expected = if uri.contains("after") {
+            "https://cdn.example.com/2024-01-01.jpg"
+        } else {
+            "https://cdn.example.com/2022-01-01.jpg"
+        };
+        assert_eq!(location, expected, "{}", uri);
+    }
+}
+
+fn mirrored_config(weights: Option<&str>) -> Config {
+    Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => {
+            Some("https://a.example.com, https://b.example.com/images".to_string())
+        }
+        "IMAGE_URL_PREFIX_WEIGHTS" => weights.map(String::from),
+        _ => None,
+    })
+    .unwrap()
+}
+
+async fn redirect_hosts(state: Arc<AppState>, requests: usize) -> Vec<String> {
+    let mut hosts = Vec::new();
+    for _ in 0..requests {
+        let resp = get_with(state.clone(), "/image").await;
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        hosts.push(prefix_host(location).unwrap());
+    }
+    hosts
+}
+
+#[tokio::test]
+async fn url_prefixes_take_turns() {
+    let config = mirrored_config(None);
+    assert_eq!(config.url_prefix, "https://a.example.com");
+    let state = Arc::new(AppState {
+        prefix_pool: config.prefix_pool(),
+        ..test_app_state()
+    });
+    let hosts = redirect_hosts(state, 20).await;
+    for pair in hosts.chunks(2) {
+        assert_eq!(pair, ["a.example.com", "b.example.com"]);
+    }
+    let resp = get_with(
+        Arc::new(AppState {
+            prefix_pool: config.prefix_pool(),
+            ..test_app_state()
+        }),
+        "/image/key/2022-01-01_00-00-00_UTC.jpg",
+    )
+    .await;
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://a.example.com/2022-01-01.jpg"
+    );
+}
+
+#[tokio::test]
+async fn weighted_url_prefixes_follow_the_rng() {
+    let seeded = |seed| {
+        Arc::new(AppState {
+            prefix_pool: mirrored_config(Some("1, 3")).prefix_pool(),
+            rng: Box::new(move || Box::new(StdRng::seed_from_u64(seed))),
+            ..test_app_state()
+        })
+    };
+    assert_eq!(
+        redirect_hosts(seeded(3), 10).await,
+        redirect_hosts(seeded(3), 10).await
+    );
+    let state = Arc::new(AppState {
+        prefix_pool: mirrored_config(Some("1, 3")).prefix_pool(),
+        ..test_app_state()
+    });
+    let hosts = redirect_hosts(state, 400).await;
+    let b = hosts.iter().filter(|h| *h == "b.example.com").count();
+    assert!(b > 200 && b < 400, "{}", b);
+}
+
+#[test]
+fn single_url_prefix_has_no_pool() {
+    let config = Config::from_lookup(|name| {
+        (name == "IMAGE_URL_PREFIX").then(|| "https://cdn.example.com".to_string())
+    })
+    .unwrap();
+    assert!(config.mirror_prefixes.is_empty());
+    assert!(config.prefix_pool().pick(rand::thread_rng).is_none());
+}
+
+#[test]
+fn several_url_prefixes_must_be_absolute() {
+    let error = Config::from_lookup(|name| {
+        (name == "IMAGE_URL_PREFIX").then(|| "https://cdn.example.com,/images".to_string())
+    })
+    .err()
+    .unwrap();
+    assert_eq!(
+        error.to_string(),
+        "IMAGE_URL_PREFIX must list only absolute URLs when it has several"
+    );
+}
+
+#[tokio::test]
+async fn debug_weights_match_weights_for() {
+    let resp = send(
+        test_state(),
+        debug_request("/debug/weights?after=2023&decay=0.5&limit=2", Some("admin")),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    assert_eq!(body["count"], 4);
+    assert_eq!(body["decay"], 0.5);
+    let expected = weights_for(4, 0.5, 1.0);
+    let weights = body["weights"].as_array().unwrap();
+    assert_eq!(weights.len(), 2);
+    assert_eq!(weights[1]["key"], "2025-01-01_00-00-00_UTC.jpg");
+    assert_eq!(weights[0]["probability"], expected[2]);
+    assert_eq!(weights[1]["probability"], expected[3]);
+
+    let unauthorized = send(test_state(), debug_request("/debug/weights", None)).await;
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn metrics_histogram_buckets_are_cumulative() {
+    let metrics = Metrics::default();
+    metrics.observe("/image", 302, 0.000_2);
+    metrics.observe("/image", 302, 0.003);
+    metrics.observe("/image", 302, 5.0);
+    let text = metrics.render();
+    let series = r#"roulette_request_duration_seconds_bucket{route="/image",status="302""#;
+    assert!(text.contains(&format!(r#"{series},le="0.0001"}} 0"#)));
+    assert!(text.contains(&format!(r#"{series},le="0.00025"}} 1"#)));
+    assert!(text.contains(&format!(r#"{series},le="0.005"}} 2"#)));
+    assert!(text.contains(&format!(r#"{series},le="1"}} 2"#)));
+    assert!(text.contains(&format!(r#"{series},le="+Inf"}} 3"#)));
+    assert!(
+        text.contains(r#"roulette_request_duration_seconds_count{route="/image",status="302"} 3"#)
+    );
+}
+
+#[tokio::test]
+async fn metrics_record_matched_route_and_status() {
+    let state = test_state();
+    get_with(state.clone(), "/image/after/2024").await;
+    get_with(state.clone(), "/image/after/2030").await;
+    get_with(state.clone(), "/no-such-route").await;
+    let resp = get_with(state, "/metrics").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    let count = |status| {
+        format!(
+            r#"roulette_request_duration_seconds_count{{route="/image/after/{{bound}}",status="{status}"}} 1"#
+        )
+    };
+    assert!(text.contains(&count(302)), "{text}");
+    assert!(text.contains(&count(404)), "{text}");
+    assert!(!text.contains("no-such-route"));
+}
+
+fn collect_refs(value: &serde_json::Value, refs: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(serde_json::Value::String(r)) = map.get("$ref") {
+                refs.push(r.clone());
+            }
+            map.values().for_each(|v| collect_refs(v, refs));
+        }
+        serde_json::Value::Array(items) => items.iter().for_each(|v| collect_refs(v, refs)),
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn openapi_document_is_valid() {
+    let resp = get("/openapi.json").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    let doc: utoipa::openapi::OpenApi = serde_json::from_value(body.clone()).unwrap();
+    assert!(body["openapi"].as_str().unwrap().starts_with("3."));
+    for path in [
+        "/image",
+        "/image/latest/after/{bound}",
+        "/version",
+        "/debug/weights",
+    ] {
+        assert!(doc.paths.paths.contains_key(path), "{path}");
+    }
+    let schemas = &body["components"]["schemas"];
+    let mut refs = Vec::new();
+    collect_refs(&body, &mut refs);
+    assert!(!refs.is_empty());
+    for r in refs {
+        let name = r.strip_prefix("#/components/schemas/").unwrap();
+        assert!(schemas.get(name).is_some(), "dangling {r}");
+    }
+}
+
+#[tokio::test]
+async fn selection_reports_server_timing() {
+    for uri in [
+        "/image",
+        "/image/after/2024",
+        "/image/latest",
+        "/image/latest/after/2024",
+        "/image/index/0",
+        "/image/themed",
+        "/image/discover",
+        "/image/after/2030",
+    ] {
+        let resp = get(uri).await;
+        let value = resp.headers()["server-timing"].to_str().unwrap();
+        let dur: f64 = value
+            .strip_prefix("select;dur=")
+            .unwrap_or_else(|| panic!("{}: {}", uri, value))
+            .parse()
+            .unwrap();
+        assert!(dur >= 0.0, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn latest_with_overflowing_decay_still_selects() {
+    assert_eq!(
+        get("/image/latest?decay=1e6").await.status(),
+        StatusCode::FOUND
+    );
+}
+
+#[test]
+fn config_serializes_with_secrets_redacted() {
+    let vars: HashMap<&str, &str> = [
+        ("IMAGE_URL_PREFIX", "https://cdn.example.com"),
+        ("ADMIN_TOKEN", "hunter2"),
+        ("SIGNING_KEY", "s3cret"),
+        ("PORT", "9000"),
+        ("SESSION_TTL", "5m"),
+        ("DEFAULT_RESPONSE", "json"),
+    ]
+    .into_iter()
+    .collect();
+    let config = Config::from_lookup(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["admin_token"], "<redacted>");
+    assert_eq!(json["signing_key"], "<redacted>");
+    assert_eq!(json["port"], 9000);
+    assert_eq!(json["session_ttl_secs"], 300);
+    assert_eq!(json["default_response"], "json");
+    assert!(json["map_path"].is_null());
+    assert!(!json.to_string().contains("hunter2"));
+}
+
+fn variant_state() -> Arc<AppState> {
+    let json = r#"{
+        "2024-01-01_00-00-00_UTC.jpg": {"full": "a-full.jpg", "thumb": "a-thumb.jpg"},
+        "2025-01-01_00-00-00_UTC.jpg": "b.jpg"
+    }"#;
+    Arc::new(state_with(json, None))
+}
+
+#[tokio::test]
+async fn variant_selects_file() {
+    let location = |resp: Response| {
+        resp.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    let thumb = get_with(variant_state(), "/image/index/0?variant=thumb").await;
+    assert_eq!(location(thumb), "https://cdn.example.com/a-thumb.jpg");
+    let full = get_with(variant_state(), "/image/index/0").await;
+    assert_eq!(location(full), "https://cdn.example.com/a-full.jpg");
+    let legacy = get_with(variant_state(), "/image/index/1?variant=full").await;
+    assert_eq!(location(legacy), "https://cdn.example.com/b.jpg");
+}
+
+#[tokio::test]
+async fn missing_variant_is_not_found() {
+    for (uri, status) in [
+        ("/image/index/1?variant=thumb", StatusCode::NOT_FOUND),
+        ("/image?variant=medium", StatusCode::NOT_FOUND),
+        ("/image?variant=", StatusCode::BAD_REQUEST),
+    ] {
+        assert_eq!(
+            get_with(variant_state(), uri).await.status(),
+            status,
+            "{}",
+            uri
+        );
+    }
+}
+
+#[cfg(feature = "transform")]
+#[test]
+fn transform_fit_width_preserves_aspect() {
+    assert_eq!(transform::fit_width(4000, 3000, 400), (400, 300));
+    assert_eq!(transform::fit_width(1000, 333, 500), (500, 167));
+    assert_eq!(transform::fit_width(3000, 1, 100), (100, 1));
+    // Never enlarged.
+    assert_eq!(transform::fit_width(300, 200, 400), (300, 200));
+}
+
+#[cfg(feature = "transform")]
+#[test]
+fn transform_output_prefers_request_then_source() {
+    use image::ImageFormat;
+    use imaging::Output;
+    assert_eq!(
+        transform::select_output(Some(Output::Png), Some(ImageFormat::Jpeg)),
+        Output::Png
+    );
+    assert_eq!(
+        transform::select_output(None, Some(ImageFormat::Png)),
+        Output::Png
+    );
+    assert_eq!(
+        transform::select_output(None, Some(ImageFormat::Gif)),
+        Output::Jpeg
+    );
+    assert_eq!(transform::select_output(None, None), Output::Jpeg);
+    assert_eq!(Output::parse("webp"), None);
+}
+
+#[cfg(feature = "transform")]
+#[tokio::test]
+async fn transform_resizes_and_rejects_unsupported_sources() {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::from_pixel(80, 60, image::Rgb([10, 200, 10]))
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    let png = png.into_inner();
+    let origin = Router::new()
+        .route("/bad.jpg", axum::routing::get(|| async { "not an image" }))
+        .fallback(move || {
+            let png = png.clone();
+            async move { ([(header::CONTENT_TYPE, "image/png")], png) }
+        });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url_prefix = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+    let state = Arc::new(AppState {
+        url_prefix,
+        allowed_host: Some("127.0.0.1".to_string()),
+        ..state_with(r#"{"a": "a.png", "bad": "bad.jpg"}"#, None)
+    });
+
+    let resp = get_with(state.clone(), "/image/key/a/transform?w=40").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/png");
+    assert_eq!(
+        resp.headers()[header::CACHE_CONTROL],
+        format!("public, max-age={}", transform::MAX_AGE)
+    );
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let img = image::load_from_memory(&body).unwrap();
+    assert_eq!((img.width(), img.height()), (40, 30));
+
+    let resp = get_with(state.clone(), "/image/key/a/transform?w=40&fmt=jpeg").await;
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/jpeg");
+
+    assert_eq!(
+        get_with(state.clone(), "/image/key/bad/transform?w=40")
+            .await
+            .status(),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    for (uri, status) in [
+        ("/image/key/a/transform?w=0", StatusCode::BAD_REQUEST),
+        ("/image/key/a/transform?w=99999", StatusCode::BAD_REQUEST),
+        (
+            "/image/key/a/transform?w=40&fmt=webp",
+            StatusCode::BAD_REQUEST,
+        ),
+        ("/image/key/missing/transform?w=40", StatusCode::NOT_FOUND),
+    ] {
+        assert_eq!(
+            get_with(state.clone(), uri).await.status(),
+            status,
+            "{}",
+            uri
+        );
+    }
+}
+
+/// A test state whose `cdn.example.com` circuit is open for another 30s.
+#[cfg(any(feature = "montage", feature = "transform"))]
+fn tripped_state() -> Arc<AppState> {
+    let state = test_state();
+    for _ in 0..5 {
+        state
+            .breakers
+            .failed("cdn.example.com", None, Instant::now());
+    }
+    state
+}
+
+#[cfg(feature = "montage")]
+#[tokio::test]
+async fn montage_fast_fails_on_open_breaker() {
+    let resp = get_with(tripped_state(), "/montage?count=2&cols=2").await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()[header::RETRY_AFTER], "30");
+}
+
+#[cfg(feature = "transform")]
+#[tokio::test]
+async fn transform_fast_fails_on_open_breaker() {
+    let resp = get_with(
+        tripped_state(),
+        "/image/key/2022-01-01_00-00-00_UTC.jpg/transform?w=40",
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()[header::RETRY_AFTER], "30");
+}
+
+#[cfg(feature = "qr")]
+#[tokio::test]
+async fn qr_returns_png() {
+    for uri in ["/qr", "/qr/after/2024?module=4&ec=H"] {
+        let resp = get(uri).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/png");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let png = image::load_from_memory_with_format(&body, image::ImageFormat::Png).unwrap();
+        assert!(png.width() <= qr::MAX_DIMENSION, "{}", uri);
+    }
+    assert!(qr::render_png(&"x".repeat(500), 32, qrcode::EcLevel::H).is_none());
+    for uri in ["/qr?module=0", "/qr?module=33", "/qr?ec=X"] {
+        assert_eq!(get(uri).await.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+    assert_eq!(get("/qr/after/2030").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn min_age_skips_recent_keys() {
+    let map: HashMap<String, String> = [
+        "2024-10-10_11-00-00.jpg",
+        "2024-10-10_02-00-00.jpg",
+        "legacy.jpg",
+    ]
+    .into_iter()
+    .map(|key| (key.to_string(), key.to_string()))
+    .collect();
+    let state = Arc::new(state_with(
+        &serde_json::to_string(&map).unwrap(),
+        Some("2024-10-10T12:00:00Z"),
+    ));
+    for route in ["/image", "/image/latest", "/image/latest/after/2024-01-01"] {
+        let mut seen = std::collections::HashSet::new();
+        for seed in 0..40 {
+            let uri = format!("{route}?min_age=6h&seed={seed}");
+            let resp = get_with(state.clone(), &uri).await;
+            assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+            let location = resp.headers()[header::LOCATION].to_str().unwrap();
+            seen.insert(location.rsplit('/').next().unwrap().to_string());
+        }
+        assert_eq!(seen.len(), 2, "{route}");
+        assert!(seen.contains("legacy.jpg"), "{route}");
+        assert!(!seen.contains("2024-10-10_11-00-00.jpg"), "{route}");
+    }
+    for (uri, status) in [
+        (
+            "/image?since=2024-10-10_06&until=2024-10-11&min_age=6h",
+            StatusCode::NOT_FOUND,
+        ),
+        ("/image?min_age=soon", StatusCode::BAD_REQUEST),
+        ("/image/latest?min_age=soon", StatusCode::BAD_REQUEST),
+    ] {
+        assert_eq!(get_with(state.clone(), uri).await.status(), status, "{uri}");
+    }
+}
+
+fn exclude_newest_state(exclude: ExcludeNewest) -> Arc<AppState> {
+    let map: HashMap<String, String> = (6..12)
+        .map(|hour| format!("2024-10-10_{hour:02}-00-00.jpg"))
+        .chain(["legacy.jpg".to_string()])
+        .map(|key| (key.clone(), key))
+        .collect();
+    Arc::new(AppState {
+        uniform_exclude_newest: Some(exclude),
+        ..state_with(
+            &serde_json::to_string(&map).unwrap(),
+            Some("2024-10-10T12:00:00Z"),
+        )
+    })
+}
+
+async fn uniform_picks(state: Arc<AppState>, query: &str) -> std::collections::BTreeSet<String> {
+    let mut seen = std::collections::BTreeSet::new();
+    for seed in 0..60 {
+        let resp = get_with(state.clone(), &format!("/image?seed={seed}{query}")).await;
+        if resp.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        seen.insert(location.rsplit('/').next().unwrap().to_string());
+    }
+    seen
+}
+
+#[tokio::test]
+async fn uniform_exclude_newest_by_count() {
+    let state = exclude_newest_state(ExcludeNewest::Count(2));
+    // `legacy.jpg` sorts last, so it is one of the two newest keys.
+    let seen = uniform_picks(state.clone(), "").await;
+    assert_eq!(seen.len(), 5);
+    assert!(!seen.contains("legacy.jpg"));
+    assert!(!seen.contains("2024-10-10_11-00-00.jpg"));
+    assert!(seen.contains("2024-10-10_10-00-00.jpg"));
+    let seen = uniform_picks(state.clone(), "&since=2024-10-10_10").await;
+    assert_eq!(seen, ["2024-10-10_10-00-00.jpg".to_string()].into());
+    let resp = get_with(state.clone(), "/image?since=2024-10-10_11").await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = get_with(state, "/image/latest/after/2024-10-10_11").await;
+    assert_eq!(resp.status(), StatusCode::FOUND, "latest is unaffected");
+}
+
+#[tokio::test]
+async fn uniform_exclude_newest_by_age() {
+    let state = exclude_newest_state(ExcludeNewest::AgeSecs(3 * 3600));
+    let seen = uniform_picks(state.clone(), "").await;
+    assert!(seen.contains("legacy.jpg"));
+    assert!(seen.contains("2024-10-10_09-00-00.jpg"));
+    assert!(!seen.contains("2024-10-10_10-00-00.jpg"));
+    assert!(!seen.contains("2024-10-10_11-00-00.jpg"));
+    // The stricter of the configured window and `?min_age=` applies.
+    let seen = uniform_picks(state.clone(), "&min_age=1h").await;
+    assert!(!seen.contains("2024-10-10_10-00-00.jpg"));
+    let seen = uniform_picks(state, "&min_age=5h").await;
+    assert!(!seen.contains("2024-10-10_08-00-00.jpg"));
+    assert!(seen.contains("2024-10-10_07-00-00.jpg"));
+}
+
+#[test]
+fn config_reads_uniform_exclude_newest() {
+    let config = |value: &'static str| {
+        Config::from_lookup(move |name| match name {
+            "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+            "UNIFORM_EXCLUDE_NEWEST" => Some(value.to_string()),
+            _ => None,
+        })
+        .unwrap()
+        .uniform_exclude_newest
+    };
+    assert_eq!(config("5"), Some(ExcludeNewest::Count(5)));
+    assert_eq!(config("6h"), Some(ExcludeNewest::AgeSecs(21600)));
+    assert!(std::panic::catch_unwind(|| config("soon")).is_err());
+    assert!(std::panic::catch_unwind(|| config("9000000000000000000s")).is_err());
+    assert!(std::panic::catch_unwind(|| config("9999999999999999d")).is_err());
+}
+
+#[tokio::test]
+async fn similar_redirects_to_a_key_sharing_tags() {
+    let map = r#"{
+        "a.jpg": {"file": "a.jpg", "tags": ["sunset", "beach"]},
+        "b.jpg": {"file": "b.jpg", "tags": ["beach"]},
+        "c.jpg": {"file": "c.jpg", "tags": ["city"]},
+        "d.jpg": "d.jpg"
+    }"#;
+    let state = Arc::new(state_with(map, None));
+    let resp = get_with(state.clone(), "/image/similar/a.jpg").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert!(resp.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .ends_with("/b.jpg"));
+    for key in ["c.jpg", "d.jpg", "missing.jpg"] {
+        let resp = get_with(state.clone(), &format!("/image/similar/{key}")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{key}");
+    }
+}
+
+#[tokio::test]
+async fn tag_after_selects_from_the_intersection() {
+    let map = r#"{
+        "2022.jpg": {"file": "a.jpg", "tags": ["sunset"]},
+        "2023.jpg": {"file": "b.jpg", "tags": ["beach"]},
+        "2024.jpg": {"file": "c.jpg", "tags": ["sunset"]},
+        "2025.jpg": "d.jpg"
+    }"#;
+    let state = Arc::new(state_with(map, None));
+    for uri in [
+        "/image/tag/sunset/after/2023",
+        "/image/latest/tag/sunset/after/2023",
+    ] {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+        assert!(resp.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .ends_with("/c.jpg"));
+    }
+    for uri in [
+        "/image/tag/sunset/after/2025",
+        "/image/tag/beach/after/2024",
+        "/image/latest/tag/mountain/after/2000",
+    ] {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+    let resp = get_with(state, "/image/tag/sunset/after/%00").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn meta_and_selections_report_dimensions() {
+    let map = r#"{
+        "a.jpg": {"file": "a.jpg", "tags": ["beach", "sunset"], "width": 1600, "height": 900},
+        "b.mp4": "b.mp4"
+    }"#;
+    let state = Arc::new(state_with(map, None));
+    let resp = get_with(state.clone(), "/meta/a.jpg").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(resp).await,
+        serde_json::json!({
+            "key": "a.jpg",
+            "file": "a.jpg",
+            "type": "image",
+            "tags": ["beach", "sunset"],
+            "width": 1600,
+            "height": 900
+        })
+    );
+    assert_eq!(
+        body_json(get_with(state.clone(), "/meta/b.mp4").await).await,
+        serde_json::json!({"key": "b.mp4", "file": "b.mp4", "type": "video", "tags": []})
+    );
+    let resp = get_with(state.clone(), "/meta/missing.jpg").await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = get_with(state.clone(), "/image/key/a.jpg").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()["x-image-width"], "1600");
+    assert_eq!(resp.headers()["x-image-height"], "900");
+    let resp = get_with(state, "/image/key/b.mp4").await;
+    assert!(resp.headers().get("x-image-width").is_none());
+}
+
+fn pinned_state(now: &str) -> Arc<AppState> {
+    let map = r#"{
+        "2024-01-01_00-00-00.jpg": {"file": "pinned.jpg", "pins": [
+            {"start": "2024-10-01T00:00:00Z", "end": "2024-10-08T00:00:00Z"}
+        ]},
+        "2024-09-01_00-00-00.jpg": "a.jpg",
+        "2024-09-02_00-00-00.jpg": "b.jpg",
+        "2024-09-03_00-00-00.jpg": "c.jpg"
+    }"#;
+    Arc::new(state_with(map, Some(now)))
+}
+
+async fn latest_locations(state: Arc<AppState>) -> std::collections::HashSet<String> {
+    let mut seen = std::collections::HashSet::new();
+    for seed in 0..30 {
+        let resp = get_with(state.clone(), &format!("/image/latest?seed={seed}")).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        seen.insert(
+            resp.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+    }
+    seen
+}
+
+#[tokio::test]
+async fn latest_serves_the_active_pin() {
+    let active = latest_locations(pinned_state("2024-10-03T00:00:00Z")).await;
+    assert_eq!(active.len(), 1);
+    assert!(active.iter().all(|l| l.ends_with("/pinned.jpg")));
+    for now in ["2024-10-08T00:00:00Z", "2024-09-15T00:00:00Z"] {
+        let normal = latest_locations(pinned_state(now)).await;
+        assert!(normal.len() > 1, "{now}: {normal:?}");
+    }
+    let state = pinned_state("2024-10-03T00:00:00Z");
+    let resp = get_with(state, "/image/latest?since=2024-09-02").await;
+    assert!(!resp.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .ends_with("/pinned.jpg"));
+}
+
+#[test]
+fn cache_buster_joins_any_existing_query() {
+    let cases = [
+        (
+            "https://cdn.example.com/a.jpg",
+            "https://cdn.example.com/a.jpg?r=ff",
+        ),
+        (
+            "https://cdn.example.com/a.jpg?v=2",
+            "https://cdn.example.com/a.jpg?v=2&r=ff",
+        ),
+        (
+            "https://cdn.example.com/a.jpg?",
+            "https://cdn.example.com/a.jpg?r=ff",
+        ),
+        ("/images/a.jpg#top", "/images/a.jpg?r=ff#top"),
+    ];
+    for (url, busted) in cases {
+        assert_eq!(with_cache_buster(url, 255), busted);
+    }
+}
+
+#[tokio::test]
+async fn cache_buster_applies_to_plain_image_only() {
+    let map = r#"{"a.jpg": "a.jpg", "b.jpg": "b.jpg?v=2"}"#;
+    let state = |buster| {
+        Arc::new(AppState {
+            redirect_cache_buster: buster,
+            ..state_with(map, None)
+        })
+    };
+    let on = state(true);
+    let mut locations = std::collections::HashSet::new();
+    for seed in 0..20 {
+        let resp = get_with(on.clone(), &format!("/image?seed={seed}")).await;
+        let location = resp.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let (file, token) = location.rsplit_once("r=").unwrap();
+        assert!(
+            file.ends_with("/a.jpg?") || file.ends_with("/b.jpg?v=2&"),
+            "{location}"
+        );
+        assert!(u64::from_str_radix(token, 16).is_ok(), "{location}");
+        locations.insert(location);
+    }
+    assert!(locations.len() > 2, "tokens differ between redirects");
+    let resp = get_with(on, "/image/key/a.jpg").await;
+    assert!(resp.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .ends_with("/a.jpg"));
+    let resp = get_with(state(false), "/image?seed=1").await;
+    assert!(!resp.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .contains("r="));
+}
+
+#[tokio::test]
+async fn ping_reports_pool_readiness() {
+    let state = test_state();
+    let resp = get_with(state.clone(), "/ping").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    assert_eq!(body["ok"], true);
+    assert_eq!(body["keys"], test_keys().len());
+    assert_eq!(body["hash"].as_str().unwrap().len(), 16);
+    assert!(body["loaded_at"].as_str().unwrap().ends_with('Z'));
+    state.maintenance.store(true, Ordering::Relaxed);
+    assert_eq!(
+        body_json(get_with(state.clone(), "/ping").await).await["ok"],
+        false
+    );
+    let resp = get_with(state, "/metrics").await;
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(!String::from_utf8(body.to_vec()).unwrap().contains("/ping"));
+}
+
+#[tokio::test]
+async fn ping_is_not_ok_for_an_empty_map() {
+    let state = Arc::new(state_with("{}", None));
+    let body = body_json(get_with(state, "/ping").await).await;
+    assert_eq!(body["ok"], false);
+    assert_eq!(body["keys"], 0);
+}
+
+#[tokio::test]
+async fn latest_count_returns_distinct_keys() {
+    let resp = get("/image/latest?count=3&seed=5").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = body_json(resp).await;
+    let keys: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(keys.len(), 3);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "{keys:?}");
+    let all = body_json(get("/image/latest?count=1000").await).await;
+    assert_eq!(all.as_array().unwrap().len(), test_keys().len());
+    assert_eq!(
+        get("/image/latest?count=0").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn json_field_names_are_configurable() {
+    let state = Arc::new(AppState {
+        field_names: FieldNames {
+            key: "image".to_string(),
+            url: "href".to_string(),
+        },
+        ..test_app_state()
+    });
+    let body = body_json(get_with(state.clone(), "/image?format=json").await).await;
+    let fields: Vec<&String> = body.as_object().unwrap().keys().collect();
+    assert_eq!(fields, ["href", "image"]);
+    assert!(body["href"].as_str().unwrap().starts_with("https://"));
+    let list = body_json(get_with(state.clone(), "/image/top?count=2").await).await;
+    for entry in list.as_array().unwrap() {
+        let fields: Vec<&String> = entry.as_object().unwrap().keys().collect();
+        assert_eq!(fields, ["href", "image", "probability"]);
+    }
+    let schema = body_json(get_with(state, "/openapi.json").await).await;
+    let properties = &schema["components"]["schemas"]["Selection"]["properties"];
+    assert!(properties.get("key").is_some() && properties.get("names").is_none());
+}
+
+#[test]
+fn config_reads_json_field_names() {
+    let config = |key: Option<&'static str>, url: Option<&'static str>| {
+        Config::from_lookup(move |name| match name {
+            "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+            "JSON_KEY_FIELD" => key.map(String::from),
+            "JSON_URL_FIELD" => url.map(String::from),
+            _ => None,
+        })
+        .unwrap()
+        .field_names
+    };
+    assert_eq!(config(None, None), FieldNames::default());
+    assert_eq!(config(Some("image"), Some("href")).url, "href");
+    assert!(std::panic::catch_unwind(|| config(Some("url"), None)).is_err());
+    assert!(std::panic::catch_unwind(|| config(None, Some("fallback"))).is_err());
+}
+
+#[test]
+fn served_counts_favor_under_served_keys() {
+    let keys: Vec<String> = ["a.jpg", "b.jpg", "c.jpg"].map(String::from).to_vec();
+    let seeded = || {
+        let counts = ServedCounts::default();
+        for _ in 0..5 {
+            counts.record("a.jpg");
+            counts.record("b.jpg");
+        }
+        counts
+    };
+    assert_eq!(seeded().weights(&keys), vec![1.0 / 6.0, 1.0 / 6.0, 1.0]);
+    let mut rng = StdRng::seed_from_u64(9);
+    let picked_c = (0..300)
+        .filter(|_| seeded().select(&keys, &mut rng) == Some("c.jpg"))
+        .count();
+    assert!(picked_c > 180, "{picked_c}");
+}
+
+#[tokio::test]
+async fn fair_mode_evens_out_serves() {
+    let state = test_state();
+    for _ in 0..3 * test_keys().len() {
+        let resp = get_with(state.clone(), "/image?mode=fair").await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+    }
+    let weights = state.served_counts.weights(&test_keys());
+    assert!(weights.iter().all(|&w| w > 0.1), "{weights:?}");
+    assert_eq!(
+        get_with(state, "/image?mode=fair&after=2024")
+            .await
+            .status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[test]
+fn served_counts_survive_a_restart() {
+    let path = std::env::temp_dir().join(format!("roulette-served-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let keys: Vec<String> = ["a.jpg", "b.jpg"].map(String::from).to_vec();
+    let counts = ServedCounts::load(&path).unwrap();
+    assert!(!counts.save().unwrap(), "nothing to write yet");
+    counts.record("a.jpg");
+    counts.record("a.jpg");
+    assert!(counts.save().unwrap());
+    let reloaded = ServedCounts::load(&path).unwrap();
+    assert_eq!(reloaded.weights(&keys), vec![1.0 / 3.0, 1.0]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bounded_selections_expose_the_candidate_window() {
+    let cases = [
+        (
+            "/image/after/2024",
+            "2024-01-01_00-00-00_UTC.jpg",
+            "2025-01-01_00-00-00_UTC.jpg",
+        ),
+        (
+            "/image/latest/after/2023?skip=1",
+            "2023-06-15_12-30-00_UTC.jpg",
+            "2024-06-15_12-30-00_UTC.jpg",
+        ),
+        (
+            "/image?since=2023&until=2024-06",
+            "2023-06-15_12-30-00_UTC.jpg",
+            "2024-01-01_00-00-00_UTC.jpg",
+        ),
+        (
+            "/image/latest?until=2024&format=json",
+            "2022-01-01_00-00-00_UTC.jpg",
+            "2023-06-15_12-30-00_UTC.jpg",
+        ),
+    ];
+    for (uri, start, end) in cases {
+        let resp = get(uri).await;
+        assert_eq!(resp.headers()["x-range-start"], start, "{uri}");
+        assert_eq!(resp.headers()["x-range-end"], end, "{uri}");
+    }
+    for uri in ["/image", "/image/after/2030"] {
+        assert!(
+            get(uri).await.headers().get("x-range-start").is_none(),
+            "{uri}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn key_prefix_filter_applies_to_reloads() {
+    let state = AppState {
+        key_prefix_filter: Some("camera1-".to_string()),
+        ..test_app_state()
+    };
+    let map = r#"{"camera1-a.jpg": "a.jpg", "camera2-b.jpg": "b.jpg"}"#;
+    reload_once(&state, &mock(map)).await;
+    let state = Arc::new(state);
+    assert_eq!(
+        body_json(get_with(state.clone(), "/ping").await).await["keys"],
+        1
+    );
+    let resp = get_with(state, "/image").await;
+    assert!(resp.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .ends_with("/a.jpg"));
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        "KEY_PREFIX_FILTER" => Some("camera1-".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(config.key_prefix_filter.as_deref(), Some("camera1-"));
+}
+
+/// Sends `request` over a fresh connection and returns the raw response.
+async fn raw_request(port: u16, request: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[cfg(feature = "tls")]
+fn fixture_tls(min_version: TlsVersion) -> tls::TlsFiles {
+    let fixture = |name: &str| format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    tls::TlsFiles {
+        cert_path: fixture("localhost.pem"),
+        key_path: fixture("localhost.key"),
+        min_version,
+    }
+}
+
+#[cfg(all(feature = "tls", feature = "http"))]
+async fn https_get(
+    min_version: TlsVersion,
+    client_max: reqwest::tls::Version,
+) -> reqwest::Result<reqwest::Response> {
+    let files = fixture_tls(min_version);
+    let tls = RustlsConfig::from_config(files.load().unwrap());
+    let state = Arc::new(AppState {
+        shutdown: Some(Notify::new()),
+        ..test_app_state()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(serve_tls(
+        listener,
+        router(state.clone()),
+        tls,
+        state.clone(),
+    ));
+    let cert = std::fs::read(&files.cert_path).unwrap();
+    let client = reqwest::Client::builder()
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(reqwest::Certificate::from_pem(&cert).unwrap())
+        .max_tls_version(client_max)
+        .resolve("localhost", ([127, 0, 0, 1], port).into())
+        .build()
+        .unwrap();
+    let resp = client
+        .get(format!("https://localhost:{port}/health"))
+        .send()
+        .await;
+    state.shutdown.as_ref().unwrap().notify_one();
+    server.await.unwrap().unwrap();
+    resp
+}
+
+#[cfg(all(feature = "tls", feature = "http"))]
+#[tokio::test]
+async fn https_listener_completes_a_handshake() {
+    let resp = https_get(TlsVersion::Tls12, reqwest::tls::Version::TLS_1_3)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = https_get(TlsVersion::Tls12, reqwest::tls::Version::TLS_1_2)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[cfg(all(feature = "tls", feature = "http"))]
+#[tokio::test]
+async fn https_listener_enforces_the_minimum_version() {
+    assert!(https_get(TlsVersion::Tls13, reqwest::tls::Version::TLS_1_2)
+        .await
+        .is_err());
+    let resp = https_get(TlsVersion::Tls13, reqwest::tls::Version::TLS_1_3)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_files_report_which_file_failed() {
+    let mut files = fixture_tls(TlsVersion::Tls12);
+    assert!(files.load().is_ok());
+    let config = RustlsConfig::from_config(files.load().unwrap());
+    files.key_path = files.cert_path.clone();
+    let error = files.load().unwrap_err().to_string();
+    assert!(error.starts_with(&files.key_path), "{error}");
+    // A failed reload leaves the current certificate in place.
+    tls::reload(&config, &files);
+    files.cert_path = "missing.pem".to_string();
+    let error = files.load().unwrap_err().to_string();
+    assert!(error.starts_with("missing.pem"), "{error}");
+}
+
+#[tokio::test]
+async fn serve_once_announces_port_and_stops_on_shutdown() {
+    let state = Arc::new(AppState {
+        shutdown: Some(Notify::new()),
+        ..test_app_state()
+    });
+    let mut out = Vec::new();
+    let listener = bind_listener(true, 8080, &mut out).await.unwrap();
+    let local = listener.local_addr().unwrap();
+    assert!(local.ip().is_loopback());
+    let server = tokio::spawn(serve_http(listener, router(state.clone()), state));
+    let line = String::from_utf8(out).unwrap();
+    let announced: serde_json::Value = serde_json::from_str(&line).unwrap();
+    let port = announced["port"].as_u64().unwrap() as u16;
+    assert_eq!(port, local.port());
+    assert_ne!(port, 8080);
+
+    // Trailing slashes are trimmed before routing.
+    let health = raw_request(
+        port,
+        "GET /health/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(health.starts_with("HTTP/1.1 200"), "{health}");
+    let echo = raw_request(
+        port,
+        "GET /debug/echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         Authorization: Bearer admin\r\n\r\n",
+    )
+    .await;
+    assert!(echo.contains(r#""remote_addr":"127.0.0.1:"#), "{echo}");
+    let denied = raw_request(
+        port,
+        "POST /admin/shutdown HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         Content-Length: 0\r\n\r\n",
+    )
+    .await;
+    assert!(denied.starts_with("HTTP/1.1 401"), "{denied}");
+    let accepted = raw_request(
+        port,
+        "POST /admin/shutdown HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         Authorization: Bearer admin\r\nContent-Length: 0\r\n\r\n",
+    )
+    .await;
+    assert!(accepted.starts_with("HTTP/1.1 202"), "{accepted}");
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server didn't stop")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_is_not_found_outside_serve_once() {
+    let req = Request::post("/admin/shutdown")
+        .header(header::AUTHORIZATION, "Bearer admin")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        send(test_state(), req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn empty_bound_means_no_bound() {
+    for uri in [
+        "/image/after/",
+        "/image/after",
+        "/random/after/",
+        "/image/latest/after/",
+    ] {
+        let resp = get(uri).await;
+        assert_eq!(resp.status(), StatusCode::FOUND, "{uri}");
+    }
+    let resp = get("/image/after/?format=json").await;
+    assert_eq!(resp.headers()["x-range-start"], test_keys()[0]);
+}
+
+#[tokio::test]
+async fn reject_empty_bound_config() {
+    let config = Config::from_lookup(|name| match name {
+        "IMAGE_URL_PREFIX" => Some("https://cdn.example.com".to_string()),
+        "REJECT_EMPTY_BOUND" => Some("1".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    assert!(config.reject_empty_bound);
+    let state = Arc::new(AppState {
+        reject_empty_bound: true,
+        ..test_app_state()
+    });
+    for uri in ["/image/after/", "/image/latest/after"] {
+        let resp = get_with(state.clone(), uri).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+    let resp = get_with(state, "/image/after/2024").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+}
@@ -0,0 +1,112 @@
+//! Fetching, decoding and encoding source images for `/montage` and
+//! `/image/key/{key}/transform`.
+
+use crate::breaker::{Breakers, UpstreamError};
+use image::{DynamicImage, ImageFormat, ImageReader, ImageResult, Limits};
+use std::{io::Cursor, sync::OnceLock, time::Duration};
+
+/// Per-image fetch timeout.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest source image body accepted, in bytes.
+const MAX_SOURCE_BYTES: usize = 16 * 1024 * 1024;
+/// Decoder allocation cap per source image, in bytes.
+const MAX_DECODE_ALLOC: u64 = 128 * 1024 * 1024;
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .user_agent("roulette/1.0")
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .expect("failed to build HTTP client")
+    })
+}
+
+/// Encoding of a generated image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Output {
+    Jpeg,
+    Png,
+}
+
+impl Output {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "jpeg" | "jpg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    /// The output matching a decoded source's format, if it can be encoded.
+    #[cfg(feature = "transform")]
+    pub fn from_format(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Jpeg => Some(Self::Jpeg),
+            ImageFormat::Png => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+        }
+    }
+
+    fn format(self) -> ImageFormat {
+        match self {
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Png => ImageFormat::Png,
+        }
+    }
+}
+
+/// `url`'s body, through its origin's circuit in `breakers`.
+pub async fn fetch(breakers: &Breakers, url: &str) -> Result<Vec<u8>, UpstreamError> {
+    let too_large = || UpstreamError::Failed("image too large".to_string());
+    let mut resp = breakers.send(url, client().get(url)).await?;
+    if resp
+        .content_length()
+        .is_some_and(|len| len > MAX_SOURCE_BYTES as u64)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| UpstreamError::Failed(e.to_string()))?
+    {
+        if body.len() + chunk.len() > MAX_SOURCE_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Decodes `bytes` in whichever supported format they're in, returning the
+/// format alongside the image. Unrecognized or unsupported formats fail with
+/// [`image::ImageError::Unsupported`].
+pub fn decode(bytes: &[u8]) -> ImageResult<(DynamicImage, Option<ImageFormat>)> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader.format();
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    Ok((reader.decode()?, format))
+}
+
+/// Encodes `img` as `output`, dropping any alpha channel for JPEG.
+pub fn encode(img: &DynamicImage, output: Output) -> ImageResult<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    if output == Output::Jpeg && img.color().has_alpha() {
+        DynamicImage::ImageRgb8(img.to_rgb8()).write_to(&mut out, output.format())?;
+    } else {
+        img.write_to(&mut out, output.format())?;
+    }
+    Ok(out.into_inner())
+}
@@ -0,0 +1,1608 @@
+//! Image map parsing and random selection, independent of the HTTP layer.
+
+pub mod source;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc, Weekday};
+use flate2::read::GzDecoder;
+use lru::LruCache;
+use rand::{distributions::WeightedIndex, prelude::*};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    num::NonZeroUsize,
+    ops::Range,
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// Parses a cache duration like `60s`, `5m`, `1h` or `7d` into seconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, suffix) = s.split_at(s.len().saturating_sub(1));
+    let value: u64 = num.parse().ok()?;
+    match suffix {
+        "s" => Some(value),
+        "m" => value.checked_mul(60),
+        "h" => value.checked_mul(3600),
+        "d" => value.checked_mul(86400),
+        _ => None,
+    }
+}
+
+/// Spreads `secs` uniformly within `±jitter` (a fraction, e.g. `0.1` for 10%).
+pub fn jittered_ttl(secs: u64, jitter: f64, rng: &mut impl Rng) -> u64 {
+    let spread = (secs as f64 * jitter).round() as u64;
+    if spread == 0 {
+        return secs;
+    }
+    rng.gen_range(secs.saturating_sub(spread)..=secs + spread)
+}
+
+/// Parses a boost spec like `sunset:3,beach:2` into tag factors.
+///
+/// Returns `None` if any part is malformed or has a non-finite or non-positive factor.
+pub fn parse_boost(spec: &str) -> Option<HashMap<String, f64>> {
+    spec.split(',')
+        .map(|part| {
+            let (tag, factor) = part.split_once(':')?;
+            let factor: f64 = factor.trim().parse().ok()?;
+            let tag = tag.trim();
+            if tag.is_empty() || !factor.is_finite() || factor <= 0.0 {
+                return None;
+            }
+            Some((tag.to_string(), factor))
+        })
+        .collect()
+}
+
+/// Parses a comma-separated month list such as `06,07,8` into month numbers
+/// (1–12). `None` if any part is not a month.
+pub fn parse_months(spec: &str) -> Option<Vec<u32>> {
+    spec.split(',')
+        .map(|part| part.trim().parse().ok().filter(|m| (1..=12).contains(m)))
+        .collect()
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decodes raw map bytes, gunzipping when the path ends in `.gz` or the bytes
+/// start with the gzip magic number.
+pub fn decode_map(path: &str, bytes: Vec<u8>) -> io::Result<String> {
+    if path.ends_with(".gz") || bytes.starts_with(&GZIP_MAGIC) {
+        let mut content = String::new();
+        GzDecoder::new(bytes.as_slice()).read_to_string(&mut content)?;
+        Ok(content)
+    } else {
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Hashes map content for reload change detection, with XXH3 when the
+/// `xxhash` feature is enabled and [`sip_hash_content`] otherwise. Both are
+/// deterministic across runs.
+pub fn hash_content(content: &str) -> u64 {
+    #[cfg(feature = "xxhash")]
+    return xxhash_rust::xxh3::xxh3_64(content.as_bytes());
+    #[cfg(not(feature = "xxhash"))]
+    sip_hash_content(content)
+}
+
+/// The standard library's SipHash over `content`, the default for
+/// [`hash_content`].
+pub fn sip_hash_content(content: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Name of the variant stored in [`ImageMap::map`].
+pub const DEFAULT_VARIANT: &str = "full";
+
+/// Extensions treated as [`MediaType::Video`] when an entry has no explicit `type`.
+const VIDEO_EXTENSIONS: [&str; 6] = ["mp4", "mov", "m4v", "webm", "mkv", "avi"];
+
+/// Whether an entry is a still image or a video.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    Image,
+    Video,
+}
+
+impl MediaType {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "image" => Some(Self::Image),
+            "video" => Some(Self::Video),
+            _ => None,
+        }
+    }
+
+    /// Infers the type from `file`'s extension, defaulting to [`MediaType::Image`].
+    pub fn infer(file: &str) -> Self {
+        let ext = file.rsplit_once('.').map_or("", |(_, ext)| ext);
+        if VIDEO_EXTENSIONS
+            .iter()
+            .any(|video| ext.eq_ignore_ascii_case(video))
+        {
+            Self::Video
+        } else {
+            Self::Image
+        }
+    }
+}
+
+/// A map's entries and the keys it repeats. `serde_json` keeps only the last
+/// value for a repeated key, and so do these; the repeats are collected so a
+/// silently shadowed entry can be reported.
+struct Entries {
+    entries: HashMap<String, MapEntry>,
+    /// Each key once per extra occurrence, in document order.
+    duplicates: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EntriesVisitor {
+            type Value = Entries;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a map of keys to entries")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut access: A,
+            ) -> Result<Entries, A::Error> {
+                let mut entries = HashMap::with_capacity(access.size_hint().unwrap_or(0));
+                let mut duplicates = Vec::new();
+                while let Some((key, entry)) = access.next_entry::<String, MapEntry>()? {
+                    if entries.contains_key(&key) {
+                        duplicates.push(key.clone());
+                    }
+                    entries.insert(key, entry);
+                }
+                Ok(Entries {
+                    entries,
+                    duplicates,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MapEntry {
+    File(String),
+    Meta(EntryMeta),
+    Variants(HashMap<String, String>),
+}
+
+/// The metadata form of an entry. Unknown fields are rejected so an object
+/// mixing `file` with variant names is read as variants instead, and reported
+/// for its missing `full`, rather than silently losing the variants.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntryMeta {
+    file: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(rename = "type", default)]
+    media_type: Option<MediaType>,
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    pins: Vec<PinWindow>,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+}
+
+/// An image's size in pixels, from an entry's `width` and `height`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A `pins` window as written in the map, RFC 3339 timestamps.
+#[derive(Deserialize)]
+struct PinWindow {
+    start: String,
+    end: String,
+}
+
+impl PinWindow {
+    fn parse(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+        let parse = |t: &str| {
+            DateTime::parse_from_rfc3339(t)
+                .map(|t| t.to_utc())
+                .map_err(|_| format!("pin timestamp {t:?} is not RFC 3339"))
+        };
+        let (start, end) = (parse(&self.start)?, parse(&self.end)?);
+        if end <= start {
+            return Err(format!(
+                "pin ending {:?} doesn't end after it starts",
+                self.end
+            ));
+        }
+        Ok((start, end))
+    }
+}
+
+/// An editorial override: `key` is served by `/image/latest` from `start`
+/// until, but excluding, `end`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pin {
+    pub key: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl MapEntry {
+    /// The default variant's filename, empty if a variant object lacks one.
+    fn file(&self) -> &str {
+        match self {
+            Self::File(file) | Self::Meta(EntryMeta { file, .. }) => file,
+            Self::Variants(files) => files.get(DEFAULT_VARIANT).map_or("", String::as_str),
+        }
+    }
+
+    /// The explicit `type`, else one inferred from the default filename.
+    fn media_type(&self) -> MediaType {
+        match self {
+            Self::Meta(EntryMeta {
+                media_type: Some(media_type),
+                ..
+            }) => *media_type,
+            _ => MediaType::infer(self.file()),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.problem().is_none()
+    }
+
+    /// Why the entry is unusable, naming the offending filename.
+    fn problem(&self) -> Option<String> {
+        match self {
+            Self::Variants(files) if !files.contains_key(DEFAULT_VARIANT) => Some(format!(
+                "variant object has no `{DEFAULT_VARIANT}` filename"
+            )),
+            Self::Variants(files) => {
+                let mut files: Vec<_> = files.iter().collect();
+                files.sort();
+                files.into_iter().find_map(|(variant, file)| {
+                    let problem = filename_problem(file)?;
+                    Some(format!("{variant} filename {file:?} {problem}"))
+                })
+            }
+            Self::Meta(EntryMeta { pins, .. }) => {
+                let file = self.file();
+                filename_problem(file)
+                    .map(|problem| format!("filename {file:?} {problem}"))
+                    .or_else(|| pins.iter().find_map(|pin| pin.parse().err()))
+            }
+            Self::File(file) => {
+                filename_problem(file).map(|problem| format!("filename {file:?} {problem}"))
+            }
+        }
+    }
+}
+
+/// Controls validation and filtering in [`ImageMap::parse_with`].
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Fail on invalid filenames instead of dropping them.
+    pub strict: bool,
+    /// Drop keys whose timestamp is after this instant.
+    pub not_after: Option<DateTime<Utc>>,
+    /// Keep only keys starting with this prefix, scoping a shared map to one
+    /// logical set.
+    pub key_prefix: Option<String>,
+}
+
+/// Parses the `YYYY-MM-DD_HH-MM-SS` prefix of a key as a UTC timestamp.
+pub fn parse_key_timestamp(key: &str) -> Option<DateTime<Utc>> {
+    let prefix = key.get(..19)?;
+    NaiveDateTime::parse_from_str(prefix, "%Y-%m-%d_%H-%M-%S")
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+fn is_future(key: &str, now: DateTime<Utc>) -> bool {
+    parse_key_timestamp(key).is_some_and(|t| t > now)
+}
+
+/// Number of `after` bounds whose partition point is cached per map.
+pub const PARTITION_CACHE_SIZE: usize = 64;
+
+/// Where a map and a storage manifest disagree, from [`ImageMap::reconcile`].
+#[derive(Debug, Default, PartialEq)]
+pub struct Reconciliation {
+    /// `(key, filename)` for mapped filenames absent from the manifest, in
+    /// key order.
+    pub missing: Vec<(String, String)>,
+    /// Manifest filenames that no entry maps to, sorted.
+    pub orphaned: Vec<String>,
+}
+
+impl Reconciliation {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+/// A parsed image map: keys in sorted order plus their target filenames.
+pub struct ImageMap {
+    /// All keys, sorted ascending by the full key, so keys sharing a
+    /// timestamp always order by their suffix.
+    pub sorted_keys: Vec<String>,
+    /// Key to filename on the asset host, for the [`DEFAULT_VARIANT`].
+    pub map: HashMap<String, String>,
+    /// Other variant name to key to filename, for keys that have the variant.
+    pub variants: HashMap<String, HashMap<String, String>>,
+    /// Tag to indices into `sorted_keys`, ascending.
+    pub tag_index: HashMap<String, Vec<usize>>,
+    /// Media type to indices into `sorted_keys`, ascending.
+    pub type_index: HashMap<MediaType, Vec<usize>>,
+    /// Key to image content hash, for keys whose metadata carries one.
+    pub image_hashes: HashMap<String, String>,
+    /// Key to pixel size, for keys whose metadata carries both `width` and
+    /// `height`.
+    pub dimensions: HashMap<String, Dimensions>,
+    /// Editorial overrides from entries' `pins`, in key order.
+    pub pins: Vec<Pin>,
+    /// [`hash_content`] of the source the map was parsed from.
+    pub content_hash: u64,
+    partition_cache: Mutex<LruCache<String, usize>>,
+}
+
+/// `previous` (sorted) with the keys missing from `entries` removed and the
+/// new ones, found by binary search, sorted and merged in at their positions.
+/// `None` when more than half the keys changed and a full sort is as cheap.
+fn patch_sorted_keys<V>(previous: &[String], entries: &HashMap<String, V>) -> Option<Vec<String>> {
+    let mut added: Vec<&String> = entries
+        .keys()
+        .filter(|key| previous.binary_search(key).is_err())
+        .collect();
+    let kept = entries.len() - added.len();
+    let removed = previous.len() - kept;
+    if added.len() + removed > entries.len().max(previous.len()) / 2 {
+        return None;
+    }
+    added.sort();
+    let retained = previous.iter().filter(|key| entries.contains_key(*key));
+    let mut sorted_keys = Vec::with_capacity(entries.len());
+    let mut added = added.into_iter().peekable();
+    for key in retained {
+        while let Some(new) = added.next_if(|new| *new < key) {
+            sorted_keys.push(new.clone());
+        }
+        sorted_keys.push(key.clone());
+    }
+    sorted_keys.extend(added.cloned());
+    Some(sorted_keys)
+}
+
+/// Whether a map filename is safe to join onto a URL prefix.
+pub fn valid_filename(file: &str) -> bool {
+    filename_problem(file).is_none()
+}
+
+/// What makes `file` unsafe to join onto a URL prefix, if anything.
+fn filename_problem(file: &str) -> Option<&'static str> {
+    if file.is_empty() {
+        Some("is empty")
+    } else if file.contains("..") {
+        Some("contains `..`")
+    } else if file.starts_with('/') || file.starts_with('\\') {
+        Some("is absolute")
+    } else if file.chars().any(char::is_control) {
+        Some("contains a control character")
+    } else {
+        None
+    }
+}
+
+/// Why a map failed to load.
+#[derive(Debug)]
+pub enum MapError {
+    /// Not a JSON object; the message carries serde's line and column.
+    Json(serde_json::Error),
+    /// An entry of the wrong shape, or (when strict) with an unsafe filename
+    /// or a key that appears more than once.
+    Entry { key: String, problem: String },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "{e}"),
+            Self::Entry { key, problem } => write!(f, "entry {key:?}: {problem}"),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Json(e) => Some(e),
+            Self::Entry { .. } => None,
+        }
+    }
+}
+
+/// The entries of a JSON map. A key that appears more than once keeps its
+/// last value, with a warning, or fails when `options.strict` is set; keys
+/// `options.key_prefix` filters out are ignored either way.
+fn parse_entries(
+    content: &str,
+    options: &ParseOptions,
+) -> Result<HashMap<String, MapEntry>, MapError> {
+    let Entries {
+        entries,
+        mut duplicates,
+    } = match serde_json::from_str(content) {
+        Ok(entries) => entries,
+        Err(e) if e.is_data() => return Err(locate_bad_entry(content, e)),
+        Err(e) => return Err(MapError::Json(e)),
+    };
+    if let Some(prefix) = &options.key_prefix {
+        duplicates.retain(|key| key.starts_with(prefix.as_str()));
+    }
+    let Some(first) = duplicates.first() else {
+        return Ok(entries);
+    };
+    if options.strict {
+        let count = 1 + duplicates.iter().filter(|&key| key == first).count();
+        return Err(MapError::Entry {
+            key: first.clone(),
+            problem: format!("key appears {count} times"),
+        });
+    }
+    let extra = duplicates.len();
+    duplicates.sort();
+    duplicates.dedup();
+    warn!(
+        count = duplicates.len(),
+        extra,
+        first = %duplicates[0],
+        "duplicate keys in map; keeping the last value of each"
+    );
+    Ok(entries)
+}
+
+/// Pinpoints the entry behind a failed [`MapEntry`] deserialization, which
+/// serde reports only as an untagged-enum mismatch at a line and column.
+fn locate_bad_entry(content: &str, error: serde_json::Error) -> MapError {
+    let Ok(values) = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(content)
+    else {
+        return MapError::Json(error);
+    };
+    let bad = values
+        .into_iter()
+        .find(|(_, value)| MapEntry::deserialize(value).is_err());
+    match bad {
+        Some((key, value)) => {
+            let found = match value {
+                serde_json::Value::Null => "null",
+                serde_json::Value::Bool(_) => "a boolean",
+                serde_json::Value::Number(_) => "a number",
+                serde_json::Value::String(_) => "a string",
+                serde_json::Value::Array(_) => "an array",
+                serde_json::Value::Object(_) => "an object of the wrong shape",
+            };
+            MapError::Entry {
+                key,
+                problem: format!(
+                    "expected a filename, a `file` object or a variant object, found {found} \
+                     (line {}, column {})",
+                    error.line(),
+                    error.column()
+                ),
+            }
+        }
+        None => MapError::Json(error),
+    }
+}
+
+impl ImageMap {
+    /// Parses a JSON map with default [`ParseOptions`].
+    pub fn parse(content: &str) -> Result<Self, MapError> {
+        Self::parse_with(content, &ParseOptions::default())
+    }
+
+    /// Parses a JSON map, validating and filtering entries per `options`.
+    pub fn parse_with(content: &str, options: &ParseOptions) -> Result<Self, MapError> {
+        let entries = parse_entries(content, options)?;
+        Self::from_entries(entries, options, hash_content(content), None)
+    }
+
+    /// Like [`parse_with`](Self::parse_with), but derives `sorted_keys` by
+    /// patching `previous`'s rather than sorting from scratch, which keeps
+    /// reloads of large, append-mostly maps cheap. Falls back to a full sort
+    /// when most keys changed.
+    pub fn parse_incremental(
+        content: &str,
+        options: &ParseOptions,
+        previous: &ImageMap,
+    ) -> Result<Self, MapError> {
+        let entries = parse_entries(content, options)?;
+        Self::from_entries(
+            entries,
+            options,
+            hash_content(content),
+            Some(&previous.sorted_keys),
+        )
+    }
+
+    /// Builds a map from key-to-filename pairs already in memory, filtered as
+    /// [`parse`](Self::parse) would. Its `content_hash` is `0`, so any reload
+    /// replaces it.
+    pub fn from_files(files: HashMap<String, String>) -> Self {
+        let entries = files
+            .into_iter()
+            .map(|(k, f)| (k, MapEntry::File(f)))
+            .collect();
+        Self::from_entries(entries, &ParseOptions::default(), 0, None)
+            .expect("only strict parsing fails")
+    }
+
+    fn from_entries(
+        mut entries: HashMap<String, MapEntry>,
+        options: &ParseOptions,
+        content_hash: u64,
+        previous: Option<&[String]>,
+    ) -> Result<Self, MapError> {
+        if let Some(prefix) = &options.key_prefix {
+            entries.retain(|key, _| key.starts_with(prefix.as_str()));
+        }
+        let mut invalid: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_valid())
+            .map(|(key, _)| key.clone())
+            .collect();
+        invalid.sort();
+        if let Some(key) = invalid.first() {
+            if options.strict {
+                return Err(MapError::Entry {
+                    key: key.clone(),
+                    problem: entries[key].problem().unwrap_or_default(),
+                });
+            }
+            warn!(
+                count = invalid.len(),
+                "dropping entries with invalid filenames"
+            );
+            for key in &invalid {
+                entries.remove(key);
+            }
+        }
+        if let Some(now) = options.not_after {
+            let before = entries.len();
+            entries.retain(|key, _| !is_future(key, now));
+            if entries.len() < before {
+                warn!(count = before - entries.len(), "dropping future-dated keys");
+            }
+        }
+        let sorted_keys = previous
+            .and_then(|previous| patch_sorted_keys(previous, &entries))
+            .unwrap_or_else(|| {
+                let mut sorted_keys: Vec<String> = entries.keys().cloned().collect();
+                sorted_keys.sort();
+                sorted_keys
+            });
+        let mut map = HashMap::with_capacity(entries.len());
+        let mut tag_index: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut type_index: HashMap<MediaType, Vec<usize>> = HashMap::new();
+        let mut variants: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut image_hashes = HashMap::new();
+        let mut dimensions = HashMap::new();
+        let mut pins = Vec::new();
+        for (i, key) in sorted_keys.iter().enumerate() {
+            let entry = &entries[key];
+            let tags = match entry {
+                MapEntry::File(_) => &[][..],
+                MapEntry::Meta(EntryMeta {
+                    tags,
+                    hash,
+                    pins: windows,
+                    width,
+                    height,
+                    ..
+                }) => {
+                    if let Some(hash) = hash {
+                        image_hashes.insert(key.clone(), hash.clone());
+                    }
+                    if let (&Some(width), &Some(height)) = (width, height) {
+                        dimensions.insert(key.clone(), Dimensions { width, height });
+                    }
+                    for (start, end) in windows.iter().filter_map(|w| w.parse().ok()) {
+                        pins.push(Pin {
+                            key: key.clone(),
+                            start,
+                            end,
+                        });
+                    }
+                    tags.as_slice()
+                }
+                MapEntry::Variants(files) => {
+                    for (variant, file) in files {
+                        if variant != DEFAULT_VARIANT {
+                            variants
+                                .entry(variant.clone())
+                                .or_default()
+                                .insert(key.clone(), file.clone());
+                        }
+                    }
+                    &[][..]
+                }
+            };
+            let file = entry.file().to_string();
+            type_index.entry(entry.media_type()).or_default().push(i);
+            for tag in tags {
+                let indices = tag_index.entry(tag.clone()).or_default();
+                if indices.last() != Some(&i) {
+                    indices.push(i);
+                }
+            }
+            map.insert(key.clone(), file);
+        }
+        Ok(Self {
+            sorted_keys,
+            map,
+            variants,
+            tag_index,
+            type_index,
+            image_hashes,
+            dimensions,
+            pins,
+            content_hash,
+            partition_cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(PARTITION_CACHE_SIZE).unwrap(),
+            )),
+        })
+    }
+
+    /// Key to filename for `variant` (the [`DEFAULT_VARIANT`] when `None`), or
+    /// `None` if no key has it.
+    pub fn files(&self, variant: Option<&str>) -> Option<&HashMap<String, String>> {
+        match variant {
+            None | Some(DEFAULT_VARIANT) => Some(&self.map),
+            Some(variant) => self.variants.get(variant),
+        }
+    }
+
+    /// The key pinned at `now`, if any. Among overlapping windows the latest
+    /// start wins, then the earliest end, then key order.
+    pub fn pinned(&self, now: DateTime<Utc>) -> Option<&str> {
+        self.pins
+            .iter()
+            .filter(|pin| pin.start <= now && now < pin.end)
+            .min_by(|a, b| {
+                b.start
+                    .cmp(&a.start)
+                    .then(a.end.cmp(&b.end))
+                    .then(a.key.cmp(&b.key))
+            })
+            .map(|pin| pin.key.as_str())
+    }
+
+    /// Number of keys in the map.
+    pub fn len(&self) -> usize {
+        self.sorted_keys.len()
+    }
+
+    /// Whether the map has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.sorted_keys.is_empty()
+    }
+
+    /// The [`DEFAULT_VARIANT`] filename for `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.map.get(key).map(String::as_str)
+    }
+
+    /// `(key, filename)` pairs for the [`DEFAULT_VARIANT`], in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.sorted_keys
+            .iter()
+            .filter_map(|key| Some((key.as_str(), self.get(key)?)))
+    }
+
+    /// Compares the mapped filenames, of every variant, against `manifest`, a
+    /// newline-delimited list of the files that exist in storage.
+    pub fn reconcile(&self, manifest: &str) -> Reconciliation {
+        let files: BTreeSet<&str> = manifest
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        let mut variants: Vec<_> = self.variants.iter().collect();
+        variants.sort_unstable_by_key(|(name, _)| *name);
+        let mut referenced = HashSet::new();
+        let mut missing = Vec::new();
+        for key in &self.sorted_keys {
+            let filenames = self
+                .map
+                .get(key)
+                .into_iter()
+                .chain(variants.iter().filter_map(|(_, files)| files.get(key)));
+            for filename in filenames {
+                referenced.insert(filename.as_str());
+                if !files.contains(filename.as_str()) {
+                    missing.push((key.clone(), filename.clone()));
+                }
+            }
+        }
+        let orphaned = files
+            .into_iter()
+            .filter(|file| !referenced.contains(file))
+            .map(String::from)
+            .collect();
+        Reconciliation { missing, orphaned }
+    }
+
+    /// Tags carried by `key`, sorted.
+    pub fn tags_of(&self, key: &str) -> Vec<&str> {
+        let Ok(i) = self.sorted_keys.binary_search_by(|k| k.as_str().cmp(key)) else {
+            return Vec::new();
+        };
+        let mut tags: Vec<&str> = self
+            .tag_index
+            .iter()
+            .filter(|(_, indices)| indices.binary_search(&i).is_ok())
+            .map(|(tag, _)| tag.as_str())
+            .collect();
+        tags.sort_unstable();
+        tags
+    }
+
+    /// The media type of `key`, or `None` if the map doesn't contain it.
+    pub fn media_type(&self, key: &str) -> Option<MediaType> {
+        let i = self
+            .sorted_keys
+            .binary_search_by(|k| k.as_str().cmp(key))
+            .ok()?;
+        let videos = self.type_index.get(&MediaType::Video);
+        if videos.is_some_and(|v| v.binary_search(&i).is_ok()) {
+            Some(MediaType::Video)
+        } else {
+            Some(MediaType::Image)
+        }
+    }
+
+    /// How many keys have a parsed timestamp after `now`, counted in one pass
+    /// without collecting them.
+    pub fn count_future(&self, now: DateTime<Utc>) -> usize {
+        self.sorted_keys
+            .iter()
+            .filter(|k| is_future(k, now))
+            .count()
+    }
+
+    /// Keys whose parsed timestamp is after `now`.
+    pub fn future_keys(&self, now: DateTime<Utc>) -> Vec<&str> {
+        self.sorted_keys
+            .iter()
+            .filter(|k| is_future(k, now))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Like [`filter_after`] over `sorted_keys`, with the partition point cached.
+    pub fn keys_after(&self, bound: &str) -> &[String] {
+        let mut cache = self.partition_cache.lock().unwrap();
+        if let Some(&start) = cache.get(bound) {
+            return &self.sorted_keys[start..];
+        }
+        let keys = filter_after(&self.sorted_keys, bound);
+        cache.put(bound.to_string(), self.sorted_keys.len() - keys.len());
+        keys
+    }
+
+    /// Indices into `sorted_keys` of keys at or after `since` and sorting
+    /// before `until`; either bound may be omitted.
+    pub fn key_range(&self, since: Option<&str>, until: Option<&str>) -> Range<usize> {
+        let len = self.sorted_keys.len();
+        let start = since.map_or(0, |since| len - self.keys_after(since).len());
+        let end = until.map_or(len, |until| {
+            self.sorted_keys.partition_point(|k| k.as_str() < until)
+        });
+        start..end.max(start)
+    }
+}
+
+/// Picks a key uniformly at random.
+pub fn select_uniform(keys: &[String]) -> Option<&str> {
+    select_uniform_with(keys, &mut thread_rng())
+}
+
+/// [`select_uniform`] drawing from `rng`, e.g. a seeded one for reproducible picks.
+pub fn select_uniform_with<'a>(keys: &'a [String], rng: &mut impl Rng) -> Option<&'a str> {
+    if keys.is_empty() {
+        return None;
+    }
+    Some(&keys[rng.gen_range(0..keys.len())])
+}
+
+/// The generator behind every seeded selection. ChaCha8 rather than `StdRng`,
+/// whose algorithm may change between `rand` releases, so a seed keeps naming
+/// the same picks across upgrades.
+pub fn seeded_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+/// The `n`-th draw, counting from 1, of [`select_uniform_with`] from a
+/// generator seeded with `seed`. The first draw is the one `/image?seed=`
+/// makes, so a seed names a whole reproducible sequence over a given map.
+pub fn select_seeded_nth(keys: &[String], seed: u64, n: usize) -> Option<&str> {
+    if n == 0 {
+        return None;
+    }
+    let mut rng = seeded_rng(seed);
+    for _ in 1..n {
+        select_uniform_with(keys, &mut rng);
+    }
+    select_uniform_with(keys, &mut rng)
+}
+
+/// Per-key selection weights, blending uniform and exponential recency bias.
+///
+/// `recency` of `0.0` is uniform, `1.0` is fully biased toward later keys.
+/// As in [`weights_at`], exponents are relative to the newest key.
+pub fn weights_for(len: usize, decay: f64, recency: f64) -> Vec<f64> {
+    let newest = len.saturating_sub(1);
+    let biased: Vec<f64> = (0..len)
+        .map(|i| (-((newest - i) as f64) * decay).exp())
+        .collect();
+    let total: f64 = biased.iter().sum();
+    let uniform = 1.0 / len as f64;
+    biased
+        .into_iter()
+        .map(|w| (1.0 - recency) * uniform + recency * w / total)
+        .collect()
+}
+
+/// [`weights_for`] over candidates at ascending `positions` in the full key
+/// list, each weighted by its position rather than its rank among the
+/// candidates, so gaps and a slice's offset count toward a key's age.
+///
+/// Exponents are taken relative to the newest candidate, so large positions
+/// don't overflow.
+pub fn weights_at(positions: &[usize], decay: f64, recency: f64) -> Vec<f64> {
+    let Some(&newest) = positions.last() else {
+        return Vec::new();
+    };
+    let biased: Vec<f64> = positions
+        .iter()
+        .map(|&p| (-((newest - p) as f64) * decay).exp())
+        .collect();
+    let total: f64 = biased.iter().sum();
+    let uniform = 1.0 / positions.len() as f64;
+    biased
+        .into_iter()
+        .map(|w| (1.0 - recency) * uniform + recency * w / total)
+        .collect()
+}
+
+/// Clamps normalized `weights` so none exceeds `max`, spreading the excess
+/// over the uncapped keys in proportion to their weight.
+///
+/// A cap too low for every key to fit under (`max * len <= 1`) makes the
+/// weights uniform. Non-finite weights are left for the caller's fallback.
+pub fn cap_weights(weights: &mut [f64], max: f64) {
+    let len = weights.len();
+    if len == 0 {
+        return;
+    }
+    if max * len as f64 <= 1.0 {
+        weights.fill(1.0 / len as f64);
+        return;
+    }
+    let total: f64 = weights.iter().sum();
+    if !total.is_finite() || total <= 0.0 {
+        return;
+    }
+    weights.iter_mut().for_each(|w| *w /= total);
+    loop {
+        let (mut excess, mut free) = (0.0, 0.0);
+        for w in weights.iter_mut() {
+            if *w > max {
+                excess += *w - max;
+                *w = max;
+            } else if *w < max {
+                free += *w;
+            }
+        }
+        if excess <= 0.0 || free <= 0.0 {
+            return;
+        }
+        let scale = 1.0 + excess / free;
+        weights
+            .iter_mut()
+            .filter(|w| **w < max)
+            .for_each(|w| *w *= scale);
+    }
+}
+
+/// Multiplier `k` in the default decay `k / len`.
+///
+/// Fixes the newest-to-oldest weight ratio at `e^k` (about 148x) whatever the
+/// collection size; at 100 keys it matches the old fixed default of `0.05`.
+pub const DECAY_SCALE: f64 = 5.0;
+
+/// The decay at which a key `halflife` positions older than another has half
+/// its weight: `ln(2) / halflife`.
+pub fn halflife_decay(halflife: f64) -> f64 {
+    std::f64::consts::LN_2 / halflife
+}
+
+/// The default recency decay for `len` keys: [`DECAY_SCALE`] `/ len`.
+pub fn scaled_decay(len: usize) -> f64 {
+    DECAY_SCALE / len.max(1) as f64
+}
+
+/// Picks a key weighted toward the end of `keys` (see [`weights_for`]).
+pub fn select_biased(keys: &[String], decay: f64, recency: f64) -> Option<&str> {
+    select_biased_with(keys, decay, recency, None, &mut thread_rng())
+}
+
+/// [`select_biased`] drawing from `rng`, with each key's probability capped
+/// at `max_weight` (see [`cap_weights`]).
+///
+/// Falls back to uniform selection when the weights are degenerate (e.g. a
+/// decay large enough to overflow them).
+pub fn select_biased_with<'a>(
+    keys: &'a [String],
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    let i = biased_position(keys.len(), decay, recency, max_weight, rng)?;
+    Some(&keys[i])
+}
+
+/// Positions in `keys` of those timestamped at or before `cutoff`, plus every
+/// key without a timestamp.
+pub fn aged_indices(keys: &[String], cutoff: DateTime<Utc>) -> Vec<usize> {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, key)| parse_key_timestamp(key).is_none_or(|t| t <= cutoff))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// A uniform pick among the keys at `indices`, positions in `keys`.
+pub fn select_uniform_among<'a>(
+    keys: &'a [String],
+    indices: &[usize],
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    let &i = indices.choose(rng)?;
+    Some(&keys[i])
+}
+
+/// [`select_biased_with`] over the keys at `indices`, ascending positions in
+/// sorted `keys`, weighting by position among the candidates.
+pub fn select_biased_among<'a>(
+    keys: &'a [String],
+    indices: &[usize],
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    let i = biased_position(indices.len(), decay, recency, max_weight, rng)?;
+    Some(&keys[indices[i]])
+}
+
+/// [`select_biased_among`] weighting by position in `keys` (see
+/// [`weights_at`]) instead of by rank among the candidates.
+pub fn select_biased_at<'a>(
+    keys: &'a [String],
+    indices: &[usize],
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    if indices.is_empty() {
+        return None;
+    }
+    let weights = weights_at(indices, decay, recency);
+    let i = weighted_position(weights, decay, recency, max_weight, rng);
+    Some(&keys[indices[i]])
+}
+
+/// Up to `count` distinct keys at `indices`, ascending positions in sorted
+/// `keys`, drawn without replacement by the weights [`select_biased_among`]
+/// uses, returned in key order.
+///
+/// Efraimidis–Spirakis sampling: each candidate gets the score `ln(u) / w`
+/// for a uniform `u`, and the highest scores win. Degenerate weights fall back
+/// to a uniform sample.
+pub fn select_biased_sample_among<'a>(
+    keys: &'a [String],
+    indices: &[usize],
+    count: usize,
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Vec<&'a str> {
+    let weights = weights_for(indices.len(), decay, recency);
+    sample_weighted(
+        keys, indices, count, weights, decay, recency, max_weight, rng,
+    )
+}
+
+/// [`select_biased_sample_among`] weighting by position in `keys` (see
+/// [`weights_at`]) instead of by rank among the candidates.
+pub fn select_biased_sample_at<'a>(
+    keys: &'a [String],
+    indices: &[usize],
+    count: usize,
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Vec<&'a str> {
+    let weights = weights_at(indices, decay, recency);
+    sample_weighted(
+        keys, indices, count, weights, decay, recency, max_weight, rng,
+    )
+}
+
+/// The sampling behind [`select_biased_sample_among`], given each
+/// candidate's uncapped `weights`; `decay` and `recency` are for the log.
+#[allow(clippy::too_many_arguments)]
+fn sample_weighted<'a>(
+    keys: &'a [String],
+    indices: &[usize],
+    count: usize,
+    mut weights: Vec<f64>,
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Vec<&'a str> {
+    if let Some(max) = max_weight {
+        cap_weights(&mut weights, max);
+    }
+    let usable =
+        weights.iter().all(|w| w.is_finite() && *w >= 0.0) && weights.iter().any(|&w| w > 0.0);
+    // A zero weight scores `-inf`, so it only fills out a batch larger than
+    // the positively weighted candidates.
+    let mut picked: Vec<usize> = if usable {
+        let mut scored: Vec<(f64, usize)> = weights
+            .iter()
+            .map(|w| rng.gen::<f64>().ln() / w)
+            .zip(0..)
+            .collect();
+        let count = count.min(scored.len());
+        let higher = |a: &(f64, usize), b: &(f64, usize)| b.0.total_cmp(&a.0);
+        if count < scored.len() {
+            scored.select_nth_unstable_by(count, higher);
+            scored.truncate(count);
+        }
+        scored.into_iter().map(|(_, i)| indices[i]).collect()
+    } else {
+        warn!(
+            decay,
+            recency, "degenerate weights, falling back to uniform"
+        );
+        let count = count.min(indices.len());
+        rand::seq::index::sample(rng, indices.len(), count)
+            .into_iter()
+            .map(|i| indices[i])
+            .collect()
+    };
+    picked.sort_unstable();
+    picked.into_iter().map(|i| keys[i].as_str()).collect()
+}
+
+/// A biased draw from `0..len`, or `None` when `len` is zero.
+fn biased_position(
+    len: usize,
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    let weights = weights_for(len, decay, recency);
+    Some(weighted_position(weights, decay, recency, max_weight, rng))
+}
+
+/// A draw from non-empty `weights` after capping, uniform when they're
+/// degenerate; `decay` and `recency` are for the log.
+fn weighted_position(
+    mut weights: Vec<f64>,
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    rng: &mut impl Rng,
+) -> usize {
+    if let Some(max) = max_weight {
+        cap_weights(&mut weights, max);
+    }
+    match WeightedIndex::new(&weights) {
+        Ok(dist) => rng.sample(dist),
+        Err(error) => {
+            warn!(%error, decay, recency, "degenerate weights, falling back to uniform");
+            rng.gen_range(0..weights.len())
+        }
+    }
+}
+
+/// Positions in `sorted_keys` of the keys tagged `tag` that sort at or after
+/// `bound`, ascending: the tag's index intersected with
+/// [`keys_after`](ImageMap::keys_after). That slice is a suffix of the map, so
+/// the intersection is the tail of the index past its start.
+pub fn tagged_after<'a>(image_map: &'a ImageMap, tag: &str, bound: &str) -> &'a [usize] {
+    let Some(indices) = image_map.tag_index.get(tag) else {
+        return &[];
+    };
+    let start = image_map.len() - image_map.keys_after(bound).len();
+    &indices[indices.partition_point(|&i| i < start)..]
+}
+
+/// Positions in sorted `keys` of those whose timestamp falls in one of
+/// `months` (1–12), in any year. Keys without a timestamp never match.
+pub fn month_indices(keys: &[String], months: &[u32]) -> Vec<usize> {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, key)| parse_key_timestamp(key).is_some_and(|t| months.contains(&t.month())))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Positions in sorted `keys` of those taken on `now`'s month and day in an
+/// earlier year, for "on this day" picks.
+pub fn on_this_day_indices(keys: &[String], now: DateTime<Utc>) -> Vec<usize> {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, key)| {
+            parse_key_timestamp(key).is_some_and(|t| {
+                (t.month(), t.day()) == (now.month(), now.day()) && t.year() < now.year()
+            })
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// The `count` keys [`select_biased_with`] favors most, heaviest first, with
+/// their probabilities. Deterministic; equal weights rank the newer key first.
+pub fn select_top_biased(
+    keys: &[String],
+    decay: f64,
+    recency: f64,
+    max_weight: Option<f64>,
+    count: usize,
+) -> Vec<(&str, f64)> {
+    let mut weights = weights_for(keys.len(), decay, recency);
+    if let Some(max) = max_weight {
+        cap_weights(&mut weights, max);
+    }
+    let heavier = |&a: &usize, &b: &usize| weights[b].total_cmp(&weights[a]).then(b.cmp(&a));
+    let mut ranked: Vec<usize> = (0..keys.len()).collect();
+    if count < ranked.len() {
+        ranked.select_nth_unstable_by(count, heavier);
+        ranked.truncate(count);
+    }
+    ranked.sort_unstable_by(heavier);
+    ranked
+        .into_iter()
+        .map(|i| (keys[i].as_str(), weights[i]))
+        .collect()
+}
+
+/// Picks a key uniformly among those of `media_type`.
+pub fn select_typed_with<'a>(
+    image_map: &'a ImageMap,
+    media_type: MediaType,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    select_typed_in(image_map, media_type, 0..image_map.sorted_keys.len(), rng)
+}
+
+/// Like [`select_typed_with`], restricted to the keys at indices in `range`.
+pub fn select_typed_in<'a>(
+    image_map: &'a ImageMap,
+    media_type: MediaType,
+    range: Range<usize>,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    let indices = image_map.type_index.get(&media_type)?;
+    let start = indices.partition_point(|&i| i < range.start);
+    let end = indices.partition_point(|&i| i < range.end);
+    let &i = indices[start..end].choose(rng)?;
+    Some(&image_map.sorted_keys[i])
+}
+
+/// Per-key weights multiplied by the factor of each boosted tag a key carries.
+pub fn boosted_weights(image_map: &ImageMap, boost: &HashMap<String, f64>) -> Vec<f64> {
+    let mut weights = vec![1.0; image_map.sorted_keys.len()];
+    for (tag, factor) in boost {
+        for &i in image_map.tag_index.get(tag).into_iter().flatten() {
+            weights[i] *= factor;
+        }
+    }
+    weights
+}
+
+/// Picks a key across the whole map, weighted by [`boosted_weights`].
+pub fn select_boosted<'a>(
+    image_map: &'a ImageMap,
+    boost: &HashMap<String, f64>,
+) -> Option<&'a str> {
+    select_boosted_with(image_map, boost, &mut thread_rng())
+}
+
+/// [`select_boosted`] drawing from `rng`.
+pub fn select_boosted_with<'a>(
+    image_map: &'a ImageMap,
+    boost: &HashMap<String, f64>,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    select_weighted(
+        &image_map.sorted_keys,
+        boosted_weights(image_map, boost),
+        rng,
+    )
+}
+
+/// Draws one of `keys` by the matching `weights`; `None` if they are empty or
+/// all zero.
+fn select_weighted<'a>(
+    keys: &'a [String],
+    weights: Vec<f64>,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    if keys.is_empty() {
+        return None;
+    }
+    let dist = WeightedIndex::new(weights).ok()?;
+    Some(&keys[rng.sample(dist)])
+}
+
+/// Per-key weights: `factor` for keys timestamped on `weekday` (UTC), `1`
+/// for the rest, including keys without a timestamp.
+pub fn weekday_weights(keys: &[String], weekday: Weekday, factor: f64) -> Vec<f64> {
+    keys.iter()
+        .map(|key| match parse_key_timestamp(key) {
+            Some(t) if t.weekday() == weekday => factor,
+            _ => 1.0,
+        })
+        .collect()
+}
+
+/// Picks one of `keys`, weighted by [`weekday_weights`].
+pub fn select_weekday_with<'a>(
+    keys: &'a [String],
+    weekday: Weekday,
+    factor: f64,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    select_weighted(keys, weekday_weights(keys, weekday, factor), rng)
+}
+
+/// Per-key counts of the tags each shares with `key`, zero for `key` itself;
+/// `None` if the map doesn't contain `key`.
+pub fn overlap_weights(image_map: &ImageMap, key: &str) -> Option<Vec<f64>> {
+    let target = image_map
+        .sorted_keys
+        .binary_search_by(|k| k.as_str().cmp(key))
+        .ok()?;
+    let mut weights = vec![0.0; image_map.sorted_keys.len()];
+    for indices in image_map.tag_index.values() {
+        if indices.binary_search(&target).is_ok() {
+            for &i in indices {
+                weights[i] += 1.0;
+            }
+        }
+    }
+    weights[target] = 0.0;
+    Some(weights)
+}
+
+/// Picks another key weighted by [`overlap_weights`]; `None` if `key` is
+/// unknown or shares no tags.
+pub fn select_similar_with<'a>(
+    image_map: &'a ImageMap,
+    key: &str,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
+    select_weighted(
+        &image_map.sorted_keys,
+        overlap_weights(image_map, key)?,
+        rng,
+    )
+}
+
+/// A tag and the number of images carrying it.
+#[derive(Serialize, Debug, PartialEq, utoipa::ToSchema)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
+}
+
+/// Tag counts sorted by count descending, dropping tags below `min_count`.
+pub fn tag_counts(image_map: &ImageMap, min_count: usize) -> Vec<TagCount> {
+    let mut counts: Vec<TagCount> = image_map
+        .tag_index
+        .iter()
+        .filter(|(_, indices)| indices.len() >= min_count)
+        .map(|(tag, indices)| TagCount {
+            tag: tag.clone(),
+            count: indices.len(),
+        })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+    counts
+}
+
+/// How finely [`count_by`] buckets keys: by year, month or day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    Year,
+    Month,
+    Day,
+}
+
+impl Granularity {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "year" => Some(Self::Year),
+            "month" => Some(Self::Month),
+            "day" => Some(Self::Day),
+            _ => None,
+        }
+    }
+
+    /// Bytes of a `YYYY-MM-DD` key prefix naming the bucket.
+    fn prefix_len(self) -> usize {
+        match self {
+            Self::Year => 4,
+            Self::Month => 7,
+            Self::Day => 10,
+        }
+    }
+}
+
+/// A timeline bucket, e.g. `2024-06`, and the number of keys in it.
+#[derive(Serialize, Debug, PartialEq, utoipa::ToSchema)]
+pub struct BucketCount {
+    pub bucket: String,
+    pub count: usize,
+}
+
+/// Counts of sorted `keys` per date prefix at `granularity`, oldest first,
+/// in one pass. Keys without a timestamp aren't counted.
+pub fn count_by(keys: &[String], granularity: Granularity) -> Vec<BucketCount> {
+    let len = granularity.prefix_len();
+    let mut counts: Vec<BucketCount> = Vec::new();
+    for key in keys.iter().filter(|key| parse_key_timestamp(key).is_some()) {
+        let bucket = &key[..len];
+        match counts.last_mut() {
+            Some(last) if last.bucket == bucket => last.count += 1,
+            _ => counts.push(BucketCount {
+                bucket: bucket.to_string(),
+                count: 1,
+            }),
+        }
+    }
+    counts
+}
+
+/// Longest `{bound}` / `?after=` value accepted, in bytes.
+pub const MAX_BOUND_LEN: usize = 64;
+
+/// Whether `bound` looks like a key prefix: non-empty, at most
+/// [`MAX_BOUND_LEN`] bytes, and only ASCII alphanumerics and `-_:.`.
+pub fn valid_bound(bound: &str) -> bool {
+    !bound.is_empty()
+        && bound.len() <= MAX_BOUND_LEN
+        && bound
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_:.".contains(&b))
+}
+
+/// The suffix of sorted `keys` that sort at or after `bound`.
+pub fn filter_after<'a>(keys: &'a [String], bound: &str) -> &'a [String] {
+    let start = keys.partition_point(|k| k.as_str() < bound);
+    &keys[start..]
+}
+
+/// Sorted `keys` without the newest `n`; empty when `n` covers them all.
+pub fn skip_newest(keys: &[String], n: usize) -> &[String] {
+    &keys[..keys.len().saturating_sub(n)]
+}
+
+/// `suffix`, a suffix of sorted `keys`, extended back to at least `min` keys.
+/// An empty `suffix` stays empty so a bound past every key still matches nothing.
+pub fn widen_suffix<'a>(keys: &'a [String], suffix: &'a [String], min: usize) -> &'a [String] {
+    if suffix.is_empty() || suffix.len() >= min {
+        return suffix;
+    }
+    &keys[keys.len().saturating_sub(min)..]
+}
+
+/// Up to `window` sorted keys centred on where `bound` would sort.
+pub fn window_around<'a>(keys: &'a [String], bound: &str, window: usize) -> &'a [String] {
+    let at = keys.partition_point(|k| k.as_str() < bound);
+    let start = at.saturating_sub(window / 2);
+    let end = (start + window).min(keys.len());
+    let start = end.saturating_sub(window);
+    &keys[start..end]
+}
+
+/// The key at index `n`, where negative indices count back from the end.
+pub fn select_index(keys: &[String], n: i64) -> Option<&str> {
+    let len = keys.len() as i64;
+    let i = if n < 0 { len + n } else { n };
+    if (0..len).contains(&i) {
+        Some(&keys[i as usize])
+    } else {
+        None
+    }
+}
+
+/// The `n`-th most recent key, where `1` is the newest.
+pub fn select_recent(keys: &[String], n: usize) -> Option<&str> {
+    if n == 0 || n > keys.len() {
+        return None;
+    }
+    Some(&keys[keys.len() - n])
+}
+
+/// Up to `count` keys at evenly spaced positions (`i * len / count`), in order.
+pub fn select_evenly(keys: &[String], count: usize) -> Vec<&str> {
+    if count >= keys.len() {
+        return keys.iter().map(String::as_str).collect();
+    }
+    (0..count)
+        .map(|i| keys[i * keys.len() / count].as_str())
+        .collect()
+}
+
+/// Up to `count` distinct keys drawn from `rng`, in sorted order.
+pub fn select_sample<'a>(keys: &'a [String], count: usize, rng: &mut impl Rng) -> Vec<&'a str> {
+    let mut indices = rand::seq::index::sample(rng, keys.len(), count.min(keys.len())).into_vec();
+    indices.sort_unstable();
+    indices.into_iter().map(|i| keys[i].as_str()).collect()
+}
+
+/// Like [`select_sample`], but passing over keys whose `hashes` entry matches
+/// one already picked, so re-uploads of an image appear at most once. Keys
+/// without a hash are always eligible.
+pub fn select_sample_distinct<'a>(
+    keys: &'a [String],
+    count: usize,
+    hashes: &HashMap<String, String>,
+    rng: &mut impl Rng,
+) -> Vec<&'a str> {
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    let mut seen = HashSet::new();
+    let mut indices = Vec::with_capacity(count.min(keys.len()));
+    let len = order.len();
+    for i in 0..len {
+        if indices.len() == count {
+            break;
+        }
+        // One step of a Fisher-Yates shuffle, so only drawn keys are visited.
+        order.swap(i, rng.gen_range(i..len));
+        let key = &keys[order[i]];
+        if hashes.get(key).is_none_or(|hash| seen.insert(hash)) {
+            indices.push(order[i]);
+        }
+    }
+    indices.sort_unstable();
+    indices.into_iter().map(|i| keys[i].as_str()).collect()
+}
+
+/// A seed identifying the ISO week containing `now`, e.g. `202441`.
+pub fn iso_week_seed(now: DateTime<Utc>) -> u64 {
+    let week = now.iso_week();
+    week.year() as u64 * 100 + week.week() as u64
+}
+
+/// A seed identifying the UTC day containing `now`, e.g. `20241014`.
+pub fn day_seed(now: DateTime<Utc>) -> u64 {
+    now.year() as u64 * 10_000 + now.month() as u64 * 100 + now.day() as u64
+}
+
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// `n` in base62, most significant digit first, with no leading zeros.
+pub fn encode_base62(mut n: u64) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(BASE62[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+/// The inverse of [`encode_base62`]. `None` for empty input, characters
+/// outside `[0-9A-Za-z]`, leading zeros, or values past `u64::MAX`.
+pub fn decode_base62(s: &str) -> Option<u64> {
+    if s.is_empty() || (s.len() > 1 && s.starts_with('0')) {
+        return None;
+    }
+    s.bytes().try_fold(0u64, |n, c| {
+        let digit = BASE62.iter().position(|&d| d == c)? as u64;
+        n.checked_mul(62)?.checked_add(digit)
+    })
+}
+
+/// The index at `position` in a permutation of `0..len` seeded by `seed`.
+///
+/// A four-round Feistel network over the smallest even-bit domain covering
+/// `len`, cycle-walking past values outside it, so each position costs
+/// constant expected time and no allocation. Panics if `position >= len`.
+pub fn shuffled_index(len: usize, seed: u64, position: usize) -> usize {
+    assert!(position < len, "position {position} out of 0..{len}");
+    let bits = usize::BITS - (len - 1).leading_zeros();
+    let half = bits.div_ceil(2).max(1);
+    let mask = (1u64 << half) - 1;
+    let mut x = position as u64;
+    loop {
+        let (mut left, mut right) = (x >> half, x & mask);
+        for round in 0..4u64 {
+            let f = splitmix64(seed ^ round.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ right) & mask;
+            (left, right) = (right, left ^ f);
+        }
+        x = (left << half) | right;
+        if x < len as u64 {
+            return x as usize;
+        }
+    }
+}
+
+/// The SplitMix64 output function: a fixed, well-mixed hash of `z`.
+fn splitmix64(z: u64) -> u64 {
+    let z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Parses `content` only if its hash differs from `current_hash`.
+pub fn maybe_parse_if_changed(content: &str, current_hash: u64) -> Option<ImageMap> {
+    maybe_parse_if_changed_with(content, current_hash, &ParseOptions::default())
+}
+
+/// [`maybe_parse_if_changed`] with explicit [`ParseOptions`].
+pub fn maybe_parse_if_changed_with(
+    content: &str,
+    current_hash: u64,
+    options: &ParseOptions,
+) -> Option<ImageMap> {
+    if hash_content(content) == current_hash {
+        return None;
+    }
+    ImageMap::parse_with(content, options).ok()
+}
+
+#[cfg(test)]
+mod tests;
@@ -1,188 +1,3799 @@
+mod access_log;
+mod audit;
+mod breaker;
+mod cdn;
+mod config;
+mod deprecation;
+mod discover;
+mod fair;
+mod forwarded;
+#[cfg(any(feature = "montage", feature = "transform"))]
+mod imaging;
+mod metrics;
+#[cfg(feature = "montage")]
+mod montage;
+#[cfg(feature = "qr")]
+mod qr;
+mod session;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "transform")]
+mod transform;
+mod validate;
+
+use access_log::AccessLog;
+use audit::Actor;
 use axum::{
-    extract::{Path, Query, State},
-    http::{header, StatusCode},
-    response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    extract::{ConnectInfo, FromRequestParts, Path, Query, Request, State},
+    http::{header, request::Parts, Extensions, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router, ServiceExt,
+};
+#[cfg(feature = "tls")]
+use axum_server::tls_rustls::RustlsConfig;
+use breaker::Breakers;
+use cdn::PrefixPool;
+use chrono::{DateTime, Datelike, SecondsFormat, Timelike, Utc};
+use config::Config;
+use deprecation::Deprecations;
+use discover::RecentlyServed;
+use fair::ServedCounts;
+use forwarded::ClientInfo;
+use hmac::{Hmac, Mac};
+use metrics::Metrics;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::{seq::SliceRandom, Rng, RngCore};
+use regex::Regex;
+#[cfg(feature = "http")]
+use roulette::source::HttpSource;
+#[cfg(feature = "s3")]
+use roulette::source::S3Source;
+use roulette::source::{EmbeddedSource, FileSource, MapSource, ReaderSource};
+use roulette::{
+    aged_indices, cap_weights, count_by, day_seed, decode_base62, encode_base62, halflife_decay,
+    hash_content, iso_week_seed, jittered_ttl, month_indices, on_this_day_indices, parse_boost,
+    parse_duration, parse_months, scaled_decay, seeded_rng, select_biased_among, select_biased_at,
+    select_biased_sample_among, select_biased_sample_at, select_biased_with, select_boosted_with,
+    select_evenly, select_index, select_recent, select_sample, select_sample_distinct,
+    select_seeded_nth, select_similar_with, select_top_biased, select_typed_in,
+    select_uniform_among, select_uniform_with, select_weekday_with, skip_newest, tag_counts,
+    tagged_after, valid_bound, weights_for, widen_suffix, window_around, BucketCount, Dimensions,
+    Granularity, ImageMap, MediaType, ParseOptions, Reconciliation, TagCount,
 };
-use rand::{distributions::WeightedIndex, prelude::*};
-use serde::Deserialize;
+use serde::{ser::SerializeMap, Deserialize, Serialize};
+use session::{SessionError, SessionStore};
+use sha2::Sha256;
 use std::{
-    collections::HashMap,
-    env, fs,
-    hash::{Hash, Hasher},
+    collections::{BTreeMap, HashMap},
+    env, io,
+    net::SocketAddr,
+    ops::Range,
     sync::Arc,
-    sync::RwLock,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock, RwLockReadGuard,
+    },
+    time::{Duration, Instant},
 };
-use tokio::signal;
-use tower_http::trace::TraceLayer;
-use tracing::{info, warn};
+use tokio::{signal, sync::Notify};
+use tower::Layer;
+use tower_http::{
+    normalize_path::{NormalizePath, NormalizePathLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, info, warn};
+use url::Url;
+use utoipa::{IntoParams, IntoResponses, OpenApi, ToSchema};
+use validate::ReloadCheck;
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct CacheQuery {
     cache: Option<String>,
 }
 
-fn parse_duration(s: &str) -> Option<u64> {
-    let s = s.trim();
-    let (num, suffix) = s.split_at(s.len().saturating_sub(1));
-    let value: u64 = num.parse().ok()?;
-    match suffix {
-        "s" => Some(value),
-        "m" => Some(value * 60),
-        "h" => Some(value * 3600),
-        "d" => Some(value * 86400),
-        _ => None,
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MaintenanceQuery {
+    on: bool,
+}
+
+/// Query-string bounds, the composable form of the `/after/{bound}` routes.
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RangeQuery {
+    /// Only keys sorting at or after this bound, e.g. `2024`.
+    since: Option<String>,
+    /// Only keys sorting before this bound, e.g. `2025`.
+    until: Option<String>,
+}
+
+impl RangeQuery {
+    fn is_empty(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    /// `400` for invalid bounds, or for any bound alongside a path bound.
+    fn check(&self, state: &AppState, path_bound: bool) -> Result<(), StatusCode> {
+        if path_bound && !self.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        state.check_bound(self.since.as_deref())?;
+        state.check_bound(self.until.as_deref())
+    }
+
+    /// `?after=` as the `since` bound, which it can't be combined with.
+    fn or_after(self, after: Option<String>) -> Result<Self, StatusCode> {
+        match after {
+            None => Ok(self),
+            Some(_) if !self.is_empty() => Err(StatusCode::BAD_REQUEST),
+            since => Ok(Self { since, until: None }),
+        }
+    }
+}
+
+/// `/image?mode=` strategies, each the selection of a dedicated route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SelectionMode {
+    Uniform,
+    /// As `/image/latest`.
+    Biased,
+    /// As `/image/themed`.
+    Weighted,
+    /// As `/image/fair`.
+    Fair,
+    /// Uniform, seeded by the UTC day: the same pick all day.
+    Daily,
+}
+
+impl SelectionMode {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "uniform" => Some(Self::Uniform),
+            "biased" => Some(Self::Biased),
+            "weighted" => Some(Self::Weighted),
+            "fair" => Some(Self::Fair),
+            "daily" => Some(Self::Daily),
+            _ => None,
+        }
+    }
+}
+
+/// What a biased pick's recency weights are relative to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Weighting {
+    /// Rank among the candidates, with the decay scaled to their count: the
+    /// oldest candidate always gets the lowest weight.
+    Slice,
+    /// Position in the whole map, with the decay scaled to its size, so a
+    /// filtered pool keeps the weights its keys have on the full timeline.
+    Global,
+}
+
+impl Weighting {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "slice" => Some(Self::Slice),
+            "global" => Some(Self::Global),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ModeQuery {
+    /// `uniform` (default), `biased`, `weighted`, `fair` or `daily`.
+    mode: Option<String>,
+    /// Only keys `>= after`, as the `/after/{bound}` routes; not with `weighted`
+    /// or `fair`.
+    after: Option<String>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EmbedQuery {
+    /// Time between images, e.g. `30s` or `5m` (default: `30s`).
+    interval: Option<String>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RandomQuery {
+    cache: Option<String>,
+    probe: Option<String>,
+    seed: Option<u64>,
+    /// Skip keys timestamped more recently than this, e.g. `6h`.
+    min_age: Option<String>,
+    #[serde(rename = "type")]
+    media_type: Option<String>,
+    /// `1`: hint the next seed's selection in a `Link` header.
+    prefetch: Option<String>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ThemedQuery {
+    cache: Option<String>,
+    boost: Option<String>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SurpriseQuery {
+    cache: Option<String>,
+    /// Seeds both the mode and the pick.
+    seed: Option<u64>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DowQuery {
+    cache: Option<String>,
+    /// Overrides `DOW_BOOST` for this request.
+    factor: Option<f64>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct IndexQuery {
+    cache: Option<String>,
+    after: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SeqQuery {
+    seed: u64,
+    /// Which draw of the seeded sequence, from `1` up to `10000`.
+    n: usize,
+    cache: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EvenlyQuery {
+    count: usize,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MonthsQuery {
+    /// Comma-separated months, e.g. `06,07,08`.
+    m: String,
+    cache: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TopQuery {
+    #[serde(default = "default_top_count")]
+    count: usize,
+    recency: Option<f64>,
+    decay: Option<f64>,
+    halflife: Option<f64>,
+    max_weight: Option<f64>,
+}
+
+fn default_top_count() -> usize {
+    5
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WeekQuery {
+    #[serde(default = "default_week_count")]
+    count: usize,
+    /// Return at most one image per content `hash`.
+    #[serde(default)]
+    distinct: bool,
+}
+
+fn default_week_count() -> usize {
+    7
+}
+
+#[derive(Serialize, ToSchema)]
+struct Stats<'a> {
+    count: usize,
+    oldest: Option<&'a str>,
+    newest: Option<&'a str>,
+    future_keys: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Version {
+    hash: String,
+    count: usize,
+    /// RFC 3339, UTC.
+    loaded_at: String,
+}
+
+/// A `/s/{code}` link to a random image.
+#[derive(Serialize, ToSchema)]
+struct ShortLink<'a> {
+    code: String,
+    /// `/s/{code}` under `BASE_PATH`.
+    path: String,
+    key: &'a str,
+}
+
+/// An entry's metadata as the map records it.
+#[derive(Serialize, ToSchema)]
+struct Meta<'a> {
+    key: &'a str,
+    file: &'a str,
+    #[serde(rename = "type")]
+    #[schema(value_type = String, example = "image")]
+    media_type: MediaType,
+    tags: Vec<&'a str>,
+    /// Pixels; present when the map records both dimensions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Ping {
+    /// `false` when the map is empty or maintenance mode is on.
+    ok: bool,
+    keys: usize,
+    /// RFC 3339, UTC.
+    loaded_at: String,
+    hash: String,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SessionQuery {
+    cache: Option<String>,
+    #[serde(default)]
+    reshuffle: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DebugKeysQuery {
+    around: Option<String>,
+    #[serde(default = "default_window")]
+    window: usize,
+}
+
+fn default_window() -> usize {
+    20
+}
+
+const MAX_DEBUG_WINDOW: usize = 1000;
+
+#[derive(Serialize, ToSchema)]
+struct DebugEntry<'a> {
+    key: &'a str,
+    file: &'a str,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DebugWeightsQuery {
+    after: Option<String>,
+    decay: Option<f64>,
+    halflife: Option<f64>,
+    recency: Option<f64>,
+    max_weight: Option<f64>,
+    skip: Option<usize>,
+    /// `slice` or `global`; defaults to `BIAS_WEIGHTING`.
+    weighting: Option<String>,
+    #[serde(default = "default_window")]
+    limit: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct KeyWeight<'a> {
+    key: &'a str,
+    probability: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DebugWeights<'a> {
+    count: usize,
+    decay: f64,
+    recency: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_weight: Option<f64>,
+    /// The newest `limit` candidates, oldest first.
+    weights: Vec<KeyWeight<'a>>,
+}
+
+/// Headers proxies commonly set or rewrite, echoed by `/debug/echo`.
+const ECHOED_HEADERS: [&str; 8] = [
+    "host",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "via",
+    "user-agent",
+];
+
+#[derive(Serialize, ToSchema)]
+struct Echo {
+    /// Peer address of the TCP connection.
+    remote_addr: Option<String>,
+    /// The address IP-based features should attribute the request to.
+    client_ip: Option<String>,
+    /// The scheme the client used, `http` or `https`.
+    scheme: &'static str,
+    headers: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DebugKeys<'a> {
+    count: usize,
+    first: Option<&'a str>,
+    last: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    around: Option<Vec<DebugEntry<'a>>>,
+}
+
+#[cfg(feature = "qr")]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct QrQuery {
+    module: Option<u32>,
+    ec: Option<String>,
+}
+
+#[cfg(feature = "montage")]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MontageQuery {
+    #[serde(default = "default_montage_count")]
+    count: usize,
+    #[serde(default = "default_montage_cols")]
+    cols: usize,
+    output: Option<String>,
+}
+
+#[cfg(feature = "montage")]
+fn default_montage_count() -> usize {
+    9
+}
+
+#[cfg(feature = "montage")]
+fn default_montage_cols() -> usize {
+    3
+}
+
+#[cfg(feature = "transform")]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TransformQuery {
+    w: u32,
+    fmt: Option<String>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TagsQuery {
+    min_count: Option<usize>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CountByQuery {
+    /// Only keys sorting at or after this bound, e.g. `2024`.
+    after: Option<String>,
+    /// Only keys sorting before this bound, e.g. `2025`.
+    until: Option<String>,
+}
+
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LatestQuery {
+    cache: Option<String>,
+    recency: Option<f64>,
+    decay: Option<f64>,
+    halflife: Option<f64>,
+    max_weight: Option<f64>,
+    skip: Option<usize>,
+    seed: Option<u64>,
+    /// Skip keys timestamped more recently than this, e.g. `6h`.
+    min_age: Option<String>,
+    /// Return this many distinct keys as a JSON list instead of one.
+    count: Option<usize>,
+    /// `slice` or `global`; defaults to `BIAS_WEIGHTING`.
+    weighting: Option<String>,
+    /// `1`: hint the next seed's selection in a `Link` header.
+    prefetch: Option<String>,
+}
+
+/// `?prefetch=1`: also select what the client should fetch next.
+fn prefetching(value: Option<&str>) -> bool {
+    value == Some("1")
+}
+
+/// The seed a prefetch hint is drawn with: the next one after `seed`, so a
+/// client stepping through seeds is served what it was told to prefetch.
+/// Unseeded requests hint an independent draw.
+fn next_seed(seed: Option<u64>) -> Option<u64> {
+    seed.map(|seed| seed.wrapping_add(1))
+}
+
+/// `?weighting=`, else `default` (`BIAS_WEIGHTING`); `400` when unrecognized.
+fn parse_weighting(value: Option<&str>, default: Weighting) -> Result<Weighting, StatusCode> {
+    match value.map(Weighting::parse) {
+        Some(Some(weighting)) => Ok(weighting),
+        Some(None) => Err(StatusCode::BAD_REQUEST),
+        None => Ok(default),
+    }
+}
+
+fn parse_decay(value: Option<f64>) -> Result<Option<f64>, StatusCode> {
+    match value {
+        Some(d) if !d.is_finite() || d < 0.0 => Err(StatusCode::BAD_REQUEST),
+        d => Ok(d),
+    }
+}
+
+/// The per-request decay: `?decay=` when given, else one derived from
+/// `?halflife=` (in posts). Either being invalid is `400`.
+fn decay_override(decay: Option<f64>, halflife: Option<f64>) -> Result<Option<f64>, StatusCode> {
+    let decay = parse_decay(decay)?;
+    match halflife {
+        Some(h) if !h.is_finite() || h <= 0.0 => Err(StatusCode::BAD_REQUEST),
+        h => Ok(decay.or(h.map(halflife_decay))),
+    }
+}
+
+fn parse_max_weight(value: Option<f64>) -> Result<Option<f64>, StatusCode> {
+    match value {
+        Some(w) if !(w > 0.0 && w <= 1.0) => Err(StatusCode::BAD_REQUEST),
+        w => Ok(w),
+    }
+}
+
+fn parse_recency(value: Option<f64>) -> Result<f64, StatusCode> {
+    match value {
+        None => Ok(1.0),
+        Some(r) if (0.0..=1.0).contains(&r) => Ok(r),
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// The default map, `image-map.json` unless `EMBEDDED_IMAGE_MAP_PATH` was set
+/// at build time (see `build.rs`).
+const EMBEDDED_IMAGE_MAP: &str = include_str!(env!("EMBEDDED_IMAGE_MAP_FILE"));
+
+/// `IMAGE_MAP_PATH` value that reads the map from stdin.
+const STDIN_PATH: &str = "-";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ResponseFormat {
+    Redirect,
+    Json,
+    Html,
+}
+
+impl ResponseFormat {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "redirect" => Some(Self::Redirect),
+            "json" => Some(Self::Json),
+            "html" => Some(Self::Html),
+            _ => None,
+        }
+    }
+}
+
+/// The strategies `/image/surprise` draws from, named in `X-Selection-Mode`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SurpriseMode {
+    Uniform,
+    /// As `/image/latest`.
+    Biased,
+    /// From the current month of any year, as `/image/months`.
+    Seasonal,
+    /// From today's date in an earlier year.
+    OnThisDay,
+    /// From a random tag's images.
+    TagRandom,
+}
+
+impl SurpriseMode {
+    /// Each mode with its relative chance of being drawn.
+    const WEIGHTS: [(Self, f64); 5] = [
+        (Self::Uniform, 3.0),
+        (Self::Biased, 3.0),
+        (Self::Seasonal, 2.0),
+        (Self::OnThisDay, 1.0),
+        (Self::TagRandom, 1.0),
+    ];
+
+    fn choose(rng: &mut impl Rng) -> Self {
+        let total: f64 = Self::WEIGHTS.iter().map(|(_, w)| w).sum();
+        let mut point = rng.gen_range(0.0..total);
+        for (mode, weight) in Self::WEIGHTS {
+            if point < weight {
+                return mode;
+            }
+            point -= weight;
+        }
+        Self::Uniform
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Uniform => "uniform",
+            Self::Biased => "biased",
+            Self::Seasonal => "seasonal",
+            Self::OnThisDay => "on-this-day",
+            Self::TagRandom => "tag-random",
+        }
+    }
+}
+
+/// `TLS_MIN_VERSION`: the oldest protocol the HTTPS listener accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "1.2" => Some(Self::Tls12),
+            "1.3" => Some(Self::Tls13),
+            _ => None,
+        }
+    }
+}
+
+/// `UNIFORM_EXCLUDE_NEWEST`: the keys plain `/image` leaves out of its pool.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ExcludeNewest {
+    /// The newest `n` keys of the map.
+    Count(usize),
+    /// Keys timestamped within this many seconds of now.
+    AgeSecs(u64),
+}
+
+impl ExcludeNewest {
+    /// A bare number is a count; anything else must be a duration like `6h`,
+    /// short enough to subtract from a timestamp.
+    fn parse(s: &str) -> Option<Self> {
+        match s.parse() {
+            Ok(n) => Some(Self::Count(n)),
+            Err(_) => parse_duration(s)
+                .filter(|&secs| age_delta(secs).is_some())
+                .map(Self::AgeSecs),
+        }
+    }
+}
+
+/// `secs` as a duration, if it is representable.
+fn age_delta(secs: u64) -> Option<chrono::TimeDelta> {
+    i64::try_from(secs)
+        .ok()
+        .and_then(chrono::TimeDelta::try_seconds)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FormatQuery {
+    format: Option<String>,
+}
+
+struct Format(ResponseFormat);
+
+impl FromRequestParts<Arc<AppState>> for Format {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let query: Query<FormatQuery> =
+            Query::try_from_uri(&parts.uri).map_err(|_| StatusCode::BAD_REQUEST)?;
+        if let Some(format) = query.0.format {
+            return ResponseFormat::parse(&format)
+                .map(Format)
+                .ok_or(StatusCode::BAD_REQUEST);
+        }
+        Ok(Format(state.format(&parts.headers)))
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PrefixQuery {
+    prefix: Option<String>,
+    sig: Option<String>,
+}
+
+/// A `?prefix=` override of `IMAGE_URL_PREFIX`, accepted only when `sig` is
+/// its hex HMAC-SHA256 under `SIGNING_KEY` and its host is allowlisted.
+struct Prefix(Option<String>);
+
+impl FromRequestParts<Arc<AppState>> for Prefix {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let query: Query<PrefixQuery> =
+            Query::try_from_uri(&parts.uri).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let Some(prefix) = query.0.prefix else {
+            return Ok(Prefix(None));
+        };
+        if !state.verify_prefix(&prefix, query.0.sig.as_deref().unwrap_or("")) {
+            warn!(%prefix, "refusing unsigned or unlisted prefix override");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(Prefix(Some(prefix.trim_end_matches('/').to_string())))
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct VariantQuery {
+    variant: Option<String>,
+}
+
+/// A decoded `{bound}` path segment that passed [`AppState::check_bound`],
+/// else `400`. On the bare `/after` routes the bound is empty, which every key
+/// sorts at or after, unless `REJECT_EMPTY_BOUND` makes it `400`.
+struct Bound(String);
+
+impl FromRequestParts<Arc<AppState>> for Bound {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let bound = Option::<Path<String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        match bound {
+            Some(Path(bound)) => {
+                state.check_bound(Some(&bound))?;
+                Ok(Bound(bound))
+            }
+            None if state.reject_empty_bound => Err(StatusCode::BAD_REQUEST),
+            None => Ok(Bound(String::new())),
+        }
+    }
+}
+
+/// `400` unless an optional `?after=`-style bound passes [`valid_bound`].
+///
+/// Admin endpoints use this directly; public ones go through
+/// [`AppState::check_bound`], which also applies `ALLOWED_BOUND_PATTERN`.
+fn check_bound(bound: Option<&str>) -> Result<(), StatusCode> {
+    match bound {
+        Some(b) if !valid_bound(b) => Err(StatusCode::BAD_REQUEST),
+        _ => Ok(()),
+    }
+}
+
+/// The `?variant=` to resolve selections to, `None` for the default.
+struct Variant(Option<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Variant {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query: Query<VariantQuery> =
+            Query::try_from_uri(&parts.uri).map_err(|_| StatusCode::BAD_REQUEST)?;
+        match query.0.variant {
+            Some(variant) if variant.is_empty() => Err(StatusCode::BAD_REQUEST),
+            variant => Ok(Variant(variant)),
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The `Authorization: Bearer` credential, if any.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn negotiate(accept: Option<&str>, default: ResponseFormat) -> ResponseFormat {
+    let Some(accept) = accept else {
+        return default;
+    };
+    let types: Vec<&str> = accept
+        .split(',')
+        .map(|t| t.split(';').next().unwrap_or("").trim())
+        .collect();
+    if types.contains(&"application/json") {
+        ResponseFormat::Json
+    } else if types.iter().any(|t| !t.is_empty() && *t != "*/*") {
+        ResponseFormat::Redirect
+    } else {
+        default
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn view_page(key: &str, url: &str) -> String {
+    let key = escape_html(key);
+    let url = escape_html(url);
+    format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{key}</title>
+<meta property="og:title" content="{key}">
+<meta property="og:type" content="website">
+<meta property="og:image" content="{url}">
+<meta name="twitter:card" content="summary_large_image">
+</head>
+<body>
+<img src="{url}" alt="{key}">
+</body>
+</html>
+"#
+    )
+}
+
+/// Full-bleed slideshow of `{base_path}/image`, reloading every `secs`.
+fn embed_page(base_path: &str, secs: u64) -> String {
+    let src = escape_html(&format!("{base_path}/image"));
+    format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{secs}">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>roulette</title>
+<style>html,body{{margin:0;height:100%;background:#000}}img{{display:block;width:100%;height:100%;object-fit:cover}}</style>
+</head>
+<body>
+<img src="{src}" alt="">
+</body>
+</html>
+"#
+    )
+}
+
+/// Names of the `key` and `url` fields in selection bodies, from
+/// `JSON_KEY_FIELD` and `JSON_URL_FIELD`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct FieldNames {
+    key: String,
+    url: String,
+}
+
+impl Default for FieldNames {
+    fn default() -> Self {
+        Self {
+            key: "key".to_string(),
+            url: "url".to_string(),
+        }
+    }
+}
+
+/// Serialized by hand so the field names follow [`FieldNames`]; the schema
+/// shows the defaults.
+#[derive(ToSchema)]
+struct Selection<'a> {
+    key: &'a str,
+    url: String,
+    /// `?prefetch=1`: what the next seed would select.
+    #[schema(no_recursion)]
+    next: Option<Box<Selection<'a>>>,
+    #[serde(skip)]
+    names: &'a FieldNames,
+}
+
+impl Serialize for Selection<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2 + usize::from(self.next.is_some())))?;
+        map.serialize_entry(&self.names.key, self.key)?;
+        map.serialize_entry(&self.names.url, &self.url)?;
+        if let Some(next) = &self.next {
+            map.serialize_entry("next", next)?;
+        }
+        map.end()
+    }
+}
+
+#[derive(ToSchema)]
+struct RankedSelection<'a> {
+    key: &'a str,
+    url: String,
+    probability: f64,
+    #[serde(skip)]
+    names: &'a FieldNames,
+}
+
+impl Serialize for RankedSelection<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry(&self.names.key, self.key)?;
+        map.serialize_entry(&self.names.url, &self.url)?;
+        map.serialize_entry("probability", &self.probability)?;
+        map.end()
+    }
+}
+
+/// JSON body for an empty selection served from `FALLBACK_URL`.
+#[derive(ToSchema)]
+struct Fallback<'a> {
+    url: &'a str,
+    fallback: bool,
+    #[serde(skip)]
+    names: &'a FieldNames,
+}
+
+impl Serialize for Fallback<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry(&self.names.url, self.url)?;
+        map.serialize_entry("fallback", &self.fallback)?;
+        map.end()
+    }
+}
+
+fn prefix_host(url_prefix: &str) -> Option<String> {
+    Url::parse(url_prefix).ok()?.host_str().map(String::from)
+}
+
+/// Whether `url_prefix` is a same-origin path such as `/images` rather than an
+/// absolute URL. Protocol-relative `//host` prefixes are not.
+fn relative_prefix(url_prefix: &str) -> bool {
+    url_prefix.starts_with('/') && !url_prefix.starts_with("//")
+}
+
+/// Whether a relative `url` resolves to a path on the serving origin.
+fn same_origin(url: &str) -> bool {
+    let base = Url::parse("http://origin.invalid/").unwrap();
+    base.join(url).is_ok_and(|u| u.origin() == base.origin())
+}
+
+fn on_host(url: &str, host: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h == host))
+        .unwrap_or(false)
+}
+
+fn now_secs() -> DateTime<Utc> {
+    Utc::now().with_nanosecond(0).unwrap()
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_options(
+    strict_map: bool,
+    drop_future_keys: bool,
+    key_prefix_filter: Option<&str>,
+) -> ParseOptions {
+    ParseOptions {
+        strict: strict_map,
+        not_after: drop_future_keys.then(Utc::now),
+        key_prefix: key_prefix_filter.map(String::from),
+    }
+}
+
+/// Reports the current time; swapped out in tests.
+type Clock = Box<dyn Fn() -> DateTime<Utc> + Send + Sync>;
+
+fn system_clock() -> Clock {
+    Box::new(Utc::now)
+}
+
+/// Produces the generator a request draws its selection from.
+type RngFactory = Box<dyn Fn() -> Box<dyn RngCore> + Send + Sync>;
+
+fn thread_rng_factory() -> RngFactory {
+    Box::new(|| Box::new(rand::thread_rng()))
+}
+
+struct AppState {
+    base_path: String,
+    url_prefix: String,
+    /// Host of an absolute `url_prefix`; `None` for a same-origin path.
+    allowed_host: Option<String>,
+    /// Every `IMAGE_URL_PREFIX` origin when several are configured; redirects
+    /// take turns across them instead of always using `url_prefix`.
+    prefix_pool: PrefixPool,
+    signing_key: Option<Vec<u8>>,
+    admin_token: Option<String>,
+    /// Hosts a signed `?prefix=` override may point at.
+    prefix_hosts: Vec<String>,
+    image_map: RwLock<ImageMap>,
+    /// When `image_map` was last loaded or replaced, whole seconds.
+    last_modified: RwLock<DateTime<Utc>>,
+    /// Fixed decay from `RECENCY_DECAY`; `None` scales with the candidate count.
+    recency_decay: Option<f64>,
+    /// Per-key probability cap from `MAX_WEIGHT`.
+    max_weight: Option<f64>,
+    /// Smallest `/latest/after` candidate slice, from `MIN_BIASED_POOL`.
+    min_biased_pool: Option<usize>,
+    /// Default `?skip=` for the `latest` endpoints, from `LATEST_SKIP_NEWEST`.
+    latest_skip_newest: usize,
+    /// Default `?weighting=` for the `latest` endpoints, from `BIAS_WEIGHTING`.
+    bias_weighting: Weighting,
+    /// Placeholder served instead of `404` when a selection comes up empty.
+    fallback_url: Option<String>,
+    /// Pattern public bounds must match, from `ALLOWED_BOUND_PATTERN`.
+    allowed_bound: Option<Regex>,
+    /// From `REJECT_EMPTY_BOUND`.
+    reject_empty_bound: bool,
+    default_response: ResponseFormat,
+    retry_after: RetryAfterFormat,
+    /// Fraction by which emitted `max-age` values are randomly spread.
+    cache_jitter: f64,
+    strict_map: bool,
+    drop_future_keys: bool,
+    /// From `KEY_PREFIX_FILTER`, applied to every reload.
+    key_prefix_filter: Option<String>,
+    sessions: SessionStore,
+    /// Keys recently served by `/image/discover`, across all clients.
+    recently_served: RecentlyServed,
+    /// Serve counts behind `/image/fair`, saved to `SERVED_COUNTS_PATH`.
+    served_counts: ServedCounts,
+    /// Set by `/admin/maintenance`; map-backed endpoints answer `503` while on.
+    maintenance: AtomicBool,
+    /// Notified by `/admin/shutdown`; `None` unless `--serve-once`.
+    shutdown: Option<Notify>,
+    rng: RngFactory,
+    clock: Clock,
+    metrics: Arc<Metrics>,
+    /// Upstream circuits for image fetches and reload validation, from
+    /// `BREAKER_THRESHOLD` and `BREAKER_COOLDOWN`.
+    breakers: Arc<Breakers>,
+    deprecations: Arc<Deprecations>,
+    /// From `VALIDATE_ON_RELOAD`.
+    reload_check: Option<ReloadCheck>,
+    /// From `TRUSTED_PROXIES`.
+    trusted_proxies: Option<usize>,
+    /// From `DOW_BOOST`.
+    dow_boost: f64,
+    /// From `UNIFORM_EXCLUDE_NEWEST`.
+    uniform_exclude_newest: Option<ExcludeNewest>,
+    /// From `REDIRECT_CACHE_BUSTER`.
+    redirect_cache_buster: bool,
+    /// From `JSON_KEY_FIELD` and `JSON_URL_FIELD`.
+    field_names: FieldNames,
+}
+
+impl AppState {
+    async fn load<S: MapSource>(config: &Config, source: &S) -> Self {
+        let content = source.fetch().await.expect("failed to read image map");
+        let options = parse_options(
+            config.strict_map,
+            config.drop_future_keys,
+            config.key_prefix_filter.as_deref(),
+        );
+        let image_map = ImageMap::parse_with(&content, &options)
+            .unwrap_or_else(|e| panic!("invalid image map: {e}"));
+        let future = image_map.count_future(Utc::now());
+        if future > 0 {
+            warn!(count = future, "image map contains future-dated keys");
+        }
+        Self::new(config, image_map)
+    }
+
+    /// Serves `map` as given by the caller, with every other setting at its
+    /// default. Panics if `url_prefix` is not a valid `IMAGE_URL_PREFIX`.
+    #[cfg(test)]
+    fn from_map(map: HashMap<String, String>, url_prefix: String) -> Self {
+        let config =
+            Config::from_lookup(|name| (name == "IMAGE_URL_PREFIX").then(|| url_prefix.clone()))
+                .unwrap();
+        Self::new(&config, ImageMap::from_files(map))
+    }
+
+    fn new(config: &Config, image_map: ImageMap) -> Self {
+        Self {
+            base_path: config.base_path.clone(),
+            url_prefix: config.url_prefix.clone(),
+            allowed_host: prefix_host(&config.url_prefix),
+            prefix_pool: config.prefix_pool(),
+            signing_key: config.signing_key.clone().map(String::into_bytes),
+            admin_token: config.admin_token.clone(),
+            prefix_hosts: config.prefix_hosts.clone(),
+            image_map: RwLock::new(image_map),
+            last_modified: RwLock::new(now_secs()),
+            recency_decay: config.recency_decay,
+            max_weight: config.max_weight,
+            min_biased_pool: config.min_biased_pool,
+            latest_skip_newest: config.latest_skip_newest,
+            bias_weighting: config.bias_weighting,
+            fallback_url: config.fallback_url.clone(),
+            allowed_bound: config
+                .allowed_bound_pattern
+                .as_deref()
+                .map(|pattern| Regex::new(pattern).unwrap()),
+            reject_empty_bound: config.reject_empty_bound,
+            default_response: config.default_response,
+            retry_after: config.retry_after,
+            cache_jitter: config.cache_jitter,
+            strict_map: config.strict_map,
+            drop_future_keys: config.drop_future_keys,
+            key_prefix_filter: config.key_prefix_filter.clone(),
+            sessions: SessionStore::new(
+                Duration::from_secs(config.session_ttl_secs),
+                config.max_sessions,
+            ),
+            recently_served: RecentlyServed::new(Duration::from_secs(config.discover_ttl_secs)),
+            served_counts: match &config.served_counts_path {
+                Some(path) => ServedCounts::load(path).expect("failed to load SERVED_COUNTS_PATH"),
+                None => ServedCounts::default(),
+            },
+            maintenance: AtomicBool::new(false),
+            shutdown: None,
+            rng: thread_rng_factory(),
+            clock: system_clock(),
+            metrics: Arc::default(),
+            breakers: Arc::new(Breakers::new(
+                config.breaker_threshold,
+                Duration::from_secs(config.breaker_cooldown_secs),
+            )),
+            deprecations: Arc::new(
+                config
+                    .deprecated_routes
+                    .as_deref()
+                    .and_then(|spec| Deprecations::parse(spec, &config.base_path))
+                    .unwrap_or_default(),
+            ),
+            reload_check: config.validate_sample.map(|sample| ReloadCheck {
+                sample,
+                max_failure_rate: config.validate_max_failures,
+            }),
+            trusted_proxies: config.trusted_proxies,
+            dow_boost: config.dow_boost,
+            uniform_exclude_newest: config.uniform_exclude_newest,
+            redirect_cache_buster: config.redirect_cache_buster,
+            field_names: config.field_names.clone(),
+        }
+    }
+
+    /// `304` if `If-Modified-Since` is at or after the map's load time,
+    /// otherwise `response` with `Last-Modified` set.
+    fn conditional(&self, headers: &HeaderMap, response: impl IntoResponse) -> Response {
+        let last_modified = *self.last_modified.read().unwrap();
+        let since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+        let mut response = if since.is_some_and(|since| since >= last_modified) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            response.into_response()
+        };
+        response.headers_mut().insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&http_date(last_modified)).unwrap(),
+        );
+        response
+    }
+
+    /// A generator seeded with `seed` when given, else one from the factory.
+    fn rng(&self, seed: Option<u64>) -> Box<dyn RngCore> {
+        match seed {
+            Some(seed) => Box::new(seeded_rng(seed)),
+            None => (self.rng)(),
+        }
+    }
+
+    fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// `503` for when [`current_map`](Self::current_map) is unavailable.
+    fn reloading(&self) -> Response {
+        let delay = if self.in_maintenance() {
+            MAINTENANCE_RETRY_DELAY
+        } else {
+            RELOAD_RETRY_DELAY
+        };
+        self.unavailable(delay)
+    }
+
+    /// `503` asking the client to retry after `delay`.
+    fn unavailable(&self, delay: Duration) -> Response {
+        let retry_after = self.retry_after.value(delay, Utc::now());
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after)],
+        )
+            .into_response()
+    }
+
+    /// The map, unless a reload holds it or maintenance mode is on.
+    fn current_map(&self) -> Option<RwLockReadGuard<'_, ImageMap>> {
+        if self.in_maintenance() {
+            return None;
+        }
+        self.image_map.try_read().ok()
+    }
+
+    /// Keys at or after `bound`, widened back to `MIN_BIASED_POOL` when set.
+    fn biased_pool<'a>(&self, image_map: &'a ImageMap, bound: &str) -> &'a [String] {
+        let keys = image_map.keys_after(bound);
+        match self.min_biased_pool {
+            Some(min) => widen_suffix(&image_map.sorted_keys, keys, min),
+            None => keys,
+        }
+    }
+
+    /// `keys` without the newest `skip`, defaulting to `LATEST_SKIP_NEWEST`.
+    fn skip_newest<'a>(&self, keys: &'a [String], skip: Option<usize>) -> &'a [String] {
+        skip_newest(keys, skip.unwrap_or(self.latest_skip_newest))
+    }
+
+    /// The decay for `len` candidates: the query override, then `RECENCY_DECAY`,
+    /// then [`scaled_decay`].
+    fn decay(&self, len: usize, query: Option<f64>) -> f64 {
+        query
+            .or(self.recency_decay)
+            .unwrap_or_else(|| scaled_decay(len))
+    }
+
+    fn format(&self, headers: &HeaderMap) -> ResponseFormat {
+        let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+        negotiate(accept, self.default_response)
+    }
+
+    /// `404` when no `ADMIN_TOKEN` is configured, `401` unless the request
+    /// carries it as a bearer token.
+    fn check_admin(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let Some(token) = &self.admin_token else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        let presented = bearer_token(headers).unwrap_or("");
+        if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+
+    /// [`check_admin`](Self::check_admin) for an audited `action`. Refusals
+    /// are recorded here; on success the handler records the outcome with the
+    /// returned [`Actor`].
+    fn audit_admin(
+        &self,
+        action: &str,
+        headers: &HeaderMap,
+        client: Option<Extension<ClientInfo>>,
+    ) -> Result<Actor, StatusCode> {
+        let actor = Actor::new(
+            bearer_token(headers),
+            client.and_then(|Extension(client)| client.ip),
+        );
+        if let Err(status) = self.check_admin(headers) {
+            let outcome = match status {
+                StatusCode::NOT_FOUND => "disabled",
+                _ => "unauthorized",
+            };
+            actor.record(action, outcome, None);
+            return Err(status);
+        }
+        Ok(actor)
+    }
+
+    fn verify_prefix(&self, prefix: &str, sig: &str) -> bool {
+        let (Some(key), Some(sig)) = (&self.signing_key, decode_hex(sig)) else {
+            return false;
+        };
+        let signed = Hmac::<Sha256>::new_from_slice(key)
+            .expect("HMAC accepts any key length")
+            .chain_update(prefix)
+            .verify_slice(&sig)
+            .is_ok();
+        signed && prefix_host(prefix).is_some_and(|host| self.prefix_hosts.contains(&host))
+    }
+
+    /// The URL for `key` under `prefix`, or `IMAGE_URL_PREFIX` when `None`.
+    fn resolve(
+        &self,
+        key: &str,
+        map: &HashMap<String, String>,
+        prefix: Option<&str>,
+    ) -> Result<String, StatusCode> {
+        let file = map.get(key).ok_or(StatusCode::NOT_FOUND)?;
+        let (url, allowed) = match (prefix, &self.allowed_host) {
+            (Some(prefix), _) => {
+                let url = format!("{}/{}", prefix, file);
+                let allowed = on_host(&url, &prefix_host(prefix).unwrap_or_default());
+                (url, allowed)
+            }
+            (None, Some(host)) => {
+                let (base, host) = self
+                    .prefix_pool
+                    .pick(|| self.rng(None))
+                    .unwrap_or((&self.url_prefix, host));
+                let url = format!("{}/{}", base, file);
+                let allowed = on_host(&url, host);
+                (url, allowed)
+            }
+            (None, None) => {
+                let url = format!("{}/{}", self.url_prefix.trim_end_matches('/'), file);
+                let allowed = same_origin(&url);
+                (url, allowed)
+            }
+        };
+        if !allowed {
+            warn!(%key, %url, "refusing redirect to unexpected host");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Ok(url)
+    }
+
+    /// The newest timestamp a `?min_age=` lets through; `400` if unparseable.
+    fn min_age_cutoff(&self, min_age: Option<&str>) -> Result<Option<DateTime<Utc>>, StatusCode> {
+        let Some(min_age) = min_age else {
+            return Ok(None);
+        };
+        let secs = parse_duration(min_age).ok_or(StatusCode::BAD_REQUEST)?;
+        let age = age_delta(secs).ok_or(StatusCode::BAD_REQUEST)?;
+        (self.clock)()
+            .checked_sub_signed(age)
+            .map(Some)
+            .ok_or(StatusCode::BAD_REQUEST)
+    }
+
+    /// Narrows the uniform pool, `range` of `len` sorted keys plus a
+    /// `?min_age=` cutoff, by `UNIFORM_EXCLUDE_NEWEST`.
+    fn exclude_newest(
+        &self,
+        len: usize,
+        range: Range<usize>,
+        cutoff: Option<DateTime<Utc>>,
+    ) -> (Range<usize>, Option<DateTime<Utc>>) {
+        match self.uniform_exclude_newest {
+            None => (range, cutoff),
+            Some(ExcludeNewest::Count(n)) => {
+                let end = range.end.min(len.saturating_sub(n));
+                (range.start.min(end)..end, cutoff)
+            }
+            Some(ExcludeNewest::AgeSecs(secs)) => {
+                let newest = age_delta(secs)
+                    .and_then(|age| (self.clock)().checked_sub_signed(age))
+                    .unwrap_or(DateTime::<Utc>::MIN_UTC);
+                (range, Some(cutoff.map_or(newest, |c| c.min(newest))))
+            }
+        }
+    }
+
+    /// A [`Selection`] for each of `keys`; the first resolution error fails
+    /// them all.
+    fn selections<'a>(
+        &'a self,
+        keys: Vec<&'a str>,
+        files: &HashMap<String, String>,
+        prefix: Option<&str>,
+    ) -> Result<Vec<Selection<'a>>, StatusCode> {
+        keys.into_iter()
+            .map(|key| {
+                Ok(Selection {
+                    key,
+                    url: self.resolve(key, files, prefix)?,
+                    next: None,
+                    names: &self.field_names,
+                })
+            })
+            .collect()
+    }
+
+    /// A biased pick among `indices`, ascending positions in `sorted_keys`.
+    #[allow(clippy::too_many_arguments)]
+    fn select_indexed<'a>(
+        &self,
+        sorted_keys: &'a [String],
+        indices: &[usize],
+        decay: Option<f64>,
+        recency: f64,
+        max_weight: Option<f64>,
+        weighting: Weighting,
+        seed: Option<u64>,
+    ) -> Option<&'a str> {
+        let mut rng = self.rng(seed);
+        match weighting {
+            Weighting::Slice => {
+                let decay = self.decay(indices.len(), decay);
+                select_biased_among(sorted_keys, indices, decay, recency, max_weight, &mut rng)
+            }
+            Weighting::Global => {
+                let decay = self.decay(sorted_keys.len(), decay);
+                select_biased_at(sorted_keys, indices, decay, recency, max_weight, &mut rng)
+            }
+        }
+    }
+
+    /// The biased draw shared by the `/image/latest` routes, from `keys`, a
+    /// contiguous slice of a `total`-key map, over the keys old enough for
+    /// `cutoff` when one is given. With [`Weighting::Global`] the decay scales
+    /// with `total`, and keys past `cutoff` leave gaps rather than shifting
+    /// older keys' weights up.
+    #[allow(clippy::too_many_arguments)]
+    fn select_latest<'a>(
+        &self,
+        keys: &'a [String],
+        total: usize,
+        cutoff: Option<DateTime<Utc>>,
+        decay: Option<f64>,
+        recency: f64,
+        max_weight: Option<f64>,
+        weighting: Weighting,
+        seed: Option<u64>,
+    ) -> Option<&'a str> {
+        let mut rng = self.rng(seed);
+        match (cutoff, weighting) {
+            (Some(cutoff), Weighting::Slice) => {
+                let indices = aged_indices(keys, cutoff);
+                let decay = self.decay(indices.len(), decay);
+                select_biased_among(keys, &indices, decay, recency, max_weight, &mut rng)
+            }
+            (Some(cutoff), Weighting::Global) => {
+                let indices = aged_indices(keys, cutoff);
+                let decay = self.decay(total, decay);
+                select_biased_at(keys, &indices, decay, recency, max_weight, &mut rng)
+            }
+            (None, weighting) => {
+                // Exponential weights over a contiguous slice don't depend on
+                // its offset, so only the decay tells the two apart.
+                let len = match weighting {
+                    Weighting::Slice => keys.len(),
+                    Weighting::Global => total,
+                };
+                let decay = self.decay(len, decay);
+                select_biased_with(keys, decay, recency, max_weight, &mut rng)
+            }
+        }
+    }
+
+    /// [`check_bound`], then `400` if `ALLOWED_BOUND_PATTERN` is set and the
+    /// bound doesn't match it.
+    fn check_bound(&self, bound: Option<&str>) -> Result<(), StatusCode> {
+        check_bound(bound)?;
+        match (bound, &self.allowed_bound) {
+            (Some(bound), Some(pattern)) if !pattern.is_match(bound) => {
+                Err(StatusCode::BAD_REQUEST)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The response for a selection that matched no key: `404`, or the
+    /// `FALLBACK_URL` placeholder when one is configured.
+    fn empty_selection(&self, format: ResponseFormat) -> Response {
+        let Some(url) = &self.fallback_url else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let mut response = match format {
+            ResponseFormat::Json => Json(Fallback {
+                url,
+                fallback: true,
+                names: &self.field_names,
+            })
+            .into_response(),
+            _ => (StatusCode::FOUND, [(header::LOCATION, url.as_str())]).into_response(),
+        };
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Accept"));
+        response
+    }
+
+    /// The `/s/{code}` code for `sorted_keys[index]`: the index in base62,
+    /// then with a `SIGNING_KEY` a tag binding it to this map.
+    fn short_code(&self, index: usize, content_hash: u64) -> String {
+        let mut code = encode_base62(index as u64);
+        if let Some(tag) = self.short_tag(index, content_hash) {
+            code.push_str(&tag);
+        }
+        code
+    }
+
+    /// The index a short `code` names, if it parses and any tag matches the
+    /// map hashed to `content_hash`. Range is left to the caller.
+    fn decode_short(&self, code: &str, content_hash: u64) -> Option<usize> {
+        let (index, tag) = match self.signing_key {
+            Some(_) => code.split_at_checked(code.len().checked_sub(SHORT_TAG_LEN)?)?,
+            None => (code, ""),
+        };
+        let index = usize::try_from(decode_base62(index)?).ok()?;
+        let expected = self.short_tag(index, content_hash).unwrap_or_default();
+        constant_time_eq(tag.as_bytes(), expected.as_bytes()).then_some(index)
+    }
+
+    /// `SHORT_TAG_LEN` base62 digits of an HMAC over `index` and the map's
+    /// hash, so codes can't be forged and go stale when the map changes.
+    fn short_tag(&self, index: usize, content_hash: u64) -> Option<String> {
+        let key = self.signing_key.as_ref()?;
+        let mac = Hmac::<Sha256>::new_from_slice(key)
+            .expect("HMAC accepts any key length")
+            .chain_update(format!("{index}:{content_hash:016x}"))
+            .finalize()
+            .into_bytes();
+        let tag = u32::from_be_bytes(mac[..4].try_into().unwrap());
+        Some(format!("{:0>SHORT_TAG_LEN$}", encode_base62(tag.into())))
+    }
+
+    /// The `/image/key/{key}` path that always serves `key`.
+    fn permalink(&self, key: &str) -> String {
+        let key = utf8_percent_encode(key, PATH_SEGMENT);
+        format!("{}/image/key/{key}", self.base_path)
+    }
+
+    /// A selection of `key`, from `map` and with `dimensions` taken from the
+    /// same map guard the handler selected it from.
+    fn redirect(
+        &self,
+        key: &str,
+        map: &HashMap<String, String>,
+        dimensions: &HashMap<String, Dimensions>,
+        prefix: Option<&str>,
+        cache_secs: Option<u64>,
+        format: ResponseFormat,
+    ) -> Response {
+        self.redirect_with_next(key, None, map, dimensions, prefix, cache_secs, format)
+    }
+
+    /// `next` paired with its resolved URL.
+    fn resolve_next<'k>(
+        &self,
+        next: Option<&'k str>,
+        map: &HashMap<String, String>,
+        prefix: Option<&str>,
+    ) -> Result<Option<(&'k str, String)>, StatusCode> {
+        next.map(|next| Ok((next, self.resolve(next, map, prefix)?)))
+            .transpose()
+    }
+
+    /// [`redirect`](Self::redirect) with a prefetch hint for `next`: a
+    /// `Link: <url>; rel="prefetch"` header, and in JSON a `next` selection.
+    #[allow(clippy::too_many_arguments)]
+    fn redirect_with_next(
+        &self,
+        key: &str,
+        next: Option<&str>,
+        map: &HashMap<String, String>,
+        dimensions: &HashMap<String, Dimensions>,
+        prefix: Option<&str>,
+        cache_secs: Option<u64>,
+        format: ResponseFormat,
+    ) -> Response {
+        let next = match self.resolve_next(next, map, prefix) {
+            Ok(next) => next,
+            Err(status) => return status.into_response(),
+        };
+        let dimensions = dimensions.get(key).copied();
+        match self.resolve(key, map, prefix) {
+            Ok(url) => self.respond(key, url, next, dimensions, cache_secs, format),
+            Err(status) => status.into_response(),
+        }
+    }
+
+    /// [`redirect`](Self::redirect) for plain `/image`, with a cache-busting
+    /// `r` query parameter on the URL when `REDIRECT_CACHE_BUSTER` is set.
+    /// `next` is hinted as in [`redirect_with_next`](Self::redirect_with_next),
+    /// without a cache buster.
+    #[allow(clippy::too_many_arguments)]
+    fn uniform_redirect(
+        &self,
+        key: &str,
+        next: Option<&str>,
+        map: &HashMap<String, String>,
+        dimensions: &HashMap<String, Dimensions>,
+        prefix: Option<&str>,
+        cache_secs: Option<u64>,
+        format: ResponseFormat,
+    ) -> Response {
+        if !self.redirect_cache_buster {
+            return self.redirect_with_next(key, next, map, dimensions, prefix, cache_secs, format);
+        }
+        let next = match self.resolve_next(next, map, prefix) {
+            Ok(next) => next,
+            Err(status) => return status.into_response(),
+        };
+        match self.resolve(key, map, prefix) {
+            Ok(url) => {
+                let url = with_cache_buster(&url, self.rng(None).next_u64());
+                let dimensions = dimensions.get(key).copied();
+                self.respond(key, url, next, dimensions, cache_secs, format)
+            }
+            Err(status) => status.into_response(),
+        }
+    }
+
+    fn respond(
+        &self,
+        key: &str,
+        url: String,
+        next: Option<(&str, String)>,
+        dimensions: Option<Dimensions>,
+        cache_secs: Option<u64>,
+        format: ResponseFormat,
+    ) -> Response {
+        let link = next
+            .as_ref()
+            .and_then(|(_, url)| HeaderValue::from_str(&format!("<{url}>; rel=\"prefetch\"")).ok());
+        let mut response = match format {
+            ResponseFormat::Redirect => {
+                (StatusCode::FOUND, [(header::LOCATION, url)]).into_response()
+            }
+            ResponseFormat::Json => {
+                let permalink = self.permalink(key);
+                let next = next.map(|(key, url)| {
+                    Box::new(Selection {
+                        key,
+                        url,
+                        next: None,
+                        names: &self.field_names,
+                    })
+                });
+                let mut response = Json(Selection {
+                    key,
+                    url,
+                    next,
+                    names: &self.field_names,
+                })
+                .into_response();
+                if let Ok(value) = HeaderValue::from_str(&permalink) {
+                    response
+                        .headers_mut()
+                        .insert(header::CONTENT_LOCATION, value);
+                }
+                response
+            }
+            ResponseFormat::Html => Html(view_page(key, &url)).into_response(),
+        };
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Accept"));
+        if let Some(link) = link {
+            response.headers_mut().insert(header::LINK, link);
+        }
+        response.extensions_mut().insert(SelectedKey(dimensions));
+        if let Some(secs) = cache_secs {
+            let secs = jittered_ttl(secs, self.cache_jitter, &mut self.rng(None));
+            response.headers_mut().insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_str(&format!("public, max-age={}", secs)).unwrap(),
+            );
+        }
+        response
+    }
+}
+
+/// Marks a selection response with the selected key's recorded dimensions,
+/// looked up from the map it was selected from, for [`dimension_headers`].
+#[derive(Clone)]
+struct SelectedKey(Option<Dimensions>);
+
+/// Adds `X-Image-Width` and `X-Image-Height` to selections whose key has
+/// recorded dimensions.
+async fn dimension_headers(req: Request, next: middleware::Next) -> Response {
+    let mut response = next.run(req).await;
+    let Some(SelectedKey(dimensions)) = response.extensions_mut().remove::<SelectedKey>() else {
+        return response;
+    };
+    if let Some(dimensions) = dimensions {
+        let headers = response.headers_mut();
+        headers.insert("x-image-width", HeaderValue::from(dimensions.width));
+        headers.insert("x-image-height", HeaderValue::from(dimensions.height));
+    }
+    response
+}
+
+/// `url` with `r={token}` appended to its query, ahead of any fragment.
+fn with_cache_buster(url: &str, token: u64) -> String {
+    let (base, fragment) = match url.find('#') {
+        Some(i) => url.split_at(i),
+        None => (url, ""),
+    };
+    let separator = if !base.contains('?') {
+        "?"
+    } else if base.ends_with(['?', '&']) {
+        ""
+    } else {
+        "&"
+    };
+    format!("{base}{separator}r={token:x}{fragment}")
+}
+
+/// Bytes escaped in a path segment: all but RFC 3986 unreserved characters.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How long clients are told to wait while a reload holds the map.
+const RELOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long clients are told to wait during maintenance.
+const MAINTENANCE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// `Retry-After` form: delta-seconds or an HTTP-date.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RetryAfterFormat {
+    Seconds,
+    Date,
+}
+
+impl RetryAfterFormat {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "seconds" => Some(Self::Seconds),
+            "date" => Some(Self::Date),
+            _ => None,
+        }
+    }
+
+    /// The header value for a retry `delay` from `now`, rounded up to whole seconds.
+    fn value(self, delay: Duration, now: DateTime<Utc>) -> String {
+        let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+        match self {
+            Self::Seconds => secs.to_string(),
+            Self::Date => http_date(now + chrono::Duration::seconds(secs as i64)),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/image",
+    description = "Random image, uniform unless `?mode=` picks another strategy. \
+                   Also served at `/random`.",
+    params(
+        FormatQuery,
+        PrefixQuery,
+        VariantQuery,
+        ModeQuery,
+        RandomQuery,
+        LatestQuery,
+        ThemedQuery,
+        RangeQuery,
+    ),
+    responses(
+        SelectionResponses,
+        (status = 204, description = "`probe=1`: the key in `X-Image-Key`"),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+async fn random_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(m): Query<ModeQuery>,
+    Query(random): Query<RandomQuery>,
+    Query(latest): Query<LatestQuery>,
+    Query(themed): Query<ThemedQuery>,
+    Query(range): Query<RangeQuery>,
+) -> Response {
+    let mode = match m.mode.as_deref().map(SelectionMode::parse) {
+        Some(Some(mode)) => mode,
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => SelectionMode::Uniform,
+    };
+    if let Err(status) = state.check_bound(m.after.as_deref()) {
+        return status.into_response();
+    }
+    let (state, format, prefix, variant) = (
+        State(state),
+        Format(format),
+        Prefix(prefix),
+        Variant(variant),
+    );
+    match (mode, m.after) {
+        (SelectionMode::Uniform, after) => {
+            let range = match range.or_after(after) {
+                Ok(range) => range,
+                Err(status) => return status.into_response(),
+            };
+            uniform_image(state, format, prefix, variant, Query(random), Query(range)).await
+        }
+        (SelectionMode::Daily, after) if random.seed.is_none() => {
+            let range = match range.or_after(after) {
+                Ok(range) => range,
+                Err(status) => return status.into_response(),
+            };
+            let random = RandomQuery {
+                seed: Some(day_seed((state.clock)())),
+                ..random
+            };
+            uniform_image(state, format, prefix, variant, Query(random), Query(range)).await
+        }
+        (SelectionMode::Biased, None) => {
+            latest_image(state, format, prefix, variant, Query(latest), Query(range)).await
+        }
+        (SelectionMode::Biased, Some(after)) => {
+            latest_image_after(
+                state,
+                format,
+                prefix,
+                variant,
+                Bound(after),
+                Query(latest),
+                Query(range),
+            )
+            .await
+        }
+        (SelectionMode::Weighted, None) if range.is_empty() => {
+            themed_image(state, format, prefix, variant, Query(themed)).await
+        }
+        (SelectionMode::Fair, None) if range.is_empty() => {
+            let cache = Query(CacheQuery {
+                cache: random.cache,
+            });
+            fair_image(state, format, prefix, variant, cache).await
+        }
+        (SelectionMode::Weighted | SelectionMode::Fair | SelectionMode::Daily, _) => {
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+/// `/image` in the default `uniform` mode.
+async fn uniform_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<RandomQuery>,
+    Query(range): Query<RangeQuery>,
+) -> Response {
+    if let Err(status) = range.check(&state, false) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let cutoff = match state.min_age_cutoff(q.min_age.as_deref()) {
+        Ok(cutoff) => cutoff,
+        Err(status) => return status.into_response(),
+    };
+    let media_type = match q.media_type.as_deref().map(MediaType::parse) {
+        Some(Some(media_type)) => Some(media_type),
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let bounded = !range.is_empty();
+    let range = guard.key_range(range.since.as_deref(), range.until.as_deref());
+    let (range, cutoff) = state.exclude_newest(guard.len(), range, cutoff);
+    let window = bounded.then(|| &guard.sorted_keys[range.clone()]);
+    let select = |seed| {
+        let mut rng = state.rng(seed);
+        let range = range.clone();
+        match (media_type, cutoff) {
+            (Some(media_type), None) => select_typed_in(&guard, media_type, range, &mut rng),
+            (None, None) => select_uniform_with(&guard.sorted_keys[range], &mut rng),
+            (media_type, Some(cutoff)) => {
+                let keys = &guard.sorted_keys[range];
+                let mut indices = aged_indices(keys, cutoff);
+                if let Some(media_type) = media_type {
+                    indices.retain(|&i| guard.media_type(&keys[i]) == Some(media_type));
+                }
+                select_uniform_among(keys, &indices, &mut rng)
+            }
+        }
+    };
+    let selected = select(q.seed);
+    let next = prefetching(q.prefetch.as_deref())
+        .then(|| select(next_seed(q.seed)))
+        .flatten();
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) if q.probe.as_deref() == Some("1") => {
+            probe(&state, key, files, prefix.as_deref())
+        }
+        Some(key) => state.uniform_redirect(
+            key,
+            next,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    let response = with_server_timing(response, elapsed);
+    match window {
+        Some(keys) => with_range_headers(response, keys),
+        None => response,
+    }
+}
+
+/// Adds `X-Range-Start` and `X-Range-End`, the first and last of the sorted
+/// candidate `keys`, so clients can check which window a bound resolved to.
+/// Skipped when `keys` is empty or a key isn't a valid header value.
+fn with_range_headers(mut response: Response, keys: &[String]) -> Response {
+    let (Some(first), Some(last)) = (keys.first(), keys.last()) else {
+        return response;
+    };
+    if let (Ok(first), Ok(last)) = (HeaderValue::from_str(first), HeaderValue::from_str(last)) {
+        let headers = response.headers_mut();
+        headers.insert("x-range-start", first);
+        headers.insert("x-range-end", last);
+    }
+    response
+}
+
+/// Adds `Server-Timing: select;dur=<ms>` for time spent filtering and selecting.
+fn with_server_timing(mut response: Response, elapsed: Duration) -> Response {
+    let value = format!("select;dur={:.3}", elapsed.as_secs_f64() * 1000.0);
+    response
+        .headers_mut()
+        .insert("server-timing", HeaderValue::from_str(&value).unwrap());
+    response
+}
+
+/// Answers an uptime probe with `204` and the selected key, after checking that
+/// it resolves to a valid redirect.
+fn probe(
+    state: &AppState,
+    key: &str,
+    map: &HashMap<String, String>,
+    prefix: Option<&str>,
+) -> Response {
+    if let Err(status) = state.resolve(key, map, prefix) {
+        return status.into_response();
+    }
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    if let Ok(value) = HeaderValue::from_str(key) {
+        response.headers_mut().insert("x-image-key", value);
+    }
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/after/{bound}",
+    description = "Uniform random image with key `>= bound`. \
+                   Also served at `/random/after/{bound}`.",
+    params(
+        ("bound" = String, Path, description = "Key prefix, e.g. `2024-06`"),
+        FormatQuery,
+        PrefixQuery,
+        VariantQuery,
+        CacheQuery,
+    ),
+    responses(SelectionResponses)
+)]
+async fn random_image_after(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Bound(bound): Bound,
+    Query(q): Query<CacheQuery>,
+    Query(range): Query<RangeQuery>,
+) -> Response {
+    if let Err(status) = range.check(&state, true) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let keys = guard.keys_after(&bound);
+    let selected = select_uniform_with(keys, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_range_headers(with_server_timing(response, elapsed), keys)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/latest",
+    description = "Recency-biased random image, unless the map pins a key for now; with `count`, a JSON list of distinct ones. Also served at `/random/latest`.",
+    params(FormatQuery, PrefixQuery, VariantQuery, LatestQuery, RangeQuery),
+    responses(SelectionResponses)
+)]
+async fn latest_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<LatestQuery>,
+    Query(range): Query<RangeQuery>,
+) -> Response {
+    if let Err(status) = range.check(&state, false) {
+        return status.into_response();
+    }
+    if q.count == Some(0) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let recency = match parse_recency(q.recency) {
+        Ok(r) => r,
+        Err(status) => return status.into_response(),
+    };
+    let decay = match decay_override(q.decay, q.halflife) {
+        Ok(d) => d,
+        Err(status) => return status.into_response(),
+    };
+    let max_weight = match parse_max_weight(q.max_weight) {
+        Ok(w) => w.or(state.max_weight),
+        Err(status) => return status.into_response(),
+    };
+    let weighting = match parse_weighting(q.weighting.as_deref(), state.bias_weighting) {
+        Ok(w) => w,
+        Err(status) => return status.into_response(),
+    };
+    let cutoff = match state.min_age_cutoff(q.min_age.as_deref()) {
+        Ok(cutoff) => cutoff,
+        Err(status) => return status.into_response(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let keys = match (range.since.as_deref(), range.until.as_deref()) {
+        (Some(since), None) => state.biased_pool(&guard, since),
+        (since, until) => &guard.sorted_keys[guard.key_range(since, until)],
+    };
+    if let Some(count) = q.count {
+        let keys = state.skip_newest(keys, q.skip);
+        let indices = match cutoff {
+            Some(cutoff) => aged_indices(keys, cutoff),
+            None => (0..keys.len()).collect(),
+        };
+        let mut rng = state.rng(q.seed);
+        let batch = match weighting {
+            Weighting::Slice => {
+                let decay = state.decay(indices.len(), decay);
+                select_biased_sample_among(
+                    keys, &indices, count, decay, recency, max_weight, &mut rng,
+                )
+            }
+            Weighting::Global => {
+                let decay = state.decay(guard.len(), decay);
+                select_biased_sample_at(keys, &indices, count, decay, recency, max_weight, &mut rng)
+            }
+        };
+        let elapsed = started.elapsed();
+        let response = match state.selections(batch, files, prefix.as_deref()) {
+            Ok(selections) => Json(selections).into_response(),
+            Err(status) => status.into_response(),
+        };
+        return with_server_timing(response, elapsed);
+    }
+    let pinned = guard.pinned((state.clock)()).filter(|&key| {
+        files.contains_key(key) && keys.binary_search_by(|k| k.as_str().cmp(key)).is_ok()
+    });
+    let select = |seed| {
+        pinned.or_else(|| {
+            let keys = state.skip_newest(keys, q.skip);
+            state.select_latest(
+                keys,
+                guard.len(),
+                cutoff,
+                decay,
+                recency,
+                max_weight,
+                weighting,
+                seed,
+            )
+        })
+    };
+    let selected = select(q.seed);
+    let next = prefetching(q.prefetch.as_deref())
+        .then(|| select(next_seed(q.seed)))
+        .flatten();
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect_with_next(
+            key,
+            next,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    let response = with_server_timing(response, elapsed);
+    if range.is_empty() {
+        return response;
+    }
+    with_range_headers(response, state.skip_newest(keys, q.skip))
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/latest/after/{bound}",
+    description = "Recency-biased random image with key `>= bound`. \
+                   Also served at `/random/latest/after/{bound}`.",
+    params(
+        ("bound" = String, Path, description = "Key prefix, e.g. `2024-06`"),
+        FormatQuery,
+        PrefixQuery,
+        VariantQuery,
+        LatestQuery,
+    ),
+    responses(SelectionResponses)
+)]
+async fn latest_image_after(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Bound(bound): Bound,
+    Query(q): Query<LatestQuery>,
+    Query(range): Query<RangeQuery>,
+) -> Response {
+    if let Err(status) = range.check(&state, true) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let recency = match parse_recency(q.recency) {
+        Ok(r) => r,
+        Err(status) => return status.into_response(),
+    };
+    let decay = match decay_override(q.decay, q.halflife) {
+        Ok(d) => d,
+        Err(status) => return status.into_response(),
+    };
+    let max_weight = match parse_max_weight(q.max_weight) {
+        Ok(w) => w.or(state.max_weight),
+        Err(status) => return status.into_response(),
+    };
+    let weighting = match parse_weighting(q.weighting.as_deref(), state.bias_weighting) {
+        Ok(w) => w,
+        Err(status) => return status.into_response(),
+    };
+    let cutoff = match state.min_age_cutoff(q.min_age.as_deref()) {
+        Ok(cutoff) => cutoff,
+        Err(status) => return status.into_response(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let keys = state.skip_newest(state.biased_pool(&guard, &bound), q.skip);
+    let selected = state.select_latest(
+        keys,
+        guard.len(),
+        cutoff,
+        decay,
+        recency,
+        max_weight,
+        weighting,
+        q.seed,
+    );
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_range_headers(with_server_timing(response, elapsed), keys)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/tag/{tag}/after/{bound}",
+    description = "Uniform random image tagged `tag` with key `>= bound`.",
+    params(
+        ("tag" = String, Path),
+        ("bound" = String, Path, description = "Key prefix, e.g. `2024-06`"),
+        FormatQuery,
+        PrefixQuery,
+        VariantQuery,
+        CacheQuery,
+    ),
+    responses(SelectionResponses)
+)]
+async fn tag_image_after(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path((tag, bound)): Path<(String, String)>,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    if let Err(status) = state.check_bound(Some(&bound)) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let indices = tagged_after(&guard, &tag, &bound);
+    let selected = select_uniform_among(&guard.sorted_keys, indices, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/latest/tag/{tag}/after/{bound}",
+    description = "Recency-biased random image tagged `tag` with key `>= bound`.",
+    params(
+        ("tag" = String, Path),
+        ("bound" = String, Path, description = "Key prefix, e.g. `2024-06`"),
+        FormatQuery,
+        PrefixQuery,
+        VariantQuery,
+        LatestQuery,
+    ),
+    responses(SelectionResponses)
+)]
+async fn latest_tag_image_after(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path((tag, bound)): Path<(String, String)>,
+    Query(q): Query<LatestQuery>,
+) -> Response {
+    if let Err(status) = state.check_bound(Some(&bound)) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let recency = match parse_recency(q.recency) {
+        Ok(r) => r,
+        Err(status) => return status.into_response(),
+    };
+    let decay = match decay_override(q.decay, q.halflife) {
+        Ok(d) => d,
+        Err(status) => return status.into_response(),
+    };
+    let max_weight = match parse_max_weight(q.max_weight) {
+        Ok(w) => w.or(state.max_weight),
+        Err(status) => return status.into_response(),
+    };
+    let weighting = match parse_weighting(q.weighting.as_deref(), state.bias_weighting) {
+        Ok(w) => w,
+        Err(status) => return status.into_response(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let indices = tagged_after(&guard, &tag, &bound);
+    let selected = state.select_indexed(
+        &guard.sorted_keys,
+        indices,
+        decay,
+        recency,
+        max_weight,
+        weighting,
+        q.seed,
+    );
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/months",
+    description = "Uniform random image from the given months of any year.",
+    params(FormatQuery, PrefixQuery, VariantQuery, MonthsQuery),
+    responses(SelectionResponses)
+)]
+async fn months_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<MonthsQuery>,
+) -> Response {
+    let Some(months) = parse_months(&q.m) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let indices = month_indices(&guard.sorted_keys, &months);
+    let selected = select_uniform_among(&guard.sorted_keys, &indices, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/latest/months",
+    description = "Recency-biased random image from the given months of any year.",
+    params(FormatQuery, PrefixQuery, VariantQuery, MonthsQuery, LatestQuery),
+    responses(SelectionResponses)
+)]
+async fn latest_months_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(months): Query<MonthsQuery>,
+    Query(q): Query<LatestQuery>,
+) -> Response {
+    let Some(months) = parse_months(&months.m) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let recency = match parse_recency(q.recency) {
+        Ok(r) => r,
+        Err(status) => return status.into_response(),
+    };
+    let decay = match decay_override(q.decay, q.halflife) {
+        Ok(d) => d,
+        Err(status) => return status.into_response(),
+    };
+    let max_weight = match parse_max_weight(q.max_weight) {
+        Ok(w) => w.or(state.max_weight),
+        Err(status) => return status.into_response(),
+    };
+    let weighting = match parse_weighting(q.weighting.as_deref(), state.bias_weighting) {
+        Ok(w) => w,
+        Err(status) => return status.into_response(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let indices = month_indices(&guard.sorted_keys, &months);
+    let selected = state.select_indexed(
+        &guard.sorted_keys,
+        &indices,
+        decay,
+        recency,
+        max_weight,
+        weighting,
+        q.seed,
+    );
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/key/{key}",
+    description = "The image with exactly this key; the `Content-Location` of JSON selections.",
+    params(("key" = String, Path), FormatQuery, PrefixQuery, VariantQuery, CacheQuery),
+    responses(SelectionResponses)
+)]
+async fn key_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(key): Path<String>,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match files.get_key_value(&key) {
+        Some((key, _)) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/meta/{key}",
+    description = "The map's metadata for `key`, including its pixel size when recorded.",
+    params(("key" = String, Path)),
+    responses(
+        (status = 200, body = Meta),
+        (status = 404, description = "No image with this key"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn meta(State(state): State<Arc<AppState>>, Path(key): Path<String>) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some((key, file)) = guard.map.get_key_value(&key) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let dimensions = guard.dimensions.get(key);
+    Json(Meta {
+        key,
+        file,
+        media_type: guard.media_type(key).unwrap_or(MediaType::Image),
+        tags: guard.tags_of(key),
+        width: dimensions.map(|d| d.width),
+        height: dimensions.map(|d| d.height),
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/similar/{key}",
+    description = "Random other image, weighted by the number of tags it shares with `key`.",
+    params(("key" = String, Path), FormatQuery, PrefixQuery, VariantQuery, CacheQuery),
+    responses(SelectionResponses)
+)]
+async fn similar_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(key): Path<String>,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !guard.map.contains_key(&key) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let started = Instant::now();
+    let selected = select_similar_with(&guard, &key, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/index/{n}",
+    description = "The image at position `n` in key order; negative counts from the end.",
+    params(("n" = i64, Path), FormatQuery, PrefixQuery, VariantQuery, IndexQuery),
+    responses(SelectionResponses)
+)]
+async fn indexed_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(n): Path<i64>,
+    Query(q): Query<IndexQuery>,
+) -> Response {
+    if let Err(status) = state.check_bound(q.after.as_deref()) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let keys = match q.after.as_deref() {
+        Some(bound) => guard.keys_after(bound),
+        None => &guard.sorted_keys,
+    };
+    let selected = select_index(keys, n);
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+/// Base62 digits of a signed short code's tag; 62^6 covers a `u32`.
+const SHORT_TAG_LEN: usize = 6;
+
+#[utoipa::path(
+    get,
+    path = "/image/short",
+    description = "A short `/s/{code}` link to a random image.",
+    responses(
+        (status = 200, body = ShortLink),
+        (status = 404, description = "Empty map"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn short_link(State(state): State<Arc<AppState>>) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    if guard.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let index = state.rng(None).gen_range(0..guard.len());
+    let code = state.short_code(index, guard.content_hash);
+    let path = format!("{}/s/{code}", state.base_path);
+    Json(ShortLink {
+        code,
+        path,
+        key: &guard.sorted_keys[index],
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/s/{code}",
+    description = "The image a short code from `/image/short` names.",
+    params(("code" = String, Path), FormatQuery, PrefixQuery, VariantQuery, CacheQuery),
+    responses(SelectionResponses)
+)]
+async fn short_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(code): Path<String>,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let key = state
+        .decode_short(&code, guard.content_hash)
+        .and_then(|index| guard.sorted_keys.get(index));
+    match key {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Longest sequence `/image/seq` replays, since each request redraws it.
+const MAX_SEQ_DRAWS: usize = 10_000;
+
+#[utoipa::path(
+    get,
+    path = "/image/seq",
+    description = "The `n`-th uniform draw from a generator seeded with `seed`; \
+                   `n=1` is the image `/image?seed=` returns.",
+    params(FormatQuery, PrefixQuery, VariantQuery, SeqQuery),
+    responses(SelectionResponses)
+)]
+async fn sequence_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<SeqQuery>,
+) -> Response {
+    if !(1..=MAX_SEQ_DRAWS).contains(&q.n) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let selected = select_seeded_nth(&guard.sorted_keys, q.seed, q.n);
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/recent/{n}",
+    description = "The `n`-th most recent image, where `1` is the newest.",
+    params(("n" = usize, Path), FormatQuery, PrefixQuery, VariantQuery, IndexQuery),
+    responses(SelectionResponses)
+)]
+async fn recent_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(n): Path<usize>,
+    Query(q): Query<IndexQuery>,
+) -> Response {
+    if let Err(status) = state.check_bound(q.after.as_deref()) {
+        return status.into_response();
+    }
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let keys = match q.after.as_deref() {
+        Some(bound) => guard.keys_after(bound),
+        None => &guard.sorted_keys,
+    };
+    let selected = select_recent(keys, n);
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/evenly",
+    description = "Images at evenly spaced positions across the timeline.",
+    params(PrefixQuery, VariantQuery, EvenlyQuery),
+    responses(ListResponses)
+)]
+async fn evenly_images(
+    State(state): State<Arc<AppState>>,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    headers: HeaderMap,
+    Query(q): Query<EvenlyQuery>,
+) -> Response {
+    if q.count == 0 {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let keys = select_evenly(&guard.sorted_keys, q.count);
+    match state.selections(keys, files, prefix.as_deref()) {
+        Ok(selections) => state.conditional(&headers, Json(selections)),
+        Err(status) => status.into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/top",
+    description = "The images recency-biased selection favors most, heaviest first.",
+    params(PrefixQuery, VariantQuery, TopQuery),
+    responses(
+        (status = 200, body = Vec<RankedSelection>),
+        (status = 400, description = "Invalid count or weighting parameter"),
+        (status = 403, description = "Unsigned or unlisted `prefix` override"),
+        (status = 404, description = "No such variant"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn top_images(
+    State(state): State<Arc<AppState>>,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    headers: HeaderMap,
+    Query(q): Query<TopQuery>,
+) -> Response {
+    if q.count == 0 {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let (recency, decay, max_weight) = match (
+        parse_recency(q.recency),
+        decay_override(q.decay, q.halflife),
+        parse_max_weight(q.max_weight),
+    ) {
+        (Ok(r), Ok(d), Ok(w)) => (r, d, w.or(state.max_weight)),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let keys = &guard.sorted_keys;
+    let decay = state.decay(keys.len(), decay);
+    let ranked: Result<Vec<RankedSelection>, StatusCode> =
+        select_top_biased(keys, decay, recency, max_weight, q.count)
+            .into_iter()
+            .map(|(key, probability)| {
+                Ok(RankedSelection {
+                    key,
+                    url: state.resolve(key, files, prefix.as_deref())?,
+                    probability,
+                    names: &state.field_names,
+                })
+            })
+            .collect();
+    match ranked {
+        Ok(ranked) => state.conditional(&headers, Json(ranked)),
+        Err(status) => status.into_response(),
+    }
+}
+
+/// `count` keys chosen deterministically for the current ISO week, in
+/// chronological order, so everyone sees the same set until the week turns.
+#[utoipa::path(
+    get,
+    path = "/image/week",
+    description = "A deterministic set of images for the current ISO week.",
+    params(PrefixQuery, VariantQuery, WeekQuery),
+    responses(ListResponses)
+)]
+async fn week_images(
+    State(state): State<Arc<AppState>>,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<WeekQuery>,
+) -> Response {
+    if q.count == 0 {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut rng = seeded_rng(iso_week_seed((state.clock)()));
+    let keys = if q.distinct {
+        select_sample_distinct(&guard.sorted_keys, q.count, &guard.image_hashes, &mut rng)
+    } else {
+        select_sample(&guard.sorted_keys, q.count, &mut rng)
+    };
+    match state.selections(keys, files, prefix.as_deref()) {
+        Ok(selections) => Json(selections).into_response(),
+        Err(status) => status.into_response(),
+    }
+}
+
+fn session_response(
+    state: &AppState,
+    token: &str,
+    prefix: Option<&str>,
+    variant: Option<&str>,
+    cache: Option<u64>,
+    format: ResponseFormat,
+) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let index = match state.sessions.next(token, guard.len(), Instant::now()) {
+        Ok(index) => index,
+        Err(SessionError::Unknown) => return StatusCode::NOT_FOUND.into_response(),
+        Err(SessionError::Exhausted) => return StatusCode::GONE.into_response(),
+    };
+    let mut response = state.redirect(
+        &guard.sorted_keys[index],
+        files,
+        &guard.dimensions,
+        prefix,
+        cache,
+        format,
+    );
+    response
+        .headers_mut()
+        .insert("x-session-token", HeaderValue::from_str(token).unwrap());
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/session",
+    description = "Starts a shuffle session; the token is in `X-Session-Token`.",
+    params(FormatQuery, PrefixQuery, VariantQuery, SessionQuery),
+    responses(SelectionResponses)
+)]
+async fn session_start(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<SessionQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    match state.current_map() {
+        Some(guard) if guard.is_empty() => return StatusCode::NOT_FOUND.into_response(),
+        Some(_) => {}
+        None => return state.reloading(),
+    }
+    let token = state.sessions.create(q.reshuffle, Instant::now());
+    session_response(
+        &state,
+        &token,
+        prefix.as_deref(),
+        variant.as_deref(),
+        cache,
+        format,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/session/{token}/next",
+    description = "The next image in a shuffle session.",
+    params(("token" = String, Path), FormatQuery, PrefixQuery, VariantQuery, CacheQuery),
+    responses(SelectionResponses, (status = 410, description = "Session exhausted"))
+)]
+async fn session_next(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(token): Path<String>,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    session_response(
+        &state,
+        &token,
+        prefix.as_deref(),
+        variant.as_deref(),
+        cache,
+        format,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/themed",
+    description = "Random image weighted by tag boosts. Also served at `/random/themed`.",
+    params(FormatQuery, PrefixQuery, VariantQuery, ThemedQuery),
+    responses(SelectionResponses)
+)]
+async fn themed_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<ThemedQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let boost = match q.boost.as_deref().map(parse_boost) {
+        Some(Some(boost)) => boost,
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => HashMap::new(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let selected = select_boosted_with(&guard, &boost, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/dow",
+    description = "Random image weighted toward keys taken on today's weekday (UTC).",
+    params(FormatQuery, PrefixQuery, VariantQuery, DowQuery),
+    responses(SelectionResponses)
+)]
+async fn dow_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<DowQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let factor = match q.factor {
+        Some(f) if !f.is_finite() || f <= 0.0 => return StatusCode::BAD_REQUEST.into_response(),
+        f => f.unwrap_or(state.dow_boost),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let weekday = (state.clock)().weekday();
+    let selected = select_weekday_with(&guard.sorted_keys, weekday, factor, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/surprise",
+    description = "Random image from a randomly drawn strategy, named in `X-Selection-Mode`; \
+                   a strategy with nothing to pick from falls back to `uniform`.",
+    params(FormatQuery, PrefixQuery, VariantQuery, SurpriseQuery),
+    responses(SelectionResponses)
+)]
+async fn surprise_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<SurpriseQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let mut rng = state.rng(q.seed);
+    let keys = &guard.sorted_keys;
+    let now = (state.clock)();
+    let mode = SurpriseMode::choose(&mut rng);
+    let mut tag = None;
+    let selected = match mode {
+        SurpriseMode::Uniform => None,
+        SurpriseMode::Biased => {
+            let decay = state.decay(keys.len(), None);
+            select_biased_with(keys, decay, 1.0, state.max_weight, &mut rng)
+        }
+        SurpriseMode::Seasonal => {
+            let indices = month_indices(keys, &[now.month()]);
+            select_uniform_among(keys, &indices, &mut rng)
+        }
+        SurpriseMode::OnThisDay => {
+            let indices = on_this_day_indices(keys, now);
+            select_uniform_among(keys, &indices, &mut rng)
+        }
+        SurpriseMode::TagRandom => {
+            let mut tags: Vec<_> = guard.tag_index.iter().collect();
+            tags.sort_unstable_by_key(|(tag, _)| tag.as_str());
+            tags.choose(&mut rng).and_then(|&(name, indices)| {
+                tag = Some(name.as_str());
+                select_uniform_among(keys, indices, &mut rng)
+            })
+        }
+    };
+    let (mode, selected) = match selected {
+        Some(key) => (mode, Some(key)),
+        None => {
+            tag = None;
+            (SurpriseMode::Uniform, select_uniform_with(keys, &mut rng))
+        }
+    };
+    let elapsed = started.elapsed();
+    let mut response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => return with_server_timing(state.empty_selection(format), elapsed),
+    };
+    let headers = response.headers_mut();
+    headers.insert("x-selection-mode", HeaderValue::from_static(mode.name()));
+    if let Some(value) = tag.and_then(|tag| HeaderValue::from_str(tag).ok()) {
+        headers.insert("x-selection-tag", value);
+    }
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/discover",
+    description = "Random image weighted away from keys recently served here to any client.",
+    params(FormatQuery, PrefixQuery, VariantQuery, CacheQuery),
+    responses(SelectionResponses)
+)]
+async fn discover_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let selected = state
+        .recently_served
+        .select(&guard.sorted_keys, started, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image/fair",
+    description = "Random image weighted toward the keys served least here, counted across restarts when `SERVED_COUNTS_PATH` is set.",
+    params(FormatQuery, PrefixQuery, VariantQuery, CacheQuery),
+    responses(SelectionResponses)
+)]
+async fn fair_image(
+    State(state): State<Arc<AppState>>,
+    Format(format): Format,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<CacheQuery>,
+) -> Response {
+    let cache = q.cache.as_deref().and_then(parse_duration);
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let started = Instant::now();
+    let selected = state
+        .served_counts
+        .select(&guard.sorted_keys, &mut state.rng(None));
+    let elapsed = started.elapsed();
+    let response = match selected {
+        Some(key) => state.redirect(
+            key,
+            files,
+            &guard.dimensions,
+            prefix.as_deref(),
+            cache,
+            format,
+        ),
+        None => state.empty_selection(format),
+    };
+    with_server_timing(response, elapsed)
+}
+
+#[cfg(feature = "qr")]
+fn qr_response(
+    state: &AppState,
+    keys: &[String],
+    files: &HashMap<String, String>,
+    prefix: Option<&str>,
+    q: &QrQuery,
+) -> Response {
+    let module = q.module.unwrap_or(qr::DEFAULT_MODULE_SIZE);
+    let ec_level = match q.ec.as_deref().map(qr::parse_ec_level) {
+        Some(Some(level)) => level,
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => qrcode::EcLevel::M,
+    };
+    if !(1..=qr::MAX_MODULE_SIZE).contains(&module) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Some(key) = select_uniform_with(keys, &mut state.rng(None)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let url = match state.resolve(key, files, prefix) {
+        Ok(url) => url,
+        Err(status) => return status.into_response(),
+    };
+    match qr::render_png(&url, module, ec_level) {
+        Some(png) => ([(header::CONTENT_TYPE, "image/png")], png).into_response(),
+        None => StatusCode::BAD_REQUEST.into_response(),
     }
 }
 
-const EMBEDDED_IMAGE_MAP: &str = include_str!("../image-map.json");
+#[cfg(feature = "qr")]
+#[utoipa::path(
+    get,
+    path = "/qr",
+    description = "PNG QR code of a random image's URL.",
+    params(PrefixQuery, VariantQuery, QrQuery),
+    responses(QrResponses)
+)]
+async fn qr_image(
+    State(state): State<Arc<AppState>>,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Query(q): Query<QrQuery>,
+) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    qr_response(&state, &guard.sorted_keys, files, prefix.as_deref(), &q)
+}
 
-fn hash_content(content: &str) -> u64 {
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    content.hash(&mut hasher);
-    hasher.finish()
+#[cfg(feature = "qr")]
+#[utoipa::path(
+    get,
+    path = "/qr/after/{bound}",
+    description = "PNG QR code of a random image's URL with key `>= bound`.",
+    params(("bound" = String, Path), PrefixQuery, VariantQuery, QrQuery),
+    responses(QrResponses)
+)]
+async fn qr_image_after(
+    State(state): State<Arc<AppState>>,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Bound(bound): Bound,
+    Query(q): Query<QrQuery>,
+) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let Some(files) = guard.files(variant.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    qr_response(
+        &state,
+        guard.keys_after(&bound),
+        files,
+        prefix.as_deref(),
+        &q,
+    )
 }
 
-struct ImageMap {
-    sorted_keys: Vec<String>,
-    map: HashMap<String, String>,
-    content_hash: u64,
+#[cfg(feature = "montage")]
+#[utoipa::path(
+    get,
+    path = "/montage",
+    description = "A grid of random images composited into one file.",
+    params(MontageQuery),
+    responses(
+        (
+            status = 200,
+            description = "JPEG contact sheet, or PNG with `output=png`",
+            content_type = "image/jpeg"
+        ),
+        (status = 400, description = "Invalid count, cols or output"),
+        (status = 404, description = "Empty map"),
+        (status = 502, description = "No source image could be loaded"),
+        (
+            status = 503,
+            description = "The map is being reloaded, or every failed source's origin circuit is open"
+        ),
+    )
+)]
+async fn montage_image(
+    State(state): State<Arc<AppState>>,
+    Query(q): Query<MontageQuery>,
+) -> Response {
+    if !(1..=montage::MAX_COUNT).contains(&q.count) || !(1..=q.count).contains(&q.cols) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let output = match q.output.as_deref().map(imaging::Output::parse) {
+        Some(Some(output)) => output,
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => imaging::Output::Jpeg,
+    };
+    let urls: Result<Vec<String>, StatusCode> = {
+        let Some(guard) = state.current_map() else {
+            return state.reloading();
+        };
+        if guard.is_empty() {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        guard
+            .sorted_keys
+            .choose_multiple(&mut state.rng(None), q.count)
+            .map(|key| state.resolve(key, &guard.map, None))
+            .collect()
+    };
+    let urls = match urls {
+        Ok(urls) => urls,
+        Err(status) => return status.into_response(),
+    };
+    match montage::render(state.breakers.clone(), urls, q.cols, output).await {
+        Ok(bytes) => ([(header::CONTENT_TYPE, output.content_type())], bytes).into_response(),
+        Err(montage::Error::Open(wait)) => state.unavailable(wait),
+        Err(montage::Error::Unavailable) => StatusCode::BAD_GATEWAY.into_response(),
+    }
 }
 
-impl ImageMap {
-    fn parse(content: &str) -> Result<Self, serde_json::Error> {
-        let map: HashMap<String, String> = serde_json::from_str(content)?;
-        let mut sorted_keys: Vec<String> = map.keys().cloned().collect();
-        sorted_keys.sort();
-        Ok(Self {
-            sorted_keys,
-            map,
-            content_hash: hash_content(content),
-        })
+#[cfg(feature = "transform")]
+#[utoipa::path(
+    get,
+    path = "/image/key/{key}/transform",
+    description = "The image with this key scaled to `w` pixels wide and re-encoded.",
+    params(("key" = String, Path), TransformQuery, PrefixQuery, VariantQuery),
+    responses(
+        (
+            status = 200,
+            description = "The resized image, as `fmt` or else in the source's format",
+            content_type = "image/jpeg"
+        ),
+        (status = 400, description = "Invalid w or fmt"),
+        (status = 404, description = "No image with this key"),
+        (status = 415, description = "The source image's format isn't supported"),
+        (status = 502, description = "The source image couldn't be loaded"),
+        (
+            status = 503,
+            description = "The map is being reloaded, or the source's origin circuit is open"
+        ),
+    )
+)]
+async fn transform_image(
+    State(state): State<Arc<AppState>>,
+    Prefix(prefix): Prefix,
+    Variant(variant): Variant,
+    Path(key): Path<String>,
+    Query(q): Query<TransformQuery>,
+) -> Response {
+    if !(1..=transform::MAX_WIDTH).contains(&q.w) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let requested = match q.fmt.as_deref().map(imaging::Output::parse) {
+        Some(Some(output)) => Some(output),
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+    let url = {
+        let Some(guard) = state.current_map() else {
+            return state.reloading();
+        };
+        let Some(files) = guard.files(variant.as_deref()) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        match state.resolve(&key, files, prefix.as_deref()) {
+            Ok(url) => url,
+            Err(status) => return status.into_response(),
+        }
+    };
+    match transform::render(&state.breakers, &url, q.w, requested).await {
+        Ok(encoded) => (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    encoded.output.content_type().to_string(),
+                ),
+                (
+                    header::CACHE_CONTROL,
+                    format!("public, max-age={}", transform::MAX_AGE),
+                ),
+            ],
+            encoded.bytes.clone(),
+        )
+            .into_response(),
+        Err(transform::Error::Unsupported) => StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response(),
+        Err(transform::Error::Open(wait)) => state.unavailable(wait),
+        Err(transform::Error::Fetch | transform::Error::Image) => {
+            StatusCode::BAD_GATEWAY.into_response()
+        }
     }
 }
 
-struct AppState {
-    url_prefix: String,
-    image_map: RwLock<ImageMap>,
-    recency_decay: f64,
+#[utoipa::path(
+    get,
+    path = "/tags",
+    description = "Tags with their image counts, most used first.",
+    params(TagsQuery),
+    responses(
+        (status = 200, body = Vec<TagCount>),
+        (status = 304, description = "Unchanged since `If-Modified-Since`"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn tags(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(q): Query<TagsQuery>,
+) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    state.conditional(&headers, Json(tag_counts(&guard, q.min_count.unwrap_or(0))))
 }
 
-impl AppState {
-    fn load() -> Self {
-        let url_prefix = env::var("IMAGE_URL_PREFIX").expect("IMAGE_URL_PREFIX required");
-        let content = env::var("IMAGE_MAP_PATH")
-            .map(|p| fs::read_to_string(p).expect("failed to read image map"))
-            .unwrap_or_else(|_| EMBEDDED_IMAGE_MAP.to_string());
-        let image_map = ImageMap::parse(&content).expect("invalid JSON");
-        let recency_decay = env::var("RECENCY_DECAY")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(0.05);
-        Self {
-            url_prefix,
-            image_map: RwLock::new(image_map),
-            recency_decay,
-        }
+#[utoipa::path(
+    get,
+    path = "/image/count-by/{granularity}",
+    description = "Key counts per year, month or day, oldest first, for timelines.",
+    params(
+        ("granularity" = String, Path, description = "`year`, `month` or `day`"),
+        CountByQuery,
+    ),
+    responses(
+        (status = 200, body = Vec<BucketCount>),
+        (status = 304, description = "Unchanged since `If-Modified-Since`"),
+        (status = 400, description = "Invalid `after` or `until`"),
+        (status = 404, description = "Unknown granularity"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn count_by_granularity(
+    State(state): State<Arc<AppState>>,
+    Path(granularity): Path<String>,
+    headers: HeaderMap,
+    Query(q): Query<CountByQuery>,
+) -> Response {
+    let Some(granularity) = Granularity::parse(&granularity) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Err(status) = state
+        .check_bound(q.after.as_deref())
+        .and_then(|()| state.check_bound(q.until.as_deref()))
+    {
+        return status.into_response();
     }
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let range = guard.key_range(q.after.as_deref(), q.until.as_deref());
+    let counts = count_by(&guard.sorted_keys[range], granularity);
+    state.conditional(&headers, Json(counts))
+}
 
-    fn redirect(
-        &self,
-        key: &str,
-        map: &HashMap<String, String>,
-        cache_secs: Option<u64>,
-    ) -> Response {
-        let url = format!("{}/{}", self.url_prefix, map[key]);
-        match cache_secs {
-            Some(secs) => (
-                StatusCode::FOUND,
-                [
-                    (header::LOCATION, url),
-                    (header::CACHE_CONTROL, format!("public, max-age={}", secs)),
-                ],
-            )
-                .into_response(),
-            None => (StatusCode::FOUND, [(header::LOCATION, url)]).into_response(),
-        }
-    }
+#[utoipa::path(
+    get,
+    path = "/stats",
+    responses(
+        (status = 200, body = Stats),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn stats(State(state): State<Arc<AppState>>) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    Json(Stats {
+        count: guard.len(),
+        oldest: guard.sorted_keys.first().map(String::as_str),
+        newest: guard.sorted_keys.last().map(String::as_str),
+        future_keys: guard.count_future(Utc::now()),
+    })
+    .into_response()
 }
 
-fn select_uniform(keys: &[String]) -> Option<&str> {
-    if keys.is_empty() {
-        return None;
-    }
-    Some(&keys[thread_rng().gen_range(0..keys.len())])
+/// The loaded map's content hash, tagged as a strong `ETag` so pollers can
+/// revalidate with `If-None-Match`.
+#[utoipa::path(
+    get,
+    path = "/version",
+    responses(
+        (status = 200, body = Version, headers(("ETag" = String))),
+        (status = 304, description = "`If-None-Match` matched the current hash"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn version(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let hash = format!("{:016x}", guard.content_hash);
+    let etag = format!("\"{hash}\"");
+    let matched = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag == etag)
+        });
+    let mut response = if matched {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        Json(Version {
+            hash,
+            count: guard.len(),
+            loaded_at: state
+                .last_modified
+                .read()
+                .unwrap()
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+        })
+        .into_response()
+    };
+    response
+        .headers_mut()
+        .insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    response
 }
 
-fn select_biased(keys: &[String], decay: f64) -> Option<&str> {
-    if keys.is_empty() {
-        return None;
-    }
-    let weights: Vec<f64> = (0..keys.len()).map(|i| (i as f64 * decay).exp()).collect();
-    let dist = WeightedIndex::new(&weights).ok()?;
-    Some(&keys[thread_rng().sample(dist)])
+/// Logs one structured line identifying the loaded map, so a deploy can be
+/// checked against the expected content hash.
+fn log_summary(state: &AppState, source: &str, sync_url: Option<&str>) {
+    let map = state.image_map.read().unwrap();
+    info!(
+        images = map.len(),
+        url_prefix = %state.url_prefix,
+        source,
+        sync_url,
+        oldest = map.sorted_keys.first().map(String::as_str),
+        newest = map.sorted_keys.last().map(String::as_str),
+        content_hash = %format!("{:016x}", map.content_hash),
+        "loaded image map"
+    );
 }
 
-fn filter_after<'a>(keys: &'a [String], bound: &str) -> &'a [String] {
-    let start = keys.partition_point(|k| k.as_str() < bound);
-    &keys[start..]
+#[utoipa::path(
+    get,
+    path = "/debug/keys",
+    params(DebugKeysQuery),
+    security(("admin" = [])),
+    responses(
+        (status = 200, body = DebugKeys),
+        (status = 400, description = "Invalid window or bound"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `ADMIN_TOKEN` configured"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn debug_keys(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    client: Option<Extension<ClientInfo>>,
+    Query(q): Query<DebugKeysQuery>,
+) -> Response {
+    let actor = match state.audit_admin("debug_keys", &headers, client) {
+        Ok(actor) => actor,
+        Err(status) => return status.into_response(),
+    };
+    audited_read(&actor, "debug_keys", debug_keys_response(&state, &q))
 }
 
-fn maybe_parse_if_changed(content: &str, current_hash: u64) -> Option<ImageMap> {
-    if hash_content(content) == current_hash {
-        return None;
-    }
-    ImageMap::parse(content).ok()
+/// The outcome an audited read is recorded with, from the status it answered.
+fn audited_read(actor: &Actor, action: &str, response: Response) -> Response {
+    let outcome = match response.status() {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        _ => "ok",
+    };
+    actor.record(action, outcome, None);
+    response
 }
 
-async fn random_image(State(state): State<Arc<AppState>>, Query(q): Query<CacheQuery>) -> Response {
-    let cache = q.cache.as_deref().and_then(parse_duration);
-    let guard = state.image_map.read().unwrap();
-    match select_uniform(&guard.sorted_keys) {
-        Some(key) => state.redirect(key, &guard.map, cache),
-        None => StatusCode::NOT_FOUND.into_response(),
+fn debug_keys_response(state: &AppState, q: &DebugKeysQuery) -> Response {
+    if q.window == 0 || q.window > MAX_DEBUG_WINDOW {
+        return StatusCode::BAD_REQUEST.into_response();
     }
+    if let Err(status) = check_bound(q.around.as_deref()) {
+        return status.into_response();
+    }
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let around = q.around.as_deref().map(|bound| {
+        window_around(&guard.sorted_keys, bound, q.window)
+            .iter()
+            .map(|key| DebugEntry {
+                key,
+                file: &guard.map[key],
+            })
+            .collect()
+    });
+    Json(DebugKeys {
+        count: guard.len(),
+        first: guard.sorted_keys.first().map(String::as_str),
+        last: guard.sorted_keys.last().map(String::as_str),
+        around,
+    })
+    .into_response()
 }
 
-async fn random_image_after(
+#[utoipa::path(
+    get,
+    path = "/debug/weights",
+    params(DebugWeightsQuery),
+    security(("admin" = [])),
+    responses(
+        (status = 200, body = DebugWeights),
+        (status = 400, description = "Invalid limit, bound or weighting parameter"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `ADMIN_TOKEN` configured"),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn debug_weights(
     State(state): State<Arc<AppState>>,
-    Path(bound): Path<String>,
-    Query(q): Query<CacheQuery>,
+    headers: HeaderMap,
+    client: Option<Extension<ClientInfo>>,
+    Query(q): Query<DebugWeightsQuery>,
 ) -> Response {
-    let cache = q.cache.as_deref().and_then(parse_duration);
-    let guard = state.image_map.read().unwrap();
-    let keys = filter_after(&guard.sorted_keys, &bound);
-    match select_uniform(keys) {
-        Some(key) => state.redirect(key, &guard.map, cache),
-        None => StatusCode::NOT_FOUND.into_response(),
-    }
+    let actor = match state.audit_admin("debug_weights", &headers, client) {
+        Ok(actor) => actor,
+        Err(status) => return status.into_response(),
+    };
+    audited_read(&actor, "debug_weights", debug_weights_response(&state, &q))
 }
 
-async fn latest_image(State(state): State<Arc<AppState>>, Query(q): Query<CacheQuery>) -> Response {
-    let cache = q.cache.as_deref().and_then(parse_duration);
-    let guard = state.image_map.read().unwrap();
-    match select_biased(&guard.sorted_keys, state.recency_decay) {
-        Some(key) => state.redirect(key, &guard.map, cache),
-        None => StatusCode::NOT_FOUND.into_response(),
+fn debug_weights_response(state: &AppState, q: &DebugWeightsQuery) -> Response {
+    if q.limit == 0 || q.limit > MAX_DEBUG_WINDOW {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    if let Err(status) = check_bound(q.after.as_deref()) {
+        return status.into_response();
     }
+    let (recency, decay, max_weight, weighting) = match (
+        parse_recency(q.recency),
+        decay_override(q.decay, q.halflife),
+        parse_max_weight(q.max_weight),
+        parse_weighting(q.weighting.as_deref(), state.bias_weighting),
+    ) {
+        (Ok(r), Ok(d), Ok(w), Ok(weighting)) => (r, d, w.or(state.max_weight), weighting),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let Some(guard) = state.current_map() else {
+        return state.reloading();
+    };
+    let keys = match &q.after {
+        Some(bound) => state.biased_pool(&guard, bound),
+        None => &guard.sorted_keys[..],
+    };
+    let keys = state.skip_newest(keys, q.skip);
+    let len = match weighting {
+        Weighting::Slice => keys.len(),
+        Weighting::Global => guard.sorted_keys.len(),
+    };
+    let decay = state.decay(len, decay);
+    let mut probabilities = weights_for(keys.len(), decay, recency);
+    if let Some(max) = max_weight {
+        cap_weights(&mut probabilities, max);
+    }
+    let start = keys.len().saturating_sub(q.limit);
+    Json(DebugWeights {
+        count: keys.len(),
+        decay,
+        recency,
+        max_weight,
+        weights: keys[start..]
+            .iter()
+            .zip(&probabilities[start..])
+            .map(|(key, &probability)| KeyWeight { key, probability })
+            .collect(),
+    })
+    .into_response()
 }
 
-async fn latest_image_after(
+#[utoipa::path(
+    get,
+    path = "/debug/echo",
+    description = "What the service sees of the request, for checking proxy configuration.",
+    security(("admin" = [])),
+    responses(
+        (status = 200, body = Echo),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `ADMIN_TOKEN` configured"),
+    )
+)]
+async fn debug_echo(
     State(state): State<Arc<AppState>>,
-    Path(bound): Path<String>,
-    Query(q): Query<CacheQuery>,
+    headers: HeaderMap,
+    extensions: Extensions,
 ) -> Response {
-    let cache = q.cache.as_deref().and_then(parse_duration);
-    let guard = state.image_map.read().unwrap();
-    let keys = filter_after(&guard.sorted_keys, &bound);
-    match select_biased(keys, state.recency_decay) {
-        Some(key) => state.redirect(key, &guard.map, cache),
-        None => StatusCode::NOT_FOUND.into_response(),
+    let client = extensions.get::<ClientInfo>().copied().map(Extension);
+    match state.audit_admin("debug_echo", &headers, client) {
+        Ok(actor) => actor.record("debug_echo", "ok", None),
+        Err(status) => return status.into_response(),
     }
+    let remote = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let echoed = ECHOED_HEADERS
+        .iter()
+        .filter_map(|&name| {
+            let values: Vec<&str> = headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            (!values.is_empty()).then(|| (name.to_string(), values.join(", ")))
+        })
+        .collect();
+    let client = extensions.get::<ClientInfo>().copied().unwrap_or_else(|| {
+        forwarded::resolve(
+            remote.map(|addr| addr.ip()),
+            &headers,
+            state.trusted_proxies,
+        )
+    });
+    Json(Echo {
+        remote_addr: remote.map(|addr| addr.to_string()),
+        client_ip: client.ip.map(|ip| ip.to_string()),
+        scheme: client.scheme,
+        headers: echoed,
+    })
+    .into_response()
 }
 
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses((status = 200, description = "Number of loaded images", body = String))
+)]
 async fn health(State(state): State<Arc<AppState>>) -> String {
     state
         .image_map
@@ -193,6 +3804,158 @@ async fn health(State(state): State<Arc<AppState>>) -> String {
         .to_string()
 }
 
+#[utoipa::path(
+    get,
+    path = "/ready",
+    responses(
+        (status = 200, description = "Serving traffic"),
+        (status = 503, description = "In maintenance mode"),
+    )
+)]
+async fn ready(State(state): State<Arc<AppState>>) -> StatusCode {
+    if state.in_maintenance() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/ping",
+    description = "Readiness of the selection pool in one call, for status pages. Not counted in `/metrics`.",
+    responses(
+        (status = 200, body = Ping),
+        (status = 503, description = "The map is being reloaded"),
+    )
+)]
+async fn ping(State(state): State<Arc<AppState>>) -> Response {
+    // Not `current_map`, which hides the map during maintenance.
+    let Ok(guard) = state.image_map.try_read() else {
+        return state.reloading();
+    };
+    Json(Ping {
+        ok: !guard.is_empty() && !state.in_maintenance(),
+        keys: guard.len(),
+        loaded_at: state
+            .last_modified
+            .read()
+            .unwrap()
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        hash: format!("{:016x}", guard.content_hash),
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/maintenance",
+    params(MaintenanceQuery),
+    security(("admin" = [])),
+    responses(
+        (status = 204, description = "Maintenance mode set"),
+        (status = 400, description = "Missing or invalid `on`"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `ADMIN_TOKEN` configured"),
+    )
+)]
+async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    client: Option<Extension<ClientInfo>>,
+    Query(q): Query<MaintenanceQuery>,
+) -> StatusCode {
+    let actor = match state.audit_admin("maintenance", &headers, client) {
+        Ok(actor) => actor,
+        Err(status) => return status,
+    };
+    let changed = state.maintenance.swap(q.on, Ordering::Relaxed) != q.on;
+    if changed {
+        info!(on = q.on, "maintenance mode changed");
+    }
+    let detail = match (q.on, changed) {
+        (true, true) => "on",
+        (false, true) => "off",
+        (true, false) => "already on",
+        (false, false) => "already off",
+    };
+    actor.record("maintenance", "ok", Some(detail));
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/shutdown",
+    description = "Stops a `--serve-once` server after in-flight requests finish.",
+    security(("admin" = [])),
+    responses(
+        (status = 202, description = "Shutting down"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `ADMIN_TOKEN` configured, or not `--serve-once`"),
+    )
+)]
+async fn shutdown(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    client: Option<Extension<ClientInfo>>,
+) -> StatusCode {
+    let actor = match state.audit_admin("shutdown", &headers, client) {
+        Ok(actor) => actor,
+        Err(status) => return status,
+    };
+    let Some(shutdown) = &state.shutdown else {
+        actor.record("shutdown", "unavailable", Some("not --serve-once"));
+        return StatusCode::NOT_FOUND;
+    };
+    actor.record("shutdown", "accepted", None);
+    info!("shutdown requested");
+    shutdown.notify_one();
+    StatusCode::ACCEPTED
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "Prometheus text exposition", body = String))
+)]
+async fn metrics_text(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render() + &state.breakers.render(Instant::now()),
+    )
+}
+
+/// Slideshow interval when `/embed` has no `?interval=`.
+const EMBED_INTERVAL_SECS: u64 = 30;
+
+#[utoipa::path(
+    get,
+    path = "/embed",
+    description = "Self-contained HTML slideshow of `/image`, refreshed every `interval`.",
+    params(EmbedQuery),
+    responses(
+        (status = 200, description = "The slideshow page", content_type = "text/html"),
+        (status = 400, description = "Invalid or zero interval"),
+    )
+)]
+async fn embed(State(state): State<Arc<AppState>>, Query(q): Query<EmbedQuery>) -> Response {
+    let secs = match q.interval.as_deref().map(parse_duration) {
+        Some(Some(secs)) if secs > 0 => secs,
+        Some(_) => return StatusCode::BAD_REQUEST.into_response(),
+        None => EMBED_INTERVAL_SECS,
+    };
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Html(embed_page(&state.base_path, secs)),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/robots.txt",
+    responses((status = 200, body = String))
+)]
 async fn robots() -> impl IntoResponse {
     (
         StatusCode::OK,
@@ -201,36 +3964,83 @@ async fn robots() -> impl IntoResponse {
     )
 }
 
-async fn sync_loop(state: Arc<AppState>, url: String, interval: Duration) {
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        reqwest::header::USER_AGENT,
-        reqwest::header::HeaderValue::from_static("roulette/1.0"),
+async fn reload_once<S: MapSource>(state: &AppState, source: &S) {
+    let current_hash = state.image_map.read().unwrap().content_hash;
+    if !source.changed(current_hash).await {
+        return;
+    }
+    let content = match source.fetch().await {
+        Ok(content) => content,
+        Err(e) => {
+            warn!(error = %e, "sync failed, keeping the current map");
+            state.metrics.reload_failed();
+            return;
+        }
+    };
+    if hash_content(&content) == current_hash {
+        return;
+    }
+    let options = parse_options(
+        state.strict_map,
+        state.drop_future_keys,
+        state.key_prefix_filter.as_deref(),
     );
-    let client = reqwest::Client::builder()
-        .default_headers(headers)
-        .build()
-        .expect("failed to build HTTP client");
-    loop {
-        match client
-            .get(&url)
-            .send()
-            .await
-            .and_then(|r| r.error_for_status())
-        {
-            Ok(resp) => match resp.text().await {
-                Ok(content) => {
-                    let current_hash = state.image_map.read().unwrap().content_hash;
-                    if let Some(new_map) = maybe_parse_if_changed(&content, current_hash) {
-                        info!(images = new_map.sorted_keys.len(), "synced image map");
-                        *state.image_map.write().unwrap() = new_map;
-                    }
-                }
-                Err(e) => warn!(error = %e, "sync failed: read error"),
-            },
-            Err(e) => warn!(error = %e, "sync failed: fetch error"),
+    let parsed = {
+        let current = state.image_map.read().unwrap();
+        ImageMap::parse_incremental(&content, &options, &current)
+    };
+    let new_map = match parsed {
+        Ok(new_map) => new_map,
+        Err(e) => {
+            warn!(error = %e, "synced map is invalid, keeping the current one");
+            state.metrics.reload_failed();
+            return;
+        }
+    };
+    if let Some(check) = &state.reload_check {
+        let urls = new_map
+            .sorted_keys
+            .choose_multiple(&mut state.rng(None), check.sample)
+            .map(|key| state.resolve(key, &new_map.map, None).ok())
+            .collect();
+        let rate = validate::failure_rate(state.breakers.clone(), urls).await;
+        if rate > check.max_failure_rate {
+            error!(
+                rate,
+                "new image map failed validation, keeping the current one"
+            );
+            state.metrics.reload_failed();
+            return;
         }
+    }
+    info!(images = new_map.len(), "synced image map");
+    // Free the old map after releasing the lock, so selections aren't turned
+    // away while a large map is dropped.
+    let mut guard = state.image_map.write().unwrap();
+    let old = std::mem::replace(&mut *guard, new_map);
+    drop(guard);
+    drop(old);
+    *state.last_modified.write().unwrap() = now_secs();
+}
+
+async fn reload_loop<S: MapSource>(state: Arc<AppState>, source: S, interval: Duration) {
+    loop {
+        reload_once(&state, &source).await;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+fn save_served_counts(state: &AppState) {
+    if let Err(error) = state.served_counts.save() {
+        warn!(%error, "failed to save served counts");
+    }
+}
+
+/// Saves the `/image/fair` counts every `interval`.
+async fn persist_loop(state: Arc<AppState>, interval: Duration) {
+    loop {
         tokio::time::sleep(interval).await;
+        save_served_counts(&state);
     }
 }
 
@@ -256,50 +4066,602 @@ async fn shutdown_signal() {
     info!("shutdown signal received");
 }
 
-#[tokio::main]
-async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
-    let state = Arc::new(AppState::load());
-    info!(
-        images = state.image_map.read().unwrap().sorted_keys.len(),
-        "loaded image map"
-    );
-    if let (Ok(url), Ok(secs)) = (
-        env::var("IMAGE_MAP_SYNC_URL"),
-        env::var("IMAGE_MAP_SYNC_INTERVAL"),
-    ) {
-        let interval = Duration::from_secs(
-            secs.parse()
-                .expect("IMAGE_MAP_SYNC_INTERVAL must be seconds"),
-        );
-        info!(%url, ?interval, "starting sync loop");
-        tokio::spawn(sync_loop(state.clone(), url, interval));
+/// [`shutdown_signal`], or with `--serve-once` whichever comes first of it and
+/// a request to `/admin/shutdown`.
+async fn shutdown_requested(state: Arc<AppState>) {
+    match &state.shutdown {
+        Some(requested) => tokio::select! {
+            _ = shutdown_signal() => {},
+            _ = requested.notified() => {},
+        },
+        None => shutdown_signal().await,
+    }
+}
+
+/// Serves `app` over HTTPS on `listener` until [`shutdown_requested`], like
+/// the plain `axum::serve` path.
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    tls: RustlsConfig,
+    state: Arc<AppState>,
+) -> std::io::Result<()> {
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown_requested(state).await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    axum_server::from_tcp_rustls(listener.into_std()?, tls)
+        .handle(handle)
+        .serve(
+            ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(normalize(
+                app,
+            )),
+        )
+        .await
+}
+
+/// The line `--serve-once` prints once it is listening.
+fn listening_line(addr: SocketAddr) -> String {
+    serde_json::json!({ "port": addr.port() }).to_string()
+}
+
+/// Binds `port` on every interface, or for `--serve-once` an ephemeral
+/// loopback port announced on `out` as a [`listening_line`].
+async fn bind_listener(
+    serve_once: bool,
+    port: u16,
+    out: &mut impl std::io::Write,
+) -> std::io::Result<tokio::net::TcpListener> {
+    let addr = if serve_once {
+        ("127.0.0.1", 0)
+    } else {
+        ("0.0.0.0", port)
+    };
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    info!(port = local.port(), "starting server");
+    if serve_once {
+        writeln!(out, "{}", listening_line(local))?;
+        out.flush()?;
+    }
+    Ok(listener)
+}
+
+/// Serves plain HTTP with peer addresses attached until
+/// [`shutdown_requested`].
+async fn serve_http(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    state: Arc<AppState>,
+) -> std::io::Result<()> {
+    axum::serve(
+        listener,
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(normalize(app)),
+    )
+    .with_graceful_shutdown(shutdown_requested(state))
+    .await
+}
+
+fn normalize_base_path(s: &str) -> String {
+    let trimmed = s.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+/// OpenAPI responses shared by the single-image selection endpoints.
+#[derive(IntoResponses)]
+#[allow(dead_code)]
+enum SelectionResponses<'a> {
+    /// Redirect to the image (the default format).
+    #[response(status = 302)]
+    Redirect,
+    /// The selection as JSON (`format=json` or `Accept: application/json`), or
+    /// an HTML preview (`format=html`).
+    #[response(status = 200)]
+    Selected(Selection<'a>),
+    /// Invalid query parameter or bound.
+    #[response(status = 400)]
+    BadRequest,
+    /// Unsigned or unlisted `prefix` override.
+    #[response(status = 403)]
+    Forbidden,
+    /// Nothing to select (unless `FALLBACK_URL` is set), or no such variant.
+    #[response(status = 404)]
+    NotFound,
+    /// The map is being reloaded.
+    #[response(status = 503)]
+    Reloading,
+}
+
+/// OpenAPI responses for endpoints returning several selections.
+#[derive(IntoResponses)]
+#[allow(dead_code)]
+enum ListResponses<'a> {
+    /// Chronological selections.
+    #[response(status = 200)]
+    Selected(Vec<Selection<'a>>),
+    /// Invalid query parameter.
+    #[response(status = 400)]
+    BadRequest,
+    /// Unsigned or unlisted `prefix` override.
+    #[response(status = 403)]
+    Forbidden,
+    /// No such variant.
+    #[response(status = 404)]
+    NotFound,
+    /// The map is being reloaded.
+    #[response(status = 503)]
+    Reloading,
+}
+
+/// OpenAPI responses for the `/qr` endpoints.
+#[cfg(feature = "qr")]
+#[derive(IntoResponses)]
+#[allow(dead_code)]
+enum QrResponses {
+    /// PNG QR code of the selected image's URL.
+    #[response(status = 200, content_type = "image/png")]
+    Png,
+    /// Invalid module size or error-correction level, or a code too large.
+    #[response(status = 400)]
+    BadRequest,
+    /// Nothing to select, or no such variant.
+    #[response(status = 404)]
+    NotFound,
+    /// The map is being reloaded.
+    #[response(status = 503)]
+    Reloading,
+}
+
+/// Registers the bearer token scheme the `/debug` endpoints require.
+struct AdminAuth;
+
+impl utoipa::Modify for AdminAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "admin",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "roulette", description = "Redirects to random images from a timestamped map."),
+    paths(
+        health,
+        ready,
+        ping,
+        random_image,
+        random_image_after,
+        latest_image,
+        latest_image_after,
+        tag_image_after,
+        latest_tag_image_after,
+        months_image,
+        latest_months_image,
+        themed_image,
+        dow_image,
+        surprise_image,
+        discover_image,
+        fair_image,
+        key_image,
+        similar_image,
+        meta,
+        indexed_image,
+        sequence_image,
+        short_link,
+        short_image,
+        recent_image,
+        evenly_images,
+        top_images,
+        week_images,
+        session_start,
+        session_next,
+        tags,
+        count_by_granularity,
+        stats,
+        version,
+        debug_keys,
+        debug_weights,
+        debug_echo,
+        set_maintenance,
+        shutdown,
+        metrics_text,
+        embed,
+        robots,
+    ),
+    components(schemas(Selection, Fallback)),
+    modifiers(&AdminAuth)
+)]
+struct ApiDoc;
+
+#[cfg(feature = "montage")]
+#[derive(OpenApi)]
+#[openapi(paths(montage_image))]
+struct MontageApiDoc;
+
+#[cfg(feature = "qr")]
+#[derive(OpenApi)]
+#[openapi(paths(qr_image, qr_image_after))]
+struct QrApiDoc;
+
+#[cfg(feature = "transform")]
+#[derive(OpenApi)]
+#[openapi(paths(transform_image))]
+struct TransformApiDoc;
+
+/// The OpenAPI document for the routes this build serves, relative to
+/// `base_path`.
+fn openapi(base_path: &str) -> utoipa::openapi::OpenApi {
+    let mut doc = ApiDoc::openapi();
+    #[cfg(feature = "montage")]
+    doc.merge(MontageApiDoc::openapi());
+    #[cfg(feature = "qr")]
+    doc.merge(QrApiDoc::openapi());
+    #[cfg(feature = "transform")]
+    doc.merge(TransformApiDoc::openapi());
+    if !base_path.is_empty() {
+        doc.servers = Some(vec![utoipa::openapi::Server::new(base_path)]);
     }
-    let app = Router::new()
+    doc
+}
+
+async fn openapi_json(State(state): State<Arc<AppState>>) -> Response {
+    Json(openapi(&state.base_path)).into_response()
+}
+
+fn router(state: Arc<AppState>) -> Router {
+    let base_path = state.base_path.clone();
+    let routes = Router::new()
         .route("/health", get(health))
+        .route("/ready", get(ready))
         .route("/image", get(random_image))
+        .route("/image/after", get(random_image_after))
         .route("/image/after/{bound}", get(random_image_after))
         .route("/image/latest", get(latest_image))
+        .route("/image/latest/after", get(latest_image_after))
         .route("/image/latest/after/{bound}", get(latest_image_after))
-        .route("/robots.txt", get(robots))
-        .layer(TraceLayer::new_for_http())
+        .route("/image/tag/{tag}/after/{bound}", get(tag_image_after))
+        .route(
+            "/image/latest/tag/{tag}/after/{bound}",
+            get(latest_tag_image_after),
+        )
+        .route("/image/months", get(months_image))
+        .route("/image/latest/months", get(latest_months_image))
+        .route("/image/themed", get(themed_image))
+        .route("/image/dow", get(dow_image))
+        .route("/image/surprise", get(surprise_image))
+        .route("/image/discover", get(discover_image))
+        .route("/image/fair", get(fair_image))
+        .route("/image/key/{key}", get(key_image))
+        .route("/image/similar/{key}", get(similar_image))
+        .route("/meta/{key}", get(meta))
+        .route("/image/index/{n}", get(indexed_image))
+        .route("/image/seq", get(sequence_image))
+        .route("/image/short", get(short_link))
+        .route("/s/{code}", get(short_image))
+        .route("/image/recent/{n}", get(recent_image))
+        .route("/image/evenly", get(evenly_images))
+        .route("/image/top", get(top_images))
+        .route("/image/week", get(week_images))
+        .route("/image/session", get(session_start))
+        .route("/image/session/{token}/next", get(session_next))
+        .route("/random", get(random_image))
+        .route("/random/after", get(random_image_after))
+        .route("/random/after/{bound}", get(random_image_after))
+        .route("/random/latest", get(latest_image))
+        .route("/random/latest/after", get(latest_image_after))
+        .route("/random/latest/after/{bound}", get(latest_image_after))
+        .route("/random/themed", get(themed_image))
+        .route("/tags", get(tags))
+        .route("/image/count-by/{granularity}", get(count_by_granularity))
+        .route("/stats", get(stats))
+        .route("/version", get(version))
+        .route("/debug/keys", get(debug_keys))
+        .route("/debug/weights", get(debug_weights))
+        .route("/debug/echo", get(debug_echo))
+        .route("/admin/maintenance", post(set_maintenance))
+        .route("/admin/shutdown", post(shutdown))
+        .route("/metrics", get(metrics_text))
+        .route("/embed", get(embed))
+        .route("/openapi.json", get(openapi_json))
+        .route("/robots.txt", get(robots));
+    #[cfg(feature = "montage")]
+    let routes = routes.route("/montage", get(montage_image));
+    #[cfg(feature = "qr")]
+    let routes = routes
+        .route("/qr", get(qr_image))
+        .route("/qr/after", get(qr_image_after))
+        .route("/qr/after/{bound}", get(qr_image_after));
+    #[cfg(feature = "transform")]
+    let routes = routes.route("/image/key/{key}/transform", get(transform_image));
+    let routes = routes
+        .route_layer(middleware::from_fn(dimension_headers))
+        .route_layer(middleware::from_fn_with_state(
+            state.deprecations.clone(),
+            deprecation::middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::middleware,
+        ))
+        // After the route layers, so status-page polling stays out of `/metrics`.
+        .route("/ping", get(ping))
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &Request| {
+            let request_id = req
+                .extensions()
+                .get::<RequestId>()
+                .and_then(|id| id.header_value().to_str().ok())
+                .unwrap_or_default();
+            tracing::info_span!(
+                "request",
+                method = %req.method(),
+                uri = %req.uri(),
+                request_id,
+            )
+        }))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(middleware::from_fn_with_state(
+            state.trusted_proxies,
+            forwarded::middleware,
+        ))
         .with_state(state);
-    let port: u16 = env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080);
-    info!(port, "starting server");
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+    if base_path.is_empty() {
+        routes
+    } else {
+        Router::new().nest(&base_path, routes)
+    }
+}
+
+// Wraps the router instead of layering it: layers run after route matching.
+fn normalize(app: Router) -> NormalizePath<Router> {
+    NormalizePathLayer::trim_trailing_slash().layer(app)
+}
+
+/// Loads and parses the map as startup would, for the offline checks.
+async fn parse_map<S: MapSource>(config: &Config, source: &S) -> Result<ImageMap, String> {
+    let content = source.fetch().await.map_err(|e| e.to_string())?;
+    let options = parse_options(
+        config.strict_map,
+        config.drop_future_keys,
+        config.key_prefix_filter.as_deref(),
+    );
+    ImageMap::parse_with(&content, &options).map_err(|e| e.to_string())
+}
+
+/// The number of images in the map, for `--validate`.
+async fn validate_map<S: MapSource>(config: &Config, source: &S) -> Result<usize, String> {
+    parse_map(config, source).await.map(|map| map.len())
+}
+
+/// Compares the map against a storage manifest, for `--reconcile`.
+async fn reconcile_map<S: MapSource>(
+    config: &Config,
+    source: &S,
+    manifest: &str,
+) -> Result<Reconciliation, String> {
+    parse_map(config, source)
         .await
-        .unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
+        .map(|map| map.reconcile(manifest))
+}
+
+/// Runs `--reconcile {manifest}`: prints a tab-separated line per mismatch
+/// and exits with status `1` if there are any.
+async fn run_reconcile(config: &Config, manifest: Option<&str>) {
+    let manifest = match manifest {
+        Some(STDIN_PATH) if config.map_path.as_deref() == Some(STDIN_PATH) => {
+            Err("the map and the manifest can't both be read from stdin".to_string())
+        }
+        Some(STDIN_PATH) => io::read_to_string(io::stdin()).map_err(|e| e.to_string()),
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}")),
+        None => Err("--reconcile requires a manifest path, or - for stdin".to_string()),
+    };
+    let result = match manifest {
+        Ok(manifest) => match config.map_path.as_deref() {
+            Some(STDIN_PATH) => reconcile_map(config, &ReaderSource::stdin(), &manifest).await,
+            Some(path) => reconcile_map(config, &FileSource::new(path), &manifest).await,
+            None => reconcile_map(config, &EmbeddedSource(EMBEDDED_IMAGE_MAP), &manifest).await,
+        },
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(reconciliation) => {
+            for (key, filename) in &reconciliation.missing {
+                println!("missing\t{key}\t{filename}");
+            }
+            for filename in &reconciliation.orphaned {
+                println!("orphaned\t{filename}");
+            }
+            if !reconciliation.is_clean() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("reconcile failed: {e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Installs the log subscriber, plus the `tokio-console` layer when built with
+/// that feature and `TOKIO_CONSOLE=1`.
+fn init_tracing() {
+    #[cfg(feature = "tokio-console")]
+    if env::var("TOKIO_CONSOLE").is_ok_and(|v| v == "1") {
+        use tracing_subscriber::prelude::*;
+        tracing_subscriber::registry()
+            .with(console_subscriber::spawn())
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_filter(tracing_subscriber::EnvFilter::from_default_env()),
+            )
+            .init();
+        return;
+    }
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    // `--serve-once` keeps stdout for the line announcing its port.
+    if env::args().any(|arg| arg == "--serve-once") {
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenvy::dotenv().ok();
+    init_tracing();
+    if env::args().any(|arg| arg == "--export-openapi") {
+        let base_path = Config::base_path_from(|name| env::var(name).ok());
+        println!("{}", openapi(&base_path).to_pretty_json().unwrap());
+        return;
+    }
+    let config = Config::from_env().unwrap_or_else(|e| {
+        eprintln!("invalid configuration: {e}");
+        std::process::exit(1);
+    });
+    if env::args().any(|arg| arg == "--print-config") {
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        return;
+    }
+    if let Some(i) = env::args().position(|arg| arg == "--reconcile") {
+        run_reconcile(&config, env::args().nth(i + 1).as_deref()).await;
+        return;
+    }
+    if env::args().any(|arg| arg == "--validate") {
+        let result = match config.map_path.as_deref() {
+            Some(STDIN_PATH) => validate_map(&config, &ReaderSource::stdin()).await,
+            Some(path) => validate_map(&config, &FileSource::new(path)).await,
+            None => validate_map(&config, &EmbeddedSource(EMBEDDED_IMAGE_MAP)).await,
+        };
+        match result {
+            Ok(count) => println!("ok: {count} images"),
+            Err(e) => {
+                eprintln!("invalid image map: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let serve_once = env::args().any(|arg| arg == "--serve-once");
+    // With `S3_BUCKET` set, the bucket serves the first map and every reload.
+    #[cfg(feature = "s3")]
+    let mut s3 = config.s3_bucket.as_deref().map(|bucket| {
+        S3Source::with_default_credentials(
+            bucket,
+            &config.s3_key,
+            &config.s3_region,
+            config.s3_endpoint.clone(),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("invalid S3 configuration: {e}");
+            std::process::exit(1);
+        })
+    });
+    #[cfg(feature = "s3")]
+    let loaded = match &s3 {
+        Some(source) => Some(AppState::load(&config, source).await),
+        None => None,
+    };
+    #[cfg(not(feature = "s3"))]
+    let loaded = {
+        if config.s3_bucket.is_some() {
+            warn!("S3_BUCKET requires the s3 feature");
+        }
+        None
+    };
+    let source = match (&loaded, config.map_path.as_deref()) {
+        (Some(_), _) => "s3",
+        (None, Some(STDIN_PATH)) => "stdin",
+        (None, Some(path)) => path,
+        (None, None) => "embedded",
+    };
+    let mut state = match (loaded, config.map_path.as_deref()) {
+        (Some(state), _) => state,
+        (None, Some(STDIN_PATH)) => AppState::load(&config, &ReaderSource::stdin()).await,
+        (None, Some(path)) => AppState::load(&config, &FileSource::new(path)).await,
+        (None, None) => AppState::load(&config, &EmbeddedSource(EMBEDDED_IMAGE_MAP)).await,
+    };
+    if serve_once {
+        state.shutdown = Some(Notify::new());
+    }
+    let state = Arc::new(state);
+    log_summary(&state, source, config.sync_url.as_deref());
+    if let Some(secs) = config.sync_interval_secs {
+        let interval = Duration::from_secs(secs);
+        match (config.sync_url.clone(), config.map_path.clone()) {
+            #[cfg(feature = "http")]
+            (Some(url), _) => {
+                info!(%url, ?interval, "starting sync loop");
+                tokio::spawn(reload_loop(state.clone(), HttpSource::new(url), interval));
+            }
+            #[cfg(not(feature = "http"))]
+            (Some(_), _) => warn!("IMAGE_MAP_SYNC_URL requires the http feature"),
+            #[cfg(feature = "s3")]
+            (None, _) if s3.is_some() => {
+                info!(?interval, "starting s3 reload loop");
+                tokio::spawn(reload_loop(state.clone(), s3.take().unwrap(), interval));
+            }
+            (None, Some(path)) if path == STDIN_PATH => {
+                warn!("reload disabled: the map was read from stdin");
+            }
+            (None, Some(path)) => {
+                info!(%path, ?interval, "starting file reload loop");
+                tokio::spawn(reload_loop(state.clone(), FileSource::new(path), interval));
+            }
+            (None, None) => {}
+        }
+    }
+    if let Some(path) = state.served_counts.path() {
+        let interval = Duration::from_secs(config.served_counts_interval_secs);
+        info!(path = %path.display(), ?interval, "persisting served counts");
+        tokio::spawn(persist_loop(state.clone(), interval));
+    }
+    let mut app = router(state.clone());
+    if let Some(path) = &config.access_log_path {
+        let log = Arc::new(AccessLog::open(path).expect("failed to open access log"));
+        info!(%path, "writing access log");
+        tokio::spawn(access_log::reopen_on_sighup(log.clone()));
+        app = app.layer(middleware::from_fn_with_state(log, access_log::middleware));
+    }
+    #[cfg(feature = "tls")]
+    let tls = config.tls_files().map(|files| {
+        let loaded = files.load().unwrap_or_else(|e| {
+            eprintln!("invalid TLS configuration: {e}");
+            std::process::exit(1);
+        });
+        let tls = RustlsConfig::from_config(loaded);
+        tokio::spawn(tls::reload_on_sighup(tls.clone(), files));
+        tls
+    });
+    let listener = bind_listener(serve_once, config.port, &mut std::io::stdout())
         .await
         .unwrap();
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        info!(min_version = ?config.tls_min_version, "serving HTTPS");
+        serve_tls(listener, app, tls, state.clone()).await.unwrap();
+        save_served_counts(&state);
+        return;
+    }
+    serve_http(listener, app, state.clone()).await.unwrap();
+    save_served_counts(&state);
 }
 
 #[cfg(test)]
-mod tests;
+mod http_tests;
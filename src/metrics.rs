@@ -0,0 +1,113 @@
+//! Per-route request latency histograms and reload counters in the Prometheus
+//! text format.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// Histogram upper bounds in seconds, dense below a millisecond where
+/// in-memory selection lands.
+pub const BUCKETS: [f64; 14] = [
+    0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+    1.0,
+];
+
+const NAME: &str = "roulette_request_duration_seconds";
+const RELOAD_FAILURES: &str = "roulette_reload_failures_total";
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Request durations keyed by matched route and status code, and failed
+/// reloads.
+#[derive(Default)]
+pub struct Metrics {
+    histograms: Mutex<BTreeMap<(String, u16), Histogram>>,
+    reload_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn observe(&self, route: &str, status: u16, secs: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry((route.to_string(), status)).or_default();
+        if let Some(i) = BUCKETS.iter().position(|&le| secs <= le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    /// Counts a scheduled reload that kept the previous map.
+    pub fn reload_failed(&self) {
+        self.reload_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reload_failures(&self) -> u64 {
+        self.reload_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out =
+            format!("# HELP {NAME} Time spent in route handlers.\n# TYPE {NAME} histogram\n");
+        for ((route, status), histogram) in self.histograms.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",status=\"{status}\"", escape(route));
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                writeln!(out, "{NAME}_bucket{{{labels},le=\"{le}\"}} {cumulative}").unwrap();
+            }
+            let count = histogram.count;
+            writeln!(out, "{NAME}_bucket{{{labels},le=\"+Inf\"}} {count}").unwrap();
+            writeln!(out, "{NAME}_sum{{{labels}}} {}", histogram.sum).unwrap();
+            writeln!(out, "{NAME}_count{{{labels}}} {count}").unwrap();
+        }
+        let failures = self.reload_failures();
+        write!(
+            out,
+            "# HELP {RELOAD_FAILURES} Map reloads that failed and kept the previous map.\n\
+             # TYPE {RELOAD_FAILURES} counter\n\
+             {RELOAD_FAILURES} {failures}\n"
+        )
+        .unwrap();
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Times the matched route's handler. Installed as a route layer so the
+/// measurement excludes tracing, request-id and access-log middleware.
+pub async fn middleware(State(metrics): State<Arc<Metrics>>, req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let started = Instant::now();
+    let response = next.run(req).await;
+    if let Some(route) = route {
+        let secs = started.elapsed().as_secs_f64();
+        metrics.observe(&route, response.status().as_u16(), secs);
+    }
+    response
+}
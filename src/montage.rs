@@ -0,0 +1,99 @@
+//! Server-side contact sheets: a grid of randomly selected images as one file.
+
+use crate::breaker::{Breakers, UpstreamError};
+use crate::imaging::{decode, encode, fetch, Output};
+use image::{imageops, DynamicImage, RgbImage};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::warn;
+
+/// Most images a single montage may request.
+pub const MAX_COUNT: usize = 25;
+/// Edge length in pixels of each square tile.
+pub const TILE_SIZE: u32 = 256;
+/// Source images fetched at once.
+const CONCURRENCY: usize = 4;
+
+/// Background shown for tiles whose source failed to load.
+const PLACEHOLDER: image::Rgb<u8> = image::Rgb([32, 32, 32]);
+
+/// Why no montage could be rendered.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Every source that failed was refused by an open circuit; the soonest
+    /// one to reopen does so after this long.
+    Open(Duration),
+    /// No source image could be loaded.
+    Unavailable,
+}
+
+/// Fetches `urls` with bounded concurrency and lays them out `cols` wide.
+/// Tiles whose source failed to load are left as placeholders.
+pub async fn render(
+    breakers: Arc<Breakers>,
+    urls: Vec<String>,
+    cols: usize,
+    output: Output,
+) -> Result<Vec<u8>, Error> {
+    let semaphore = Arc::new(Semaphore::new(CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for (i, url) in urls.iter().cloned().enumerate() {
+        let semaphore = semaphore.clone();
+        let breakers = breakers.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.map_err(|_| None)?;
+            let bytes = fetch(&breakers, &url).await.map_err(|error| {
+                warn!(%url, %error, "montage fetch failed");
+                match error {
+                    UpstreamError::Open(wait) => Some(wait),
+                    UpstreamError::Failed(_) => None,
+                }
+            })?;
+            let tile = tokio::task::spawn_blocking(move || {
+                decode(&bytes).map(|(img, _)| {
+                    img.resize_to_fill(TILE_SIZE, TILE_SIZE, imageops::FilterType::Triangle)
+                        .to_rgb8()
+                })
+            })
+            .await
+            .map_err(|_| None)?
+            .map_err(|error| {
+                warn!(%url, %error, "montage decode failed");
+                None
+            })?;
+            Ok((i, tile))
+        });
+    }
+    let mut tiles = Vec::with_capacity(urls.len());
+    let mut soonest: Option<Duration> = None;
+    let mut only_refused = true;
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(tile)) => tiles.push(tile),
+            Ok(Err(Some(wait))) => soonest = Some(soonest.map_or(wait, |s| s.min(wait))),
+            _ => only_refused = false,
+        }
+    }
+    if tiles.is_empty() {
+        return Err(match soonest {
+            Some(wait) if only_refused => Error::Open(wait),
+            _ => Error::Unavailable,
+        });
+    }
+    let rows = urls.len().div_ceil(cols);
+    tokio::task::spawn_blocking(move || {
+        let mut sheet = RgbImage::from_pixel(
+            cols as u32 * TILE_SIZE,
+            rows as u32 * TILE_SIZE,
+            PLACEHOLDER,
+        );
+        for (i, tile) in &tiles {
+            let x = (i % cols) as i64 * TILE_SIZE as i64;
+            let y = (i / cols) as i64 * TILE_SIZE as i64;
+            imageops::overlay(&mut sheet, tile, x, y);
+        }
+        encode(&DynamicImage::ImageRgb8(sheet), output).map_err(|_| Error::Unavailable)
+    })
+    .await
+    .map_err(|_| Error::Unavailable)?
+}
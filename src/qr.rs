@@ -0,0 +1,42 @@
+use image::{DynamicImage, ImageFormat, Luma};
+use qrcode::{EcLevel, QrCode};
+use std::io::Cursor;
+
+/// Default pixels per QR module.
+pub const DEFAULT_MODULE_SIZE: u32 = 8;
+/// Largest accepted pixels per module.
+pub const MAX_MODULE_SIZE: u32 = 32;
+/// Largest rendered edge, quiet zone included, in pixels.
+pub const MAX_DIMENSION: u32 = 2048;
+/// Modules of blank border the spec requires around the code.
+const QUIET_ZONE: u32 = 4;
+
+/// Parses an error-correction level: `L`, `M`, `Q` or `H`.
+pub fn parse_ec_level(s: &str) -> Option<EcLevel> {
+    match s {
+        "L" | "l" => Some(EcLevel::L),
+        "M" | "m" => Some(EcLevel::M),
+        "Q" | "q" => Some(EcLevel::Q),
+        "H" | "h" => Some(EcLevel::H),
+        _ => None,
+    }
+}
+
+/// Renders `data` as a PNG QR code, or `None` if it can't be encoded within
+/// [`MAX_DIMENSION`].
+pub fn render_png(data: &str, module_size: u32, ec_level: EcLevel) -> Option<Vec<u8>> {
+    let code = QrCode::with_error_correction_level(data, ec_level).ok()?;
+    let edge = (code.width() as u32 + 2 * QUIET_ZONE) * module_size;
+    if edge > MAX_DIMENSION {
+        return None;
+    }
+    let image = code
+        .render::<Luma<u8>>()
+        .module_dimensions(module_size, module_size)
+        .build();
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut out, ImageFormat::Png)
+        .ok()?;
+    Some(out.into_inner())
+}
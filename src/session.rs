@@ -0,0 +1,88 @@
+use rand::prelude::*;
+use roulette::shuffled_index;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+struct Session {
+    seed: u64,
+    position: usize,
+    reshuffle: bool,
+    expires_at: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    Unknown,
+    Exhausted,
+}
+
+pub struct SessionStore {
+    ttl: Duration,
+    limit: usize,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    /// Keeps at most `limit` live sessions; creating one more evicts the one
+    /// soonest to expire.
+    pub fn new(ttl: Duration, limit: usize) -> Self {
+        Self {
+            ttl,
+            limit,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn create(&self, reshuffle: bool, now: Instant) -> String {
+        let mut rng = thread_rng();
+        let token = format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>());
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires_at > now);
+        if sessions.len() >= self.limit {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, s)| s.expires_at)
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(
+            token.clone(),
+            Session {
+                seed: rng.gen(),
+                position: 0,
+                reshuffle,
+                expires_at: now + self.ttl,
+            },
+        );
+        token
+    }
+
+    pub fn next(&self, token: &str, len: usize, now: Instant) -> Result<usize, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = match sessions.get_mut(token) {
+            Some(s) if s.expires_at > now => s,
+            Some(_) => {
+                sessions.remove(token);
+                return Err(SessionError::Unknown);
+            }
+            None => return Err(SessionError::Unknown),
+        };
+        if session.position >= len {
+            if !session.reshuffle || len == 0 {
+                return Err(SessionError::Exhausted);
+            }
+            session.seed = session.seed.wrapping_add(1);
+            session.position = 0;
+        }
+        let (seed, position) = (session.seed, session.position);
+        session.position += 1;
+        session.expires_at = now + self.ttl;
+        drop(sessions);
+        Ok(shuffled_index(len, seed, position))
+    }
+}
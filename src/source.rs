@@ -0,0 +1,284 @@
+//! Where an image map is loaded from.
+
+use crate::decode_map;
+use std::{error::Error, fmt, future::Future, io, sync::Mutex, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Failure to fetch map content from a [`MapSource`].
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    #[cfg(feature = "http")]
+    Http(reqwest::Error),
+    #[cfg(feature = "s3")]
+    S3(s3::error::S3Error),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "read error: {}", e),
+            #[cfg(feature = "http")]
+            Self::Http(e) => write!(f, "fetch error: {}", e),
+            #[cfg(feature = "s3")]
+            Self::S3(e) => write!(f, "s3 error: {}", e),
+        }
+    }
+}
+
+impl Error for SourceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            #[cfg(feature = "http")]
+            Self::Http(e) => Some(e),
+            #[cfg(feature = "s3")]
+            Self::S3(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<reqwest::Error> for SourceError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+#[cfg(feature = "s3")]
+impl From<s3::error::S3Error> for SourceError {
+    fn from(e: s3::error::S3Error) -> Self {
+        Self::S3(e)
+    }
+}
+
+/// A place the map JSON can be fetched from, initially and on reload.
+pub trait MapSource: Send + Sync {
+    /// Fetches the full map content.
+    fn fetch(&self) -> impl Future<Output = Result<String, SourceError>> + Send;
+
+    /// Whether the content may differ from the map whose hash is `last_hash`.
+    ///
+    /// Defaults to `true`, leaving change detection to the hash comparison
+    /// after fetching.
+    fn changed(&self, last_hash: u64) -> impl Future<Output = bool> + Send {
+        let _ = last_hash;
+        async { true }
+    }
+}
+
+/// A map compiled into the binary.
+pub struct EmbeddedSource(pub &'static str);
+
+impl MapSource for EmbeddedSource {
+    async fn fetch(&self) -> Result<String, SourceError> {
+        Ok(self.0.to_string())
+    }
+
+    async fn changed(&self, _last_hash: u64) -> bool {
+        false
+    }
+}
+
+/// A map file on disk, optionally gzip-compressed.
+///
+/// Changes are detected from the file's modification time and length, so an
+/// unchanged file is never read again.
+pub struct FileSource {
+    path: String,
+    /// Modification time and length when last fetched.
+    fetched: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl FileSource {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            fetched: Mutex::new(None),
+        }
+    }
+
+    async fn stamp(&self) -> io::Result<(SystemTime, u64)> {
+        let metadata = tokio::fs::metadata(&self.path).await?;
+        Ok((metadata.modified()?, metadata.len()))
+    }
+}
+
+impl MapSource for FileSource {
+    async fn fetch(&self) -> Result<String, SourceError> {
+        // Stamped before reading, so a write racing the read shows as a change.
+        let stamp = self.stamp().await.ok();
+        let bytes = tokio::fs::read(&self.path).await?;
+        let content = decode_map(&self.path, bytes)?;
+        *self.fetched.lock().unwrap() = stamp;
+        Ok(content)
+    }
+
+    async fn changed(&self, _last_hash: u64) -> bool {
+        let fetched = *self.fetched.lock().unwrap();
+        match (self.stamp().await, fetched) {
+            (Ok(stamp), Some(fetched)) => stamp != fetched,
+            _ => true,
+        }
+    }
+}
+
+/// A map read to the end of a stream, such as stdin, on first fetch.
+///
+/// A stream can't be re-read, so later fetches return empty content and
+/// `changed` is always `false`.
+pub struct ReaderSource<R> {
+    reader: tokio::sync::Mutex<R>,
+}
+
+impl ReaderSource<tokio::io::Stdin> {
+    pub fn stdin() -> Self {
+        Self::new(tokio::io::stdin())
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> ReaderSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: tokio::sync::Mutex::new(reader),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> MapSource for ReaderSource<R> {
+    async fn fetch(&self) -> Result<String, SourceError> {
+        let mut bytes = Vec::new();
+        self.reader.lock().await.read_to_end(&mut bytes).await?;
+        Ok(decode_map("", bytes)?)
+    }
+
+    async fn changed(&self, _last_hash: u64) -> bool {
+        false
+    }
+}
+
+/// A map served over HTTP.
+#[cfg(feature = "http")]
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "http")]
+impl HttpSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            reqwest::header::HeaderValue::from_static("roulette/1.0"),
+        );
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .expect("failed to build HTTP client");
+        Self {
+            client,
+            url: url.into(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl MapSource for HttpSource {
+    async fn fetch(&self) -> Result<String, SourceError> {
+        let resp = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.text().await?)
+    }
+}
+
+/// A map stored as an object in an S3-compatible bucket.
+///
+/// Reload checks compare the object's ETag via `HEAD` instead of downloading it.
+#[cfg(feature = "s3")]
+pub struct S3Source {
+    bucket: Box<s3::Bucket>,
+    key: String,
+    etag: Mutex<Option<String>>,
+}
+
+#[cfg(feature = "s3")]
+impl S3Source {
+    /// Uses `endpoint` (path-style) instead of AWS when given, e.g. for MinIO.
+    pub fn new(
+        bucket: &str,
+        key: impl Into<String>,
+        region: &str,
+        endpoint: Option<String>,
+        credentials: s3::creds::Credentials,
+    ) -> Result<Self, SourceError> {
+        let bucket = match endpoint {
+            Some(endpoint) => {
+                let region = s3::Region::Custom {
+                    region: region.to_string(),
+                    endpoint,
+                };
+                s3::Bucket::new(bucket, region, credentials)?.with_path_style()
+            }
+            None => {
+                let region = region.parse().map_err(s3::error::S3Error::from)?;
+                s3::Bucket::new(bucket, region, credentials)?
+            }
+        };
+        Ok(Self {
+            bucket,
+            key: key.into(),
+            etag: Mutex::new(None),
+        })
+    }
+
+    /// Like [`S3Source::new`], with credentials from the standard AWS
+    /// environment variables or profile.
+    pub fn with_default_credentials(
+        bucket: &str,
+        key: impl Into<String>,
+        region: &str,
+        endpoint: Option<String>,
+    ) -> Result<Self, SourceError> {
+        let credentials =
+            s3::creds::Credentials::default().map_err(|e| SourceError::S3(e.into()))?;
+        Self::new(bucket, key, region, endpoint, credentials)
+    }
+}
+
+#[cfg(feature = "s3")]
+impl MapSource for S3Source {
+    async fn fetch(&self) -> Result<String, SourceError> {
+        let resp = self.bucket.get_object(&self.key).await?;
+        if resp.status_code() != 200 {
+            return Err(s3::error::S3Error::HttpFailWithBody(
+                resp.status_code(),
+                resp.to_string().unwrap_or_default(),
+            )
+            .into());
+        }
+        let content = decode_map(&self.key, resp.bytes().to_vec())?;
+        *self.etag.lock().unwrap() = resp.headers().get("etag").cloned();
+        Ok(content)
+    }
+
+    async fn changed(&self, _last_hash: u64) -> bool {
+        let Some(last) = self.etag.lock().unwrap().clone() else {
+            return true;
+        };
+        match self.bucket.head_object(&self.key).await {
+            Ok((head, 200)) => head.e_tag.as_deref() != Some(last.as_str()),
+            _ => true,
+        }
+    }
+}
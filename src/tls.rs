@@ -0,0 +1,90 @@
+//! Native HTTPS for deployments without a TLS-terminating proxy, from a PEM
+//! certificate chain and private key. `SIGHUP` rereads both files, so renewed
+//! certificates are picked up without a restart.
+
+use crate::TlsVersion;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, SupportedProtocolVersion,
+};
+use std::{fmt, sync::Arc};
+use tracing::{info, warn};
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// Where the certificate and key are read from, and the oldest protocol
+/// version accepted.
+#[derive(Clone)]
+pub struct TlsFiles {
+    pub cert_path: String,
+    pub key_path: String,
+    pub min_version: TlsVersion,
+}
+
+/// A certificate or key that couldn't be loaded, naming the file.
+#[derive(Debug)]
+pub struct TlsError(String);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl TlsFiles {
+    /// Reads both files into a server config, advertising `h2` and
+    /// `http/1.1`.
+    pub fn load(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| TlsError(format!("{}: {e}", self.cert_path)))?;
+        if certs.is_empty() {
+            return Err(TlsError(format!("{}: no certificates", self.cert_path)));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| TlsError(format!("{}: {e}", self.key_path)))?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(self.versions())
+            .map_err(|e| TlsError(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| TlsError(format!("{}: {e}", self.key_path)))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    fn versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self.min_version {
+            TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+/// Swaps in freshly read files on every `SIGHUP`; a failed read keeps the
+/// current certificate.
+#[cfg(unix)]
+pub async fn reload_on_sighup(config: RustlsConfig, files: TlsFiles) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        reload(&config, &files);
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_config: RustlsConfig, _files: TlsFiles) {}
+
+pub fn reload(config: &RustlsConfig, files: &TlsFiles) {
+    match files.load() {
+        Ok(loaded) => {
+            config.reload_from_config(loaded);
+            info!(path = %files.cert_path, "reloaded TLS certificate");
+        }
+        Err(error) => warn!(%error, "failed to reload TLS certificate"),
+    }
+}
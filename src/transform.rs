@@ -0,0 +1,114 @@
+//! Resized, re-encoded copies of single images for bandwidth-sensitive embeds,
+//! kept in memory so repeat requests skip the fetch and encode.
+
+use crate::breaker::{Breakers, UpstreamError};
+use crate::imaging::{decode, encode, fetch, Output};
+use image::{imageops::FilterType, ImageError};
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use tracing::warn;
+
+/// Widest output a transform may request, in pixels.
+pub const MAX_WIDTH: u32 = 2048;
+/// `Cache-Control` max-age of a transform, in seconds.
+pub const MAX_AGE: u64 = 86_400;
+/// Encoded transforms kept in memory.
+const CACHE_CAPACITY: usize = 64;
+
+/// `(source url, width, requested output)`. The URL stands in for the key so
+/// variants, prefixes and reloads that repoint a key each get their own
+/// entry; a request without `fmt` is cached apart from one naming the
+/// source's format.
+type CacheKey = (String, u32, Option<Output>);
+
+fn cache() -> &'static Mutex<LruCache<CacheKey, Arc<Encoded>>> {
+    static CACHE: OnceLock<Mutex<LruCache<CacheKey, Arc<Encoded>>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_CAPACITY).unwrap())))
+}
+
+pub struct Encoded {
+    pub output: Output,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The source's origin circuit is open; retry after this long.
+    Open(Duration),
+    /// The source couldn't be fetched.
+    Fetch,
+    /// The source isn't in a format this build decodes.
+    Unsupported,
+    /// The source was corrupt, too large to decode, or failed to re-encode.
+    Image,
+}
+
+/// Dimensions of a `width` x `height` image scaled to `target` pixels wide,
+/// preserving its aspect ratio. Images are never enlarged, and neither edge
+/// rounds down to zero.
+pub fn fit_width(width: u32, height: u32, target: u32) -> (u32, u32) {
+    if target >= width {
+        return (width, height);
+    }
+    let scaled = (height as u64 * target as u64 + width as u64 / 2) / width as u64;
+    (target.max(1), (scaled as u32).max(1))
+}
+
+/// The requested output, or else the source's own format when it can be
+/// encoded, or else JPEG.
+pub fn select_output(requested: Option<Output>, source: Option<image::ImageFormat>) -> Output {
+    requested
+        .or_else(|| source.and_then(Output::from_format))
+        .unwrap_or(Output::Jpeg)
+}
+
+/// The image at `url` scaled to at most `width` pixels wide and encoded as
+/// [`select_output`] picks.
+pub async fn render(
+    breakers: &Breakers,
+    url: &str,
+    width: u32,
+    requested: Option<Output>,
+) -> Result<Arc<Encoded>, Error> {
+    let cache_key = (url.to_string(), width, requested);
+    if let Some(hit) = cache().lock().unwrap().get(&cache_key) {
+        return Ok(hit.clone());
+    }
+    let bytes = fetch(breakers, url).await.map_err(|error| {
+        warn!(%url, %error, "transform fetch failed");
+        match error {
+            UpstreamError::Open(wait) => Error::Open(wait),
+            UpstreamError::Failed(_) => Error::Fetch,
+        }
+    })?;
+    let encoded = tokio::task::spawn_blocking(move || {
+        let (img, format) = decode(&bytes).map_err(|error| {
+            warn!(%error, "transform decode failed");
+            match error {
+                ImageError::Unsupported(_) => Error::Unsupported,
+                _ => Error::Image,
+            }
+        })?;
+        let (w, h) = fit_width(img.width(), img.height(), width);
+        let img = if (w, h) == (img.width(), img.height()) {
+            img
+        } else {
+            img.resize_exact(w, h, FilterType::Lanczos3)
+        };
+        let output = select_output(requested, format);
+        let bytes = encode(&img, output).map_err(|error| {
+            warn!(%error, "transform encode failed");
+            Error::Image
+        })?;
+        Ok(Encoded { output, bytes })
+    })
+    .await
+    .map_err(|_| Error::Image)??;
+    let encoded = Arc::new(encoded);
+    cache().lock().unwrap().put(cache_key, encoded.clone());
+    Ok(encoded)
+}
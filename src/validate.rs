@@ -0,0 +1,81 @@
+//! Reachability check for a sample of a reloaded map's URLs, so a broken map
+//! is rejected before it replaces the live one.
+
+use crate::breaker::Breakers;
+use std::sync::Arc;
+#[cfg(feature = "http")]
+use std::time::Duration;
+use tokio::{sync::Semaphore, task::JoinSet};
+#[cfg(feature = "http")]
+use tracing::warn;
+
+/// Default percentage of sampled URLs allowed to fail.
+pub const DEFAULT_MAX_FAILURES: f64 = 10.0;
+/// `HEAD` requests in flight at once.
+const CONCURRENCY: usize = 8;
+/// Per-request timeout.
+#[cfg(feature = "http")]
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `VALIDATE_ON_RELOAD` settings.
+pub struct ReloadCheck {
+    /// URLs checked per reload.
+    pub sample: usize,
+    /// Fraction of the sample that may fail before the map is rejected.
+    pub max_failure_rate: f64,
+}
+
+/// Whether `url` answers a `HEAD`. URLs on an origin whose circuit is open
+/// fail without a request.
+#[cfg(feature = "http")]
+async fn reachable(breakers: &Breakers, url: &str) -> bool {
+    use std::sync::OnceLock;
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    let client = CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .user_agent("roulette/1.0")
+            .timeout(TIMEOUT)
+            .build()
+            .expect("failed to build HTTP client")
+    });
+    match breakers.send(url, client.head(url)).await {
+        Ok(_) => true,
+        Err(error) => {
+            warn!(%url, %error, "reload validation request failed");
+            false
+        }
+    }
+}
+
+/// Config rejects `VALIDATE_ON_RELOAD` without the `http` feature.
+#[cfg(not(feature = "http"))]
+async fn reachable(_breakers: &Breakers, _url: &str) -> bool {
+    unreachable!("VALIDATE_ON_RELOAD requires the http feature")
+}
+
+/// `HEAD`-checks `urls` with bounded concurrency and returns the fraction
+/// that failed. `None` entries, URLs that could not be built, count as
+/// failures; an empty sample never fails.
+pub async fn failure_rate(breakers: Arc<Breakers>, urls: Vec<Option<String>>) -> f64 {
+    if urls.is_empty() {
+        return 0.0;
+    }
+    let total = urls.len();
+    let semaphore = Arc::new(Semaphore::new(CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for url in urls.into_iter().flatten() {
+        let semaphore = semaphore.clone();
+        let breakers = breakers.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.ok()?;
+            reachable(&breakers, &url).await.then_some(())
+        });
+    }
+    let mut ok = 0;
+    while let Some(result) = tasks.join_next().await {
+        if matches!(result, Ok(Some(()))) {
+            ok += 1;
+        }
+    }
+    (total - ok) as f64 / total as f64
+}